@@ -0,0 +1,92 @@
+//! One record per player session, filled in as the connection progresses and
+//! logged as a single JSON line when it closes.
+
+use crate::connection::ModLoader;
+use log::info;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+#[derive(Debug, Default, Serialize)]
+pub struct AccessLogRecord {
+    pub context_id: usize,
+    pub client_ip: String,
+    pub hostname: String,
+    pub protocol_version: i32,
+    pub mod_loader: ModLoader,
+    pub username: Option<String>,
+    pub backend: Option<String>,
+    pub backend_name: Option<String>,
+    // The geo region the backend was picked for, in geo mode.
+    pub region: Option<String>,
+    pub selection_latency_ms: Option<u64>,
+    // The id stored in the player's session cookie, when one is configured.
+    pub session_id: Option<String>,
+    // The locale the client reported, once it reaches the configuration state.
+    pub locale: Option<String>,
+}
+
+impl AccessLogRecord {
+    pub fn new(context_id: usize, addr: SocketAddr) -> Self {
+        AccessLogRecord {
+            context_id,
+            client_ip: addr.ip().to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_selection_latency(&mut self, latency: Duration) {
+        self.selection_latency_ms = Some(latency.as_millis() as u64);
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn emit(&self) {
+        info!(target: "access_log", "{}", self.to_json());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_record_json_fields() {
+        let mut record = AccessLogRecord::new(7, "[2001:db8::1]:50000".parse().unwrap());
+        record.hostname = "play.example.com".to_string();
+        record.protocol_version = 772;
+        record.mod_loader = ModLoader::Fml2;
+        record.username = Some("Notch".to_string());
+        record.backend = Some("eu.example.com:25565".to_string());
+        record.backend_name = Some("EU-West".to_string());
+        record.region = Some("EU".to_string());
+        record.locale = Some("fr_FR".to_string());
+        record.set_selection_latency(Duration::from_micros(12_500));
+
+        let json: Value = serde_json::from_str(&record.to_json()).unwrap();
+        assert_eq!(json["context_id"], 7);
+        assert_eq!(json["client_ip"], "2001:db8::1");
+        assert_eq!(json["hostname"], "play.example.com");
+        assert_eq!(json["protocol_version"], 772);
+        assert_eq!(json["mod_loader"], "fml2");
+        assert_eq!(json["username"], "Notch");
+        assert_eq!(json["backend"], "eu.example.com:25565");
+        assert_eq!(json["backend_name"], "EU-West");
+        assert_eq!(json["region"], "EU");
+        assert_eq!(json["locale"], "fr_FR");
+        assert_eq!(json["selection_latency_ms"], 12);
+    }
+
+    #[test]
+    fn test_unset_fields_are_null() {
+        let record = AccessLogRecord::new(1, "127.0.0.1:50000".parse().unwrap());
+
+        let json: Value = serde_json::from_str(&record.to_json()).unwrap();
+        assert!(json["username"].is_null());
+        assert!(json["backend"].is_null());
+        assert!(json["selection_latency_ms"].is_null());
+    }
+}
@@ -1,12 +1,19 @@
-use std::net::IpAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
+use async_trait::async_trait;
 use hickory_resolver::{
     TokioAsyncResolver,
-    config::{ResolverConfig, ResolverOpts},
+    config::{NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
     error::ResolveError,
     proto::rr::rdata::SRV,
+    system_conf::read_system_conf,
 };
+
+use crate::config::{DnsConfig, DnsUpstream, IpPreference};
 use rand::Rng;
 use rand::seq::SliceRandom;
 
@@ -20,6 +27,8 @@ pub enum EndpointError {
     InvalidHostPort,
     #[error("SRV lookup failed and no fallback available")]
     NoSrvAndNoFallback,
+    #[error("Invalid nameserver address: {0}")]
+    InvalidNameserver(String),
 }
 
 #[derive(Debug, Clone)]
@@ -30,33 +39,206 @@ pub struct ResolvedEndpoint {
     pub resolved_host: String,
 }
 
+/// The DNS queries endpoint resolution needs, so tests can stand in a fake.
+#[async_trait]
+pub trait Resolve: Send + Sync {
+    /// Addresses for `host`, along with when the answer expires.
+    async fn lookup_ips(&self, host: &str) -> Result<(Vec<IpAddr>, Instant), ResolveError>;
+
+    /// SRV records for `name`, along with when the answer expires.
+    async fn lookup_srv(&self, name: &str) -> Result<(Vec<SRV>, Instant), ResolveError>;
+}
+
+#[async_trait]
+impl Resolve for TokioAsyncResolver {
+    async fn lookup_ips(&self, host: &str) -> Result<(Vec<IpAddr>, Instant), ResolveError> {
+        let lookup = self.lookup_ip(host).await?;
+        Ok((lookup.iter().collect(), lookup.valid_until()))
+    }
+
+    async fn lookup_srv(&self, name: &str) -> Result<(Vec<SRV>, Instant), ResolveError> {
+        let lookup = self.srv_lookup(name).await?;
+        Ok((
+            lookup.iter().cloned().collect(),
+            lookup.as_lookup().valid_until(),
+        ))
+    }
+}
+
+type EndpointKey = (String, String, String);
+
+/// Resolved endpoints keyed by `(address, service, proto)`. Entries live as long
+/// as the DNS answers they came from, but never longer than `max_ttl`.
+pub struct EndpointCache {
+    max_ttl: Duration,
+    ip_preference: IpPreference,
+    entries: Mutex<HashMap<EndpointKey, (ResolvedEndpoint, Instant)>>,
+}
+
+impl EndpointCache {
+    pub fn new(max_ttl: Duration, ip_preference: IpPreference) -> Self {
+        EndpointCache {
+            max_ttl,
+            ip_preference,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn resolve(
+        &self,
+        dns: &dyn Resolve,
+        input: &str,
+        service: &str,
+        proto: &str,
+        fallback_port: u16,
+    ) -> Result<ResolvedEndpoint, EndpointError> {
+        let key = (input.to_string(), service.to_string(), proto.to_string());
+        if let Some(endpoint) = self.get(&key) {
+            return Ok(endpoint);
+        }
+
+        let (endpoint, valid_until) = resolve_uncached(
+            dns,
+            input,
+            service,
+            proto,
+            fallback_port,
+            self.ip_preference,
+        )
+        .await?;
+
+        let ceiling = Instant::now() + self.max_ttl;
+        let expires_at = valid_until.map_or(ceiling, |valid_until| valid_until.min(ceiling));
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key, (endpoint.clone(), expires_at));
+        Ok(endpoint)
+    }
+
+    fn get(&self, key: &EndpointKey) -> Option<ResolvedEndpoint> {
+        let entries = self.entries.lock().unwrap();
+        let (endpoint, expires_at) = entries.get(key)?;
+        (*expires_at > Instant::now()).then(|| endpoint.clone())
+    }
+}
+
+/// Builds the resolver settings for the configured upstreams.
+pub fn resolver_config(
+    config: &DnsConfig,
+) -> Result<(ResolverConfig, ResolverOpts), EndpointError> {
+    match config.upstream {
+        DnsUpstream::Default => Ok((ResolverConfig::default(), ResolverOpts::default())),
+        DnsUpstream::System => Ok(read_system_conf()?),
+        DnsUpstream::Custom => {
+            if config.nameservers.is_empty() {
+                return Err(EndpointError::InvalidNameserver(
+                    "dns.nameservers must list at least one server".into(),
+                ));
+            }
+
+            let mut name_servers = Vec::new();
+            for nameserver in &config.nameservers {
+                let socket_addr = parse_nameserver(nameserver)?;
+                name_servers.push(NameServerConfig::new(socket_addr, Protocol::Udp));
+                name_servers.push(NameServerConfig::new(socket_addr, Protocol::Tcp));
+            }
+            let group = NameServerConfigGroup::from(name_servers);
+            Ok((
+                ResolverConfig::from_parts(None, Vec::new(), group),
+                ResolverOpts::default(),
+            ))
+        }
+    }
+}
+
+// Accepts "ip", "ip:port" and "[ipv6]:port", defaulting to port 53.
+fn parse_nameserver(input: &str) -> Result<SocketAddr, EndpointError> {
+    if let Ok(socket_addr) = SocketAddr::from_str(input) {
+        return Ok(socket_addr);
+    }
+    IpAddr::from_str(input)
+        .map(|ip| SocketAddr::new(ip, 53))
+        .map_err(|_| EndpointError::InvalidNameserver(input.to_string()))
+}
+
+struct SharedResolver {
+    dns: TokioAsyncResolver,
+    cache: EndpointCache,
+}
+
+static SHARED: OnceLock<SharedResolver> = OnceLock::new();
+
+fn build_shared(config: &DnsConfig) -> Result<SharedResolver, EndpointError> {
+    let (resolver_config, opts) = resolver_config(config)?;
+    Ok(SharedResolver {
+        dns: TokioAsyncResolver::tokio(resolver_config, opts),
+        cache: EndpointCache::new(config.max_cache_ttl(), config.ip_preference),
+    })
+}
+
+/// Builds the process-wide resolver from config. Only takes effect if called
+/// before the first resolution.
+pub fn init(config: &DnsConfig) -> Result<(), EndpointError> {
+    let shared = build_shared(config)?;
+    let _ = SHARED.set(shared);
+    Ok(())
+}
+
+fn shared() -> &'static SharedResolver {
+    SHARED.get_or_init(|| {
+        build_shared(&DnsConfig::default()).expect("the default DNS config is always valid")
+    })
+}
+
 pub async fn resolve_host_port(
     input: &str,
     service: &str,
     proto: &str,
     fallback_port: u16,
 ) -> Result<ResolvedEndpoint, EndpointError> {
-    let resolver = TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default());
+    let shared = shared();
+    shared
+        .cache
+        .resolve(&shared.dns, input, service, proto, fallback_port)
+        .await
+}
+
+// Returns the endpoint and, when DNS was involved, when its answer expires.
+async fn resolve_uncached(
+    dns: &dyn Resolve,
+    input: &str,
+    service: &str,
+    proto: &str,
+    fallback_port: u16,
+    ip_preference: IpPreference,
+) -> Result<(ResolvedEndpoint, Option<Instant>), EndpointError> {
 
     if let Some((host_part, port)) = split_host_port(input)? {
 
         if let Ok(ip) = IpAddr::from_str(host_part) {
-            return Ok(ResolvedEndpoint {
-                ip: ip.to_string(),
-                port,
-                original_input: input.to_string(),
-                resolved_host: host_part.to_string(),
-            });
+            return Ok((
+                ResolvedEndpoint {
+                    ip: ip.to_string(),
+                    port,
+                    original_input: input.to_string(),
+                    resolved_host: host_part.to_string(),
+                },
+                None,
+            ));
         }
 
-        let addrs = resolver.lookup_ip(host_part).await?;
-        if let Some(ip) = addrs.iter().next() {
-            return Ok(ResolvedEndpoint {
-                ip: ip.to_string(),
-                port,
-                original_input: input.to_string(),
-                resolved_host: host_part.to_string(),
-            });
+        let (addrs, valid_until) = dns.lookup_ips(host_part).await?;
+        if let Some(ip) = pick_ip(&addrs, ip_preference) {
+            return Ok((
+                ResolvedEndpoint {
+                    ip: ip.to_string(),
+                    port,
+                    original_input: input.to_string(),
+                    resolved_host: host_part.to_string(),
+                },
+                Some(valid_until),
+            ));
         } else {
             return Err(EndpointError::NoAddress(host_part.to_string()));
         }
@@ -65,12 +247,15 @@ pub async fn resolve_host_port(
     let host = normalize_host_without_port(input);
 
     if let Ok(ip) = IpAddr::from_str(&host) {
-        return Ok(ResolvedEndpoint {
-            ip: ip.to_string(),
-            port: fallback_port,
-            original_input: input.to_string(),
-            resolved_host: host,
-        });
+        return Ok((
+            ResolvedEndpoint {
+                ip: ip.to_string(),
+                port: fallback_port,
+                original_input: input.to_string(),
+                resolved_host: host,
+            },
+            None,
+        ));
     }
 
     let has_alpha = host.chars().any(|c| c.is_ascii_alphabetic());
@@ -82,28 +267,39 @@ pub async fn resolve_host_port(
             host
         );
 
-        if let Ok(answers) = resolver.srv_lookup(&srv_name).await {
+        if let Ok((answers, valid_until)) = dns.lookup_srv(&srv_name).await {
             let srv_records: Vec<&SRV> = answers.iter().collect();
             if let Some(chosen) = pick_srv(&srv_records) {
                 let target = chosen.target().to_utf8().trim_end_matches('.').to_string();
-                let addrs = target.parse().map_err(|_| EndpointError::InvalidHostPort)?;
-                return Ok(ResolvedEndpoint {
-                    ip: addrs,
-                    port: chosen.port(),
-                    original_input: input.to_string(),
-                    resolved_host: target,
-                });
+                // Resolve the target here too, so the endpoint always carries an
+                // IP and the configured upstreams answer for SRV targets as well.
+                let (addrs, target_valid_until) = dns.lookup_ips(&target).await?;
+                let Some(ip) = pick_ip(&addrs, ip_preference) else {
+                    return Err(EndpointError::NoAddress(target));
+                };
+                return Ok((
+                    ResolvedEndpoint {
+                        ip: ip.to_string(),
+                        port: chosen.port(),
+                        original_input: input.to_string(),
+                        resolved_host: target,
+                    },
+                    Some(valid_until.min(target_valid_until)),
+                ));
             }
         }
 
-        let addrs = resolver.lookup_ip(&host).await?;
-        if let Some(ip) = addrs.iter().next() {
-            return Ok(ResolvedEndpoint {
-                ip: ip.to_string(),
-                port: fallback_port,
-                original_input: input.to_string(),
-                resolved_host: host,
-            });
+        let (addrs, valid_until) = dns.lookup_ips(&host).await?;
+        if let Some(ip) = pick_ip(&addrs, ip_preference) {
+            return Ok((
+                ResolvedEndpoint {
+                    ip: ip.to_string(),
+                    port: fallback_port,
+                    original_input: input.to_string(),
+                    resolved_host: host,
+                },
+                Some(valid_until),
+            ));
         } else {
             return Err(EndpointError::NoAddress(host));
         }
@@ -112,6 +308,29 @@ pub async fn resolve_host_port(
     Err(EndpointError::NoSrvAndNoFallback)
 }
 
+// The first address of the preferred family, or the first address at all when
+// the host has none of that family.
+fn pick_ip(addrs: &[IpAddr], preference: IpPreference) -> Option<IpAddr> {
+    let preferred = addrs.iter().find(|ip| match preference {
+        IpPreference::Any => true,
+        IpPreference::Ipv4 => ip.is_ipv4(),
+        IpPreference::Ipv6 => ip.is_ipv6(),
+    });
+    preferred.or(addrs.first()).copied()
+}
+
+/// Checks `input` is a well-formed host, IP, or host:port without doing any DNS.
+pub fn validate_address(input: &str) -> Result<(), EndpointError> {
+    let host = match split_host_port(input)? {
+        Some((host, _)) => host.to_string(),
+        None => normalize_host_without_port(input),
+    };
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err(EndpointError::InvalidHostPort);
+    }
+    Ok(())
+}
+
 // RFC 2782 selection (priority + weight)
 fn pick_srv<'a>(records: &'a [&'a SRV]) -> Option<&'a SRV> {
     if records.is_empty() {
@@ -144,6 +363,18 @@ fn pick_srv<'a>(records: &'a [&'a SRV]) -> Option<&'a SRV> {
     None
 }
 
+/// `address` with `port` added, unless it already names one. Bare IPv6
+/// addresses are bracketed.
+pub fn with_port(address: &str, port: u16) -> String {
+    match split_host_port(address) {
+        Ok(Some(_)) | Err(_) => address.to_string(),
+        Ok(None) if IpAddr::from_str(address).is_ok_and(|ip| ip.is_ipv6()) => {
+            format!("[{}]:{}", address, port)
+        }
+        Ok(None) => format!("{}:{}", address, port),
+    }
+}
+
 fn split_host_port(input: &str) -> Result<Option<(&str, u16)>, EndpointError> {
     if input.starts_with('[') {
         return if let Some(end) = input.find(']') {
@@ -192,3 +423,275 @@ fn normalize_host_without_port(input: &str) -> String {
     let h = input.trim();
     h.strip_suffix('.').unwrap_or(h).to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::rr::Name;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    /// Answers every A/AAAA query with one address and counts the queries.
+    struct CountingResolver {
+        ttl: Duration,
+        ip_lookups: AtomicUsize,
+        srv_lookups: AtomicUsize,
+    }
+
+    impl CountingResolver {
+        fn new(ttl: Duration) -> Self {
+            CountingResolver {
+                ttl,
+                ip_lookups: AtomicUsize::new(0),
+                srv_lookups: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Resolve for CountingResolver {
+        async fn lookup_ips(&self, _host: &str) -> Result<(Vec<IpAddr>, Instant), ResolveError> {
+            self.ip_lookups.fetch_add(1, SeqCst);
+            Ok((
+                vec!["192.0.2.10".parse().unwrap()],
+                Instant::now() + self.ttl,
+            ))
+        }
+
+        async fn lookup_srv(&self, _name: &str) -> Result<(Vec<SRV>, Instant), ResolveError> {
+            self.srv_lookups.fetch_add(1, SeqCst);
+            Err("no SRV records".into())
+        }
+    }
+
+    /// Publishes one SRV record, for `play.example.com`, gives `dual.example.com`
+    /// both an AAAA and an A record, and records every query.
+    #[derive(Default)]
+    struct SrvResolver {
+        queries: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Resolve for SrvResolver {
+        async fn lookup_ips(&self, host: &str) -> Result<(Vec<IpAddr>, Instant), ResolveError> {
+            self.queries.lock().unwrap().push(host.to_string());
+            let ips: &[&str] = match host {
+                "mc1.example.com" => &["192.0.2.20"],
+                "dual.example.com" => &["2001:db8::30", "192.0.2.30"],
+                _ => &["192.0.2.10"],
+            };
+            let ips = ips.iter().map(|ip| ip.parse().unwrap()).collect();
+            Ok((ips, Instant::now()))
+        }
+
+        async fn lookup_srv(&self, name: &str) -> Result<(Vec<SRV>, Instant), ResolveError> {
+            self.queries.lock().unwrap().push(name.to_string());
+            if name != "_minecraft._tcp.play.example.com" {
+                return Err("no SRV records".into());
+            }
+            let target = Name::from_ascii("mc1.example.com.").unwrap();
+            Ok((vec![SRV::new(0, 5, 25570, target)], Instant::now()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bare_domain_uses_srv_record() {
+        let dns = SrvResolver::default();
+
+        let (endpoint, _) = resolve_uncached(
+            &dns,
+            "play.example.com",
+            "minecraft",
+            "tcp",
+            25565,
+            IpPreference::Any,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(endpoint.ip, "192.0.2.20");
+        assert_eq!(endpoint.port, 25570);
+        assert_eq!(endpoint.resolved_host, "mc1.example.com");
+        assert_eq!(
+            *dns.queries.lock().unwrap(),
+            ["_minecraft._tcp.play.example.com", "mc1.example.com"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bare_domain_without_srv_uses_default_port() {
+        let dns = SrvResolver::default();
+
+        let (endpoint, _) = resolve_uncached(
+            &dns,
+            "other.example.com",
+            "minecraft",
+            "tcp",
+            25565,
+            IpPreference::Any,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(endpoint.ip, "192.0.2.10");
+        assert_eq!(endpoint.port, 25565);
+        assert_eq!(
+            *dns.queries.lock().unwrap(),
+            ["_minecraft._tcp.other.example.com", "other.example.com"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_explicit_port_skips_srv() {
+        let dns = SrvResolver::default();
+
+        let (endpoint, _) = resolve_uncached(
+            &dns,
+            "play.example.com:25565",
+            "minecraft",
+            "tcp",
+            25565,
+            IpPreference::Any,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(endpoint.ip, "192.0.2.10");
+        assert_eq!(endpoint.port, 25565);
+        assert_eq!(*dns.queries.lock().unwrap(), ["play.example.com"]);
+    }
+
+    #[tokio::test]
+    async fn test_ip_preference_picks_address_family() {
+        let dns = SrvResolver::default();
+        let cases = [
+            ("dual.example.com:25565", IpPreference::Any, "2001:db8::30"),
+            ("dual.example.com:25565", IpPreference::Ipv4, "192.0.2.30"),
+            ("dual.example.com:25565", IpPreference::Ipv6, "2001:db8::30"),
+            // Falls back to the other family rather than failing.
+            ("v4.example.com:25565", IpPreference::Ipv6, "192.0.2.10"),
+        ];
+
+        for (input, preference, expected) in cases {
+            let (endpoint, _) =
+                resolve_uncached(&dns, input, "minecraft", "tcp", 25565, preference)
+                    .await
+                    .unwrap();
+            assert_eq!(endpoint.ip, expected, "{} {:?}", input, preference);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_second_resolution_within_ttl_is_cached() {
+        let dns = CountingResolver::new(Duration::from_secs(60));
+        let cache = EndpointCache::new(Duration::from_secs(300), IpPreference::Any);
+
+        let first = cache
+            .resolve(&dns, "play.example.com", "minecraft", "tcp", 25565)
+            .await
+            .unwrap();
+        let second = cache
+            .resolve(&dns, "play.example.com", "minecraft", "tcp", 25565)
+            .await
+            .unwrap();
+
+        assert_eq!(first.ip, "192.0.2.10");
+        assert_eq!(second.ip, first.ip);
+        assert_eq!(dns.ip_lookups.load(SeqCst), 1);
+        assert_eq!(dns.srv_lookups.load(SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_record_is_resolved_again() {
+        let dns = CountingResolver::new(Duration::ZERO);
+        let cache = EndpointCache::new(Duration::from_secs(300), IpPreference::Any);
+
+        for _ in 0..2 {
+            cache
+                .resolve(&dns, "play.example.com:25570", "minecraft", "tcp", 25565)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(dns.ip_lookups.load(SeqCst), 2);
+    }
+
+    #[test]
+    fn test_with_port_keeps_an_existing_port() {
+        assert_eq!(with_port("mc.example.com", 25565), "mc.example.com:25565");
+        assert_eq!(with_port("mc.example.com:25570", 25565), "mc.example.com:25570");
+        assert_eq!(with_port("10.0.0.1", 25566), "10.0.0.1:25566");
+        assert_eq!(with_port("2001:db8::1", 25565), "[2001:db8::1]:25565");
+        assert_eq!(with_port("[2001:db8::1]:25570", 25565), "[2001:db8::1]:25570");
+    }
+
+    #[test]
+    fn test_custom_nameservers_are_configured() {
+        let config = DnsConfig {
+            upstream: DnsUpstream::Custom,
+            nameservers: vec!["9.9.9.9".into(), "[2620:fe::fe]:5353".into()],
+            ..DnsConfig::default()
+        };
+
+        let (resolver_config, _) = resolver_config(&config).unwrap();
+        let mut addrs: Vec<SocketAddr> = resolver_config
+            .name_servers()
+            .iter()
+            .map(|server| server.socket_addr)
+            .collect();
+        addrs.dedup();
+
+        assert_eq!(
+            addrs,
+            vec![
+                "9.9.9.9:53".parse().unwrap(),
+                "[2620:fe::fe]:5353".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_nameserver_is_rejected() {
+        let config = DnsConfig {
+            upstream: DnsUpstream::Custom,
+            nameservers: vec!["dns.example.com".into()],
+            ..DnsConfig::default()
+        };
+
+        assert!(matches!(
+            resolver_config(&config),
+            Err(EndpointError::InvalidNameserver(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shared_resolver_resolves_ip_literals() {
+        let cases = [
+            ("127.0.0.1:25570", "127.0.0.1", 25570),
+            ("[::1]:25566", "::1", 25566),
+            ("192.0.2.1", "192.0.2.1", 25565),
+        ];
+
+        for (input, ip, port) in cases {
+            let endpoint = resolve_host_port(input, "minecraft", "tcp", 25565)
+                .await
+                .unwrap();
+            assert_eq!(endpoint.ip, ip, "{}", input);
+            assert_eq!(endpoint.port, port, "{}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_ttl_caps_record_ttl() {
+        let dns = CountingResolver::new(Duration::from_secs(3600));
+        let cache = EndpointCache::new(Duration::ZERO, IpPreference::Any);
+
+        for _ in 0..2 {
+            cache
+                .resolve(&dns, "play.example.com:25570", "minecraft", "tcp", 25565)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(dns.ip_lookups.load(SeqCst), 2);
+    }
+}
@@ -0,0 +1,345 @@
+//! A small HTTP API for inspecting and draining backends at runtime.
+//!
+//! `GET /backends` lists every backend the finder can select, sorted by
+//! address, `POST /backends/{address}/drain` stops new players being sent to
+//! one, and `POST /backends/{address}/enable` puts it back into rotation.
+//! Backends are named by address, as listed, since the finder's own order can
+//! change between requests. `POST /maintenance/enable` and
+//! `POST /maintenance/disable` toggle maintenance mode for the whole balancer.
+
+use crate::backend::MinecraftServer;
+use crate::finder::ServerFinder;
+use crate::http_server::{self, Request, Response};
+use crate::maintenance::Maintenance;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+/// Serves the admin API for whichever finder is behind `server_finder`, so
+/// backends added by a reload show up right away. The backends listed share
+/// their runtime state with the finder's copies, so draining one here
+/// affects selection immediately.
+pub async fn serve(
+    listener: TcpListener,
+    server_finder: Arc<Mutex<Box<dyn ServerFinder>>>,
+    maintenance: Arc<Maintenance>,
+    token: Option<String>,
+) -> std::io::Result<()> {
+    http_server::serve(listener, "admin", move |request| {
+        let server_finder = server_finder.clone();
+        let maintenance = maintenance.clone();
+        let token = token.clone();
+        async move {
+            let (status, body) =
+                respond(&request, &server_finder, &maintenance, token.as_deref()).await;
+            Response {
+                status,
+                content_type: "application/json",
+                body: body.to_string(),
+            }
+        }
+    })
+    .await
+}
+
+// Only the backend routes lock the finder, so maintenance can be toggled
+// while it is busy.
+async fn respond(
+    request: &Request,
+    server_finder: &Mutex<Box<dyn ServerFinder>>,
+    maintenance: &Maintenance,
+    token: Option<&str>,
+) -> (&'static str, Value) {
+    if let Some(token) = token {
+        let expected = format!("Bearer {}", token);
+        if request.header("authorization") != Some(expected.as_str()) {
+            return ("401 Unauthorized", json!({ "error": "unauthorized" }));
+        }
+    }
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["backends"]) => {
+            let mut backends = server_finder.lock().await.backends();
+            backends.sort_by(|a, b| a.address.cmp(&b.address));
+            let list = backends.iter().map(backend_json).collect();
+            ("200 OK", Value::Array(list))
+        }
+        ("POST", ["backends", address, action @ ("drain" | "enable")]) => {
+            // A backend listed more than once, like a fallback shared by two
+            // regions, is drained everywhere.
+            let matching: Vec<MinecraftServer> = server_finder
+                .lock()
+                .await
+                .backends()
+                .into_iter()
+                .filter(|backend| backend.address == *address)
+                .collect();
+            let Some(first) = matching.first() else {
+                return ("404 Not Found", json!({ "error": "no such backend" }));
+            };
+            for backend in &matching {
+                backend.set_draining(*action == "drain");
+            }
+            ("200 OK", backend_json(first))
+        }
+        ("POST", ["maintenance", action @ ("enable" | "disable")]) => {
+            maintenance.set_enabled(*action == "enable");
+            ("200 OK", json!({ "maintenance": maintenance.is_enabled() }))
+        }
+        _ => ("404 Not Found", json!({ "error": "not found" })),
+    }
+}
+
+fn backend_json(backend: &MinecraftServer) -> Value {
+    let player_count = backend.last_player_count();
+    let last_error = backend.last_error().map(|error| {
+        json!({
+            "message": error.message,
+            "at": error.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        })
+    });
+    json!({
+        "address": backend.address,
+        "player_count": player_count,
+        "up": player_count.is_some(),
+        "draining": backend.is_draining(),
+        "active_connections": backend.active_connections(),
+        "max_connections": backend.max_connections,
+        "last_error": last_error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::finder::get_server_finder;
+    use crate::reload::swap_finder;
+    use crate::test_support::{NoServerFinder, test_config};
+    use std::net::SocketAddr;
+
+    async fn spawn_admin(
+        server_finder: Arc<Mutex<Box<dyn ServerFinder>>>,
+        token: Option<&str>,
+    ) -> SocketAddr {
+        spawn_admin_with_maintenance(server_finder, Arc::new(Maintenance::default()), token).await
+    }
+
+    async fn spawn_admin_with_maintenance(
+        server_finder: Arc<Mutex<Box<dyn ServerFinder>>>,
+        maintenance: Arc<Maintenance>,
+        token: Option<&str>,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            server_finder,
+            maintenance,
+            token.map(str::to_string),
+        ));
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_list_backends() {
+        let config = Config::from_yaml_str(
+            r#"
+mode: static
+motd: test
+static:
+  algorithm: round_robin
+  servers:
+    - address: "a.example.com"
+    - address: "b.example.com"
+"#,
+        )
+        .unwrap();
+        let finder = get_server_finder(config).unwrap();
+        finder.backends()[1].record_error("connection refused".to_string());
+        let addr = spawn_admin(Arc::new(Mutex::new(finder)), None).await;
+
+        let body: Value = reqwest::get(format!("http://{}/backends", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        let backends = body.as_array().unwrap();
+        assert_eq!(backends.len(), 2);
+        assert_eq!(backends[0]["address"], "a.example.com");
+        assert_eq!(backends[0]["up"], false);
+        assert_eq!(backends[0]["draining"], false);
+        assert!(backends[0]["last_error"].is_null());
+        assert_eq!(backends[1]["last_error"]["message"], "connection refused");
+    }
+
+    #[tokio::test]
+    async fn test_drain_backend() {
+        let finder = get_server_finder(test_config("")).unwrap();
+        let backend = finder.backends()[0].clone();
+        let addr = spawn_admin(Arc::new(Mutex::new(finder)), None).await;
+        let client = reqwest::Client::new();
+
+        let response = client
+            .post(format!("http://{}/backends/a.example.com/drain", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(backend.is_draining());
+
+        let response = client
+            .post(format!("http://{}/backends/a.example.com/enable", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(!backend.is_draining());
+
+        let response = client
+            .post(format!("http://{}/backends/0/drain", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn test_backends_are_named_by_address() {
+        let config = Config::from_yaml_str(
+            r#"
+mode: static
+motd: test
+static:
+  algorithm: round_robin
+  servers:
+    - address: "b.example.com"
+    - address: "a.example.com"
+"#,
+        )
+        .unwrap();
+        let finder = get_server_finder(config).unwrap();
+        let backends = finder.backends();
+        let addr = spawn_admin(Arc::new(Mutex::new(finder)), None).await;
+        let client = reqwest::Client::new();
+
+        let body: Value = client
+            .post(format!("http://{}/backends/b.example.com/drain", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["address"], "b.example.com");
+        assert!(backends[0].is_draining());
+        assert!(!backends[1].is_draining());
+
+        let body: Value = client
+            .get(format!("http://{}/backends", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let listed: Vec<(&str, bool)> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|backend| {
+                (
+                    backend["address"].as_str().unwrap(),
+                    backend["draining"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(listed, [("a.example.com", false), ("b.example.com", true)]);
+    }
+
+    #[tokio::test]
+    async fn test_toggle_maintenance() {
+        let maintenance = Arc::new(Maintenance::default());
+        let server_finder: Arc<Mutex<Box<dyn ServerFinder>>> =
+            Arc::new(Mutex::new(Box::new(NoServerFinder)));
+        let addr =
+            spawn_admin_with_maintenance(server_finder.clone(), maintenance.clone(), None).await;
+        let client = reqwest::Client::new();
+        // Toggling maintenance doesn't wait on a busy finder.
+        let _busy = server_finder.lock().await;
+
+        let body: Value = client
+            .post(format!("http://{}/maintenance/enable", addr))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(body["maintenance"], true);
+        assert!(maintenance.is_enabled());
+
+        client
+            .post(format!("http://{}/maintenance/disable", addr))
+            .send()
+            .await
+            .unwrap();
+        assert!(!maintenance.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_token_is_required() {
+        let finder = get_server_finder(test_config("")).unwrap();
+        let addr = spawn_admin(Arc::new(Mutex::new(finder)), Some("secret")).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/backends", addr);
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = client.get(&url).bearer_auth("wrong").send().await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = client.get(&url).bearer_auth("secret").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_backends_are_listed() {
+        let server_finder: Arc<Mutex<Box<dyn ServerFinder>>> =
+            Arc::new(Mutex::new(get_server_finder(test_config("")).unwrap()));
+        let addr = spawn_admin(server_finder.clone(), None).await;
+        let config = Config::from_yaml_str(
+            r#"
+mode: static
+motd: test
+static:
+  algorithm: round_robin
+  servers:
+    - address: "a.example.com"
+    - address: "b.example.com"
+"#,
+        )
+        .unwrap();
+        swap_finder(&server_finder, get_server_finder(config).unwrap()).await;
+
+        let body: Value = reqwest::get(format!("http://{}/backends", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let addresses: Vec<&str> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|backend| backend["address"].as_str().unwrap())
+            .collect();
+        assert_eq!(addresses, ["a.example.com", "b.example.com"]);
+    }
+}
@@ -1,47 +1,434 @@
-use crate::address_resolver::resolve_host_port;
+use crate::address_resolver::{resolve_host_port, with_port};
+use crate::config::{BackendCountSource, Config, Coordinates, Server};
 use crate::connection::Connection;
+use crate::proxy_protocol;
+use crate::query;
 use log::debug;
 use pumpkin_protocol::{
     ClientPacket, ConnectionState, RawPacket, ServerPacket, codec::var_int::VarInt,
     java::client::status::CStatusResponse, java::packet_decoder::TCPNetworkDecoder,
     java::packet_encoder::TCPNetworkEncoder, java::server::handshake::SHandShake,
-    java::server::status::SStatusRequest,
+    java::server::status::SStatusRequest, packet::Packet,
 };
 use serde_json::Value;
+use std::collections::VecDeque;
 use std::error::Error;
-use tokio::io::{BufReader, BufWriter};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::TcpStream;
-use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::tcp::WriteHalf;
+
+/// What a backend reported in its status response. Backends counted over
+/// Query only report the player count.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BackendStatus {
+    pub online: u32,
+    pub max: Option<u32>,
+    pub version_name: Option<String>,
+    pub protocol: Option<i32>,
+    /// As sent: a plain string or a text component.
+    pub description: Option<Value>,
+    /// The server icon as a data URI.
+    pub favicon: Option<String>,
+}
+
+impl BackendStatus {
+    /// The description's text, without formatting.
+    pub fn description_text(&self) -> Option<String> {
+        self.description.as_ref().map(component_text)
+    }
+}
+
+// The text of a chat component and its children, in order.
+fn component_text(component: &Value) -> String {
+    match component {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter().map(component_text).collect(),
+        Value::Object(fields) => {
+            let mut text = fields
+                .get("text")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string();
+            if let Some(Value::Array(extra)) = fields.get("extra") {
+                text.extend(extra.iter().map(component_text));
+            }
+            text
+        }
+        _ => String::new(),
+    }
+}
+
+/// The most recent failure seen while talking to a backend.
+#[derive(Debug, Clone)]
+pub struct BackendError {
+    pub message: String,
+    pub at: SystemTime,
+}
+
+// How long a measured status-ping round trip is trusted before pinging again.
+const LATENCY_CACHE_TTL: Duration = Duration::from_secs(30);
+
+// Wait before the first retry of a failed ping; doubled after every attempt.
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How status pings to a backend are carried out.
+#[derive(Debug, Clone, Copy)]
+pub struct PingOptions {
+    /// Extra attempts after a failed ping.
+    pub retries: u32,
+    /// Upper bound on a ping, including every retry.
+    pub timeout: Duration,
+    /// Upper bound on opening the TCP connection of a single attempt.
+    pub connect_timeout: Duration,
+    /// Protocol version sent in the status handshake.
+    pub protocol_version: i32,
+    /// Most backends a finder pings at once.
+    pub poll_concurrency: usize,
+}
+
+impl Default for PingOptions {
+    fn default() -> Self {
+        PingOptions {
+            retries: 2,
+            timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(2),
+            protocol_version: 772,
+            poll_concurrency: 8,
+        }
+    }
+}
+
+impl PingOptions {
+    pub fn from_config(config: &Config) -> Self {
+        PingOptions {
+            retries: config.ping_retries(),
+            timeout: Duration::from_secs(config.timeout()),
+            connect_timeout: config.connect_timeout(),
+            protocol_version: config.ping_protocol_version(),
+            poll_concurrency: config.poll_concurrency(),
+        }
+    }
+}
+
+// How long a routed connection keeps counting towards a backend after it
+// closes. Players are transferred away immediately, so this stands in for
+// how long they actually stay on the backend.
+const CLOSED_CONNECTION_DECAY: Duration = Duration::from_secs(120);
+
+/// Runtime state shared between every clone of a `MinecraftServer`.
+#[derive(Debug, Default)]
+struct BackendState {
+    last_error: Mutex<Option<BackendError>>,
+    // None until the first successful ping, and again after a failed one.
+    status: Mutex<Option<BackendStatus>>,
+    latency: Mutex<Option<(Duration, Instant)>>,
+    draining: AtomicBool,
+    open_connections: AtomicU32,
+    closed_connections: Mutex<VecDeque<Instant>>,
+}
+
+impl BackendState {
+    fn active_connections(&self, decay: Duration) -> u32 {
+        let mut closed = self.closed_connections.lock().unwrap();
+        while closed
+            .front()
+            .is_some_and(|closed_at| closed_at.elapsed() >= decay)
+        {
+            closed.pop_front();
+        }
+        self.open_connections.load(Ordering::Relaxed) + closed.len() as u32
+    }
+}
+
+/// A connection the balancer routed to a backend, counted against it until dropped.
+#[derive(Debug)]
+pub struct RoutedConnection {
+    state: Arc<BackendState>,
+}
+
+impl Drop for RoutedConnection {
+    fn drop(&mut self) {
+        self.state.open_connections.fetch_sub(1, Ordering::Relaxed);
+        self.state
+            .closed_connections
+            .lock()
+            .unwrap()
+            .push_back(Instant::now());
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct MinecraftServer {
     pub address: String,
+    /// The server's `name` from the config, for logs.
+    pub name: Option<String>,
+    /// Starts status pings with a PROXY v2 header naming the balancer itself.
+    pub ping_with_proxy_protocol: bool,
+    pub max_players: Option<u32>,
+    /// Most connections routed here at once, when capped.
+    pub max_connections: Option<u32>,
+    /// The geo region this backend serves, set by the geo finder.
+    pub region: Option<String>,
+    pub count_source: BackendCountSource,
+    /// Where `BackendCountSource::Query` asks, when not the game port.
+    pub query_port: Option<u16>,
+    /// Sent as the server address of status-ping handshakes instead of the
+    /// resolved IP, for backends that route by virtual host.
+    pub forced_host: Option<String>,
+    /// Lower tiers are preferred by static groups.
+    pub tier: u32,
+    /// Where the backend is, for sending geo clients to the nearest one.
+    pub coordinates: Option<Coordinates>,
+    ping: PingOptions,
+    state: Arc<BackendState>,
 }
 
 impl MinecraftServer {
     pub fn new(address: String) -> Self {
-        MinecraftServer { address }
+        MinecraftServer {
+            address,
+            name: None,
+            ping_with_proxy_protocol: false,
+            max_players: None,
+            max_connections: None,
+            region: None,
+            count_source: BackendCountSource::Status,
+            query_port: None,
+            forced_host: None,
+            tier: 0,
+            coordinates: None,
+            ping: PingOptions::default(),
+            state: Arc::new(BackendState::default()),
+        }
+    }
+
+    pub fn from_config(server: &Server, ping: PingOptions) -> Self {
+        MinecraftServer {
+            name: server.name.clone(),
+            ping_with_proxy_protocol: server.ping_with_proxy_protocol,
+            max_players: server.max_players,
+            max_connections: server.max_connections,
+            count_source: server.count_source,
+            query_port: server.query_port,
+            forced_host: server.forced_host.clone(),
+            tier: server.tier,
+            coordinates: server.coordinates,
+            ping,
+            ..Self::new(match server.port {
+                Some(port) => with_port(&server.address, port),
+                None => server.address.clone(),
+            })
+        }
+    }
+
+    /// Takes over the runtime state of `previous`, the same backend in the
+    /// finder a reload replaces: its routed connections, drain flag and last
+    /// poll.
+    pub fn share_state(&mut self, previous: &MinecraftServer) {
+        self.state = previous.state.clone();
+    }
+
+    pub fn last_error(&self) -> Option<BackendError> {
+        self.state.last_error.lock().unwrap().clone()
+    }
+
+    pub fn record_error(&self, message: String) {
+        *self.state.last_error.lock().unwrap() = Some(BackendError {
+            message,
+            at: SystemTime::now(),
+        });
     }
 
+    /// Whether the last ping failed, or the backend was found unreachable
+    /// before any ping succeeded. Backends not pinged yet aren't down, and a
+    /// successful ping brings one back up.
+    pub fn is_down(&self) -> bool {
+        self.last_player_count().is_none() && self.last_error().is_some()
+    }
+
+    /// The status from the most recent ping, if it succeeded.
+    pub fn last_status(&self) -> Option<BackendStatus> {
+        self.state.status.lock().unwrap().clone()
+    }
+
+    /// The player count from the most recent ping, if it succeeded.
+    pub fn last_player_count(&self) -> Option<u32> {
+        self.state
+            .status
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|status| status.online)
+    }
+
+    /// The player limit the backend reported in its last successful status
+    /// ping. Backends counted over Query report none.
+    pub fn last_max_players(&self) -> Option<u32> {
+        self.state
+            .status
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|status| status.max)
+    }
+
+    /// A draining backend receives no new players.
+    pub fn is_draining(&self) -> bool {
+        self.state.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.state.draining.store(draining, Ordering::Relaxed);
+    }
+
+    /// Counts a connection against this backend until the returned guard is dropped.
+    pub fn route_connection(&self) -> RoutedConnection {
+        self.state.open_connections.fetch_add(1, Ordering::Relaxed);
+        RoutedConnection {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Connections routed here that are still open or closed only recently.
+    pub fn active_connections(&self) -> u32 {
+        self.state.active_connections(CLOSED_CONNECTION_DECAY)
+    }
+
+    /// Whether `max_connections` players are already routed here.
+    pub fn is_at_capacity(&self) -> bool {
+        self.max_connections
+            .is_some_and(|max| self.active_connections() >= max)
+    }
+
+    /// Whether new players may be sent here: it isn't draining or at capacity.
+    pub fn accepts_players(&self) -> bool {
+        !self.is_draining() && !self.is_at_capacity()
+    }
+
+    /// The status-ping round trip, pinging the backend if the last
+    /// measurement is missing or stale.
+    pub async fn get_latency(&self) -> Result<Duration, Box<dyn Error>> {
+        if let Some(latency) = self.cached_latency() {
+            return Ok(latency);
+        }
+        self.get_player_count().await?;
+        self.cached_latency()
+            .ok_or_else(|| "No latency was measured".into())
+    }
+
+    fn cached_latency(&self) -> Option<Duration> {
+        let latency = *self.state.latency.lock().unwrap();
+        latency
+            .filter(|(_, measured_at)| measured_at.elapsed() < LATENCY_CACHE_TTL)
+            .map(|(latency, _)| latency)
+    }
+
+    /// Pings the backend for its player count, see `get_status`.
     pub async fn get_player_count(&self) -> Result<u32, Box<dyn Error>> {
+        Ok(self.get_status().await?.online)
+    }
+
+    /// Pings the backend, retrying failures with exponential backoff until
+    /// the retries or the ping timeout run out.
+    pub async fn get_status(&self) -> Result<BackendStatus, Box<dyn Error>> {
+        let deadline = tokio::time::Instant::now() + self.ping.timeout;
+        let mut backoff = INITIAL_RETRY_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            let result = match tokio::time::timeout_at(deadline, self.poll_status()).await {
+                Ok(result) => result.map_err(|error| error.to_string()),
+                Err(elapsed) => Err(elapsed.to_string()),
+            };
+
+            let message = match result {
+                Ok(status) => {
+                    *self.state.status.lock().unwrap() = Some(status.clone());
+                    return Ok(status);
+                }
+                Err(message) => message,
+            };
+
+            attempt += 1;
+            if attempt > self.ping.retries || tokio::time::Instant::now() + backoff >= deadline {
+                *self.state.status.lock().unwrap() = None;
+                self.record_error(message.clone());
+                return Err(message.into());
+            }
+
+            debug!(
+                "Ping to {} failed ({}), retrying in {:?}",
+                self.address, message, backoff
+            );
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    async fn poll_status(&self) -> Result<BackendStatus, Box<dyn Error>> {
+        match self.count_source {
+            BackendCountSource::Status => self.ping_status().await,
+            BackendCountSource::Query => self.query_status().await,
+        }
+    }
+
+    // The connect timeout bounds each reply instead, as UDP has no connect.
+    async fn query_status(&self) -> Result<BackendStatus, Box<dyn Error>> {
+        let (ip, port) = self.get_host_and_port().await?;
+        let addr = SocketAddr::new(ip.parse()?, self.query_port.unwrap_or(port));
+        let online = query::player_count(addr, self.ping.connect_timeout).await?;
+        Ok(BackendStatus {
+            online,
+            ..BackendStatus::default()
+        })
+    }
+
+    // Every attempt opens a new connection. Vanilla servers answer a single
+    // status request per connection and then disconnect, so there is no idle
+    // socket to keep around for the next poll.
+    async fn ping_status(&self) -> Result<BackendStatus, Box<dyn Error>> {
         debug!("Getting player count from {}", self.address);
 
         let (hostname, port) = self.get_host_and_port().await?;
 
         debug!("{}:{}", hostname, port);
 
-        let stream = TcpStream::connect((hostname.clone(), port)).await?;
+        // Without its own timeout a connect to a host that drops SYNs would
+        // use up the whole ping timeout, leaving no time to retry.
+        let mut stream = tokio::time::timeout(
+            self.ping.connect_timeout,
+            TcpStream::connect((hostname.clone(), port)),
+        )
+        .await
+        .map_err(|_| {
+            format!(
+                "Timed out connecting to {} after {:?}",
+                self.address, self.ping.connect_timeout
+            )
+        })??;
+        // The exchange is two small writes and a read; waiting to coalesce
+        // them only adds latency.
+        stream.set_nodelay(true)?;
 
         debug!("Connected to server");
 
-        let (reader, writer) = stream.into_split();
+        if self.ping_with_proxy_protocol {
+            let header = proxy_protocol::encode_v2(stream.local_addr()?, stream.peer_addr()?);
+            stream.write_all(&header).await?;
+        }
+
+        let (reader, writer) = stream.split();
 
         let mut stream_writer = TCPNetworkEncoder::new(BufWriter::new(writer));
         let mut stream_reader = TCPNetworkDecoder::new(BufReader::new(reader));
 
         let handshake_packet = SHandShake {
-            protocol_version: VarInt(772),
-            server_address: hostname.to_string(),
+            protocol_version: VarInt(self.ping.protocol_version),
+            server_address: self.forced_host.clone().unwrap_or(hostname),
             server_port: port,
             next_state: ConnectionState::Status,
         };
@@ -54,23 +441,47 @@ impl MinecraftServer {
 
         debug!("Waiting for response");
 
+        let sent_at = Instant::now();
         let packet: RawPacket = stream_reader.get_raw_packet().await?;
+        *self.state.latency.lock().unwrap() = Some((sent_at.elapsed(), Instant::now()));
 
-        let bytebuf = &packet.payload[..];
-        let packet = CStatusResponse::read(bytebuf)?;
-
-        let response = serde_json::from_str::<'_, Value>(&packet.json_response)?;
+        // Close our side now rather than leaving the socket to linger until
+        // a slow backend sends its FIN. The response is in either way, so a
+        // failed shutdown doesn't fail the ping.
+        drop((stream_reader, stream_writer));
+        let _ = stream.shutdown().await;
 
-        let players = response
-            .get("players")
-            .ok_or("Response did not contain 'players' field")?;
+        // Backends only enable compression during login, so a status
+        // exchange should never see anything but an uncompressed response.
+        if packet.id != CStatusResponse::PACKET_ID {
+            return Err(format!(
+                "Expected a status response, got unexpected packet id {:#04x}",
+                packet.id
+            )
+            .into());
+        }
 
-        let online_field = players
-            .get("online")
-            .ok_or("Response did not contain 'online' field")?;
+        let bytebuf = &packet.payload[..];
+        let packet = CStatusResponse::read(bytebuf)?;
+        parse_status(&packet.json_response)
+    }
 
-        let online = online_field.as_u64().ok_or("'online' field is not a u64")? as u32;
-        Ok(online)
+    /// Opens and closes a TCP connection to the backend, to check it is
+    /// reachable right before sending a player there.
+    pub async fn probe(&self) -> Result<(), Box<dyn Error>> {
+        let (hostname, port) = self.get_host_and_port().await?;
+        tokio::time::timeout(
+            self.ping.connect_timeout,
+            TcpStream::connect((hostname, port)),
+        )
+        .await
+        .map_err(|_| {
+            format!(
+                "Timed out connecting to {} after {:?}",
+                self.address, self.ping.connect_timeout
+            )
+        })??;
+        Ok(())
     }
 
     pub async fn get_host_and_port(&self) -> Result<(String, u16), Box<dyn Error>> {
@@ -79,7 +490,7 @@ impl MinecraftServer {
         Ok((result.ip.to_string(), result.port))
     }
     async fn send_packet<PACKET>(
-        stream_writer: &mut TCPNetworkEncoder<BufWriter<OwnedWriteHalf>>,
+        stream_writer: &mut TCPNetworkEncoder<BufWriter<WriteHalf<'_>>>,
         packet: &PACKET,
     ) -> Result<(), Box<dyn Error>>
     where
@@ -93,9 +504,51 @@ impl MinecraftServer {
     }
 }
 
+// Only the player count is required. Vanilla servers send the rest too,
+// but some proxies and plugins leave parts out.
+fn parse_status(json: &str) -> Result<BackendStatus, Box<dyn Error>> {
+    let response = serde_json::from_str::<'_, Value>(json)?;
+
+    let players = response
+        .get("players")
+        .ok_or("Response did not contain 'players' field")?;
+
+    let online_field = players
+        .get("online")
+        .ok_or("Response did not contain 'online' field")?;
+
+    let online = online_field.as_u64().ok_or("'online' field is not a u64")? as u32;
+    let version = response.get("version");
+    Ok(BackendStatus {
+        online,
+        max: players
+            .get("max")
+            .and_then(Value::as_u64)
+            .map(|max| max as u32),
+        version_name: version
+            .and_then(|version| version.get("name"))
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        protocol: version
+            .and_then(|version| version.get("protocol"))
+            .and_then(Value::as_i64)
+            .map(|protocol| protocol as i32),
+        description: response.get("description").cloned(),
+        favicon: response
+            .get("favicon")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::{
+        spawn_close_watching_backend, spawn_delayed_player_count_backend,
+        spawn_delayed_status_backend, spawn_flaky_player_count_backend,
+        spawn_handshake_recording_backend, spawn_query_backend,
+    };
 
     #[tokio::test]
     async fn test_backend_new() {
@@ -120,4 +573,259 @@ mod tests {
 
         println!("{} {}", host, port)
     }
+
+    #[tokio::test]
+    async fn test_retry_recovers_from_flaky_backend() {
+        let addr = spawn_flaky_player_count_backend(7, 1).await;
+        let backend = MinecraftServer::new(addr.to_string());
+
+        assert_eq!(backend.get_player_count().await.unwrap(), 7);
+        assert_eq!(backend.last_max_players(), Some(100));
+        assert!(backend.last_error().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_status_fields_are_parsed() {
+        let addr = spawn_delayed_status_backend(
+            serde_json::json!({
+                "version": { "name": "Paper 1.21.8", "protocol": 772 },
+                "players": { "online": 12, "max": 50 },
+                "description": { "text": "A backend" },
+                "favicon": "data:image/png;base64,AAAA",
+            }),
+            Duration::ZERO,
+        )
+        .await;
+        let backend = MinecraftServer::new(addr.to_string());
+
+        let status = backend.get_status().await.unwrap();
+        assert_eq!(
+            status,
+            BackendStatus {
+                online: 12,
+                max: Some(50),
+                version_name: Some("Paper 1.21.8".to_string()),
+                protocol: Some(772),
+                description: Some(serde_json::json!({ "text": "A backend" })),
+                favicon: Some("data:image/png;base64,AAAA".to_string()),
+            }
+        );
+        assert_eq!(backend.last_status(), Some(status));
+        assert_eq!(backend.get_player_count().await.unwrap(), 12);
+    }
+
+    #[test]
+    fn test_description_text_flattens_components() {
+        let status = |description: Value| BackendStatus {
+            description: Some(description),
+            ..BackendStatus::default()
+        };
+        assert_eq!(
+            status(Value::from("§aPlain")).description_text().as_deref(),
+            Some("§aPlain")
+        );
+        let component = serde_json::json!({
+            "text": "Welcome to ",
+            "extra": [{ "text": "the", "bold": true }, " network", [{ "text": "!" }]],
+        });
+        assert_eq!(
+            status(component).description_text().as_deref(),
+            Some("Welcome to the network!")
+        );
+    }
+
+    #[test]
+    fn test_status_only_requires_the_online_count() {
+        let status = parse_status(r#"{ "players": { "online": 3 } }"#).unwrap();
+        assert_eq!(
+            status,
+            BackendStatus {
+                online: 3,
+                ..BackendStatus::default()
+            }
+        );
+
+        let error = parse_status(r#"{ "players": { "max": 20 } }"#).unwrap_err();
+        assert!(error.to_string().contains("'online'"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_player_count_over_query() {
+        let addr = spawn_query_backend(12).await;
+        let backend = MinecraftServer {
+            count_source: BackendCountSource::Query,
+            ..MinecraftServer::new(addr.to_string())
+        };
+
+        assert_eq!(backend.get_player_count().await.unwrap(), 12);
+        assert_eq!(backend.last_player_count(), Some(12));
+    }
+
+    #[tokio::test]
+    async fn test_query_uses_its_own_port() {
+        let addr = spawn_query_backend(7).await;
+        let backend = MinecraftServer {
+            count_source: BackendCountSource::Query,
+            query_port: Some(addr.port()),
+            ..MinecraftServer::new("127.0.0.1:1".to_string())
+        };
+
+        assert_eq!(backend.get_player_count().await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_ping_sends_configured_protocol_version() {
+        let (addr, mut handshakes) = spawn_handshake_recording_backend().await;
+
+        let backend = MinecraftServer::new(addr.to_string());
+        backend.get_player_count().await.unwrap();
+        assert_eq!(handshakes.recv().await.unwrap().0, 772);
+
+        let backend = MinecraftServer {
+            ping: PingOptions {
+                protocol_version: 767,
+                ..PingOptions::default()
+            },
+            ..MinecraftServer::new(addr.to_string())
+        };
+        backend.get_player_count().await.unwrap();
+        assert_eq!(handshakes.recv().await.unwrap().0, 767);
+    }
+
+    #[tokio::test]
+    async fn test_ping_closes_the_connection() {
+        let (addr, mut closes) = spawn_close_watching_backend().await;
+
+        let backend = MinecraftServer::new(addr.to_string());
+        assert_eq!(backend.get_player_count().await.unwrap(), 0);
+        tokio::time::timeout(Duration::from_secs(1), closes.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ping_sends_forced_host() {
+        let (addr, mut handshakes) = spawn_handshake_recording_backend().await;
+
+        let backend = MinecraftServer::new(addr.to_string());
+        backend.get_player_count().await.unwrap();
+        assert_eq!(handshakes.recv().await.unwrap().1, "127.0.0.1");
+
+        let backend = MinecraftServer {
+            forced_host: Some("play.example.com".to_string()),
+            ..MinecraftServer::new(addr.to_string())
+        };
+        backend.get_player_count().await.unwrap();
+        assert_eq!(handshakes.recv().await.unwrap().1, "play.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_retries_are_bounded() {
+        let addr = spawn_flaky_player_count_backend(7, 2).await;
+        let backend = MinecraftServer {
+            ping: PingOptions {
+                retries: 1,
+                ..PingOptions::default()
+            },
+            ..MinecraftServer::new(addr.to_string())
+        };
+
+        assert!(backend.get_player_count().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failed_ping_records_last_error() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let backend = MinecraftServer::new(format!("127.0.0.1:{}", port));
+        assert!(backend.last_error().is_none());
+
+        let result = backend.get_player_count().await;
+        assert!(result.is_err());
+
+        let last_error = backend.clone().last_error().unwrap();
+        assert_eq!(last_error.message, result.unwrap_err().to_string());
+        assert!(last_error.message.contains("refused"));
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_status_packet_is_rejected() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // A SetCompression packet with a threshold of 256.
+            stream.write_all(&[0x03, 0x03, 0x80, 0x02]).await.unwrap();
+            let _ = tokio::io::AsyncReadExt::read(&mut stream, &mut [0u8; 64]).await;
+        });
+
+        let backend = MinecraftServer {
+            ping: PingOptions {
+                retries: 0,
+                ..PingOptions::default()
+            },
+            ..MinecraftServer::new(addr.to_string())
+        };
+        let error = backend.get_player_count().await.unwrap_err();
+        assert!(
+            error.to_string().contains("unexpected packet id 0x03"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_backend_times_out() {
+        let addr = spawn_delayed_player_count_backend(1, Duration::from_secs(30)).await;
+        let backend = MinecraftServer {
+            ping: PingOptions {
+                retries: 0,
+                timeout: Duration::from_millis(300),
+                ..PingOptions::default()
+            },
+            ..MinecraftServer::new(addr.to_string())
+        };
+
+        let started = Instant::now();
+        assert!(backend.get_player_count().await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_host_and_port_from_address() {
+        for (address, host, port) in [
+            ("127.0.0.1:25570", "127.0.0.1", 25570),
+            ("127.0.0.1", "127.0.0.1", 25565),
+            ("[::1]:25570", "::1", 25570),
+        ] {
+            let backend = MinecraftServer::new(address.to_string());
+            assert_eq!(
+                backend.get_host_and_port().await.unwrap(),
+                (host.to_string(), port),
+                "{}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn test_routed_connections_are_counted() {
+        let backend = MinecraftServer::new("127.0.0.1:25565".to_string());
+        let first = backend.route_connection();
+        let second = backend.clone().route_connection();
+        assert_eq!(backend.active_connections(), 2);
+
+        drop(first);
+        assert_eq!(backend.state.open_connections.load(Ordering::Relaxed), 1);
+        // Closed connections keep counting until they decay.
+        assert_eq!(backend.active_connections(), 2);
+        assert_eq!(backend.state.active_connections(Duration::ZERO), 1);
+
+        drop(second);
+        assert_eq!(backend.state.active_connections(Duration::ZERO), 0);
+        assert_eq!(backend.active_connections(), 0);
+    }
 }
@@ -0,0 +1,140 @@
+//! Command-line flags, and the `--check` mode that validates a config
+//! without starting the balancer.
+
+use crate::config::Config;
+use crate::finder;
+use crate::self_test;
+use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "A load balancer for Minecraft servers")]
+pub struct Args {
+    /// Config file to load, instead of the first of config.yaml, config.toml
+    /// and config.json in the working directory.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Validate the config and resolve every backend, then exit without
+    /// listening for players.
+    #[arg(long)]
+    pub check: bool,
+    /// With --check, also status-ping every backend, and fail unless all of
+    /// them answer.
+    #[arg(long, requires = "check")]
+    pub strict: bool,
+    /// Address to listen on, replacing the config's `listen` addresses.
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+    /// Port to listen on, replacing the port of every listen address.
+    #[arg(long)]
+    pub port: Option<u16>,
+}
+
+impl Args {
+    /// The addresses to listen on. Flags take precedence over the config's
+    /// `listen` addresses, which take precedence over the default.
+    pub fn listen_addresses(&self, config: &Config) -> Vec<SocketAddr> {
+        // Validated when the config was loaded.
+        let configured: Vec<SocketAddr> = config
+            .listen_addresses()
+            .iter()
+            .filter_map(|address| address.parse().ok())
+            .collect();
+        if let Some(bind) = self.bind {
+            let port = self
+                .port
+                .or(configured.first().map(SocketAddr::port))
+                .unwrap_or(25565);
+            return vec![SocketAddr::new(bind, port)];
+        }
+        configured
+            .into_iter()
+            .map(|address| SocketAddr::new(address.ip(), self.port.unwrap_or(address.port())))
+            .collect()
+    }
+}
+
+/// Resolves every backend `config` can route to, printing a line per
+/// backend. When `strict`, also status-pings them and prints the self-test
+/// summary. Returns whether all of them resolved, and answered if `strict`.
+pub async fn check(config: Config, strict: bool) -> bool {
+    let finder = match finder::get_server_finder(config.clone()) {
+        Ok(finder) => finder,
+        Err(error) => {
+            println!("Could not set up {:?} mode: {}", config.mode, error);
+            return false;
+        }
+    };
+    let servers = self_test::backends(&config, &*finder);
+
+    let mut failures = 0;
+    for server in &servers {
+        match server.get_host_and_port().await {
+            Ok((host, port)) => println!("  {} -> {}:{}", server.address, host, port),
+            Err(error) => {
+                println!("  {}: {}", server.address, error);
+                failures += 1;
+            }
+        }
+    }
+    finder.close();
+
+    if failures > 0 {
+        println!(
+            "{} of {} backend(s) did not resolve",
+            failures,
+            servers.len()
+        );
+        return false;
+    }
+    println!("Config OK, {} backend(s) resolved", servers.len());
+    if !strict {
+        return true;
+    }
+
+    let report = self_test::run(servers, config.poll_concurrency()).await;
+    for line in report.summary() {
+        println!("{}", line);
+    }
+    report.all_reachable()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_config;
+
+    fn listen(flags: &[&str], config: &str) -> Vec<String> {
+        let args =
+            Args::parse_from(std::iter::once("loadbalancer-rs").chain(flags.iter().copied()));
+        args.listen_addresses(&test_config(config))
+            .iter()
+            .map(SocketAddr::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_listen_address_precedence() {
+        let configured = "listen: [\"0.0.0.0:25570\", \"[::]:25570\"]";
+
+        assert_eq!(listen(&[], ""), ["0.0.0.0:25565"]);
+        assert_eq!(listen(&[], configured), ["0.0.0.0:25570", "[::]:25570"]);
+
+        assert_eq!(
+            listen(&["--port", "25580"], configured),
+            ["0.0.0.0:25580", "[::]:25580"]
+        );
+        assert_eq!(listen(&["--port", "25580"], ""), ["0.0.0.0:25580"]);
+
+        assert_eq!(
+            listen(&["--bind", "127.0.0.1"], configured),
+            ["127.0.0.1:25570"]
+        );
+        assert_eq!(listen(&["--bind", "::1"], ""), ["[::1]:25565"]);
+        assert_eq!(
+            listen(&["--bind", "127.0.0.1", "--port", "25580"], configured),
+            ["127.0.0.1:25580"]
+        );
+    }
+}
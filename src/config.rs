@@ -1,5 +1,14 @@
+use crate::address_resolver::validate_address;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fs, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::Path,
+    time::Duration,
+};
 use thiserror::Error;
 
 /* ---------------- Errors ---------------- */
@@ -12,6 +21,8 @@ pub enum ConfigError {
     Yaml(#[from] serde_yaml::Error),
     #[error("JSON parse error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("TOML parse error: {0}")]
+    Toml(#[from] toml::de::Error),
     #[error("Invalid configuration: {0}")]
     Invalid(String),
 }
@@ -24,6 +35,9 @@ pub enum Mode {
     Static,
     Geo,
     Http,
+    Hostname,
+    // A finder the embedding application registered, named in `custom`.
+    Custom,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
@@ -31,6 +45,21 @@ pub enum Mode {
 pub enum Algorithm {
     RoundRobin,
     LowestPlayerCount,
+    LowestLatency,
+    LowestLoadRatio,
+    LeastConnections,
+    // The lowest weighted sum of player count and latency, per `composite_weights`.
+    Composite,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlayerCountSource {
+    // Ping every backend and add up what they report.
+    #[default]
+    Ping,
+    // Count the players the balancer routed recently, without pinging.
+    Transfers,
 }
 
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
@@ -52,190 +81,1483 @@ pub enum HttpMethod {
     POST,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendCountSource {
+    // A server list ping over TCP.
+    #[default]
+    Status,
+    // The GameSpy4 Query protocol over UDP, on the server's `query_port` or
+    // else its game port. Needs `enable-query=true` on the backend.
+    Query,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct Server {
     pub name: Option<String>,
     pub address: String,
+    // Older configs gave the port on its own. Added to `address` when that
+    // names no port.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    // Prefix the balancer's status pings to this backend with a PROXY protocol
+    // v2 header. Players are transferred, never proxied, so no client
+    // connection reaches the backend through the balancer to carry one.
+    #[serde(default, alias = "send_proxy_protocol")]
+    pub ping_with_proxy_protocol: bool,
+    // How this backend's player count is read.
+    #[serde(default)]
+    pub count_source: BackendCountSource,
+    // The UDP port the `query` count source asks, when the backend's
+    // `query.port` differs from its game port.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query_port: Option<u16>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_players: Option<u32>,
+    // Most players routed here at once, counted like `least_connections`
+    // does. Backends at the limit are skipped until players leave.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<u32>,
+    // Hostname sent in the handshake of the balancer's status pings to this
+    // backend, instead of its resolved IP.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forced_host: Option<String>,
+    // Static groups only use a higher tier while every server of the lower
+    // ones is full or down.
+    #[serde(default)]
+    pub tier: u32,
+    // Where the backend is. Geo regions send each located client to the
+    // nearest of their servers that have coordinates.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coordinates: Option<Coordinates>,
+}
+
+/// A point on the globe, in degrees.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// How the `composite` algorithm weighs a backend's player count against its
+/// latency. Each is scaled to 0..=1 between the lowest and highest among the
+/// candidates, and the lowest weighted sum wins. Both weights default to 1.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompositeWeights {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player_count: Option<f64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<f64>,
+}
+
+impl CompositeWeights {
+    pub fn player_count(&self) -> f64 {
+        self.player_count.unwrap_or(1.0)
+    }
+
+    pub fn latency(&self) -> f64 {
+        self.latency.unwrap_or(1.0)
+    }
 }
 
 /* ---------------- Section Structures ---------------- */
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct StaticConfig {
     pub algorithm: Algorithm,
     pub servers: Vec<Server>,
+    // Capacity assumed for servers without their own `max_players`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_max_players: Option<u32>,
+    // Used by the composite algorithm.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub composite_weights: Option<CompositeWeights>,
+    // When set, the round-robin position is saved here and restored on startup.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_file: Option<String>,
+    // Server list version name for this group, instead of the global one.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_name: Option<String>,
+    // Server list motd while this mode is active, instead of the global one.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+}
+
+impl StaticConfig {
+    pub fn default_max_players(&self) -> u32 {
+        self.default_max_players.unwrap_or(100)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GeoProviderKind {
+    // The ipinfo.io Lite API, which needs a token.
+    #[default]
+    Ipinfo,
+    // A local MaxMind .mmdb database, such as GeoLite2-Country.
+    Maxmind,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct GeoConfig {
+    #[serde(default)]
+    pub provider: GeoProviderKind,
+    #[serde(default)]
     pub token: String,
-    pub regions: HashMap<String, Server>, // keys like "NA", "EU"
-    pub fallback: Server,
+    // Path to the .mmdb file used by the maxmind provider.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<String>,
+    // Keys are country codes ("GB"), continent codes ("EU") or "*"; the most
+    // specific match wins. Codes are case-insensitive. A region is a single
+    // server or a list, balanced with `region_algorithm`.
+    #[serde(deserialize_with = "map_of_one_or_many")]
+    pub regions: HashMap<String, Vec<Server>>,
+    // How players are spread over the servers of a region. Defaults to round robin.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_algorithm: Option<Algorithm>,
+    // Used when `region_algorithm` is composite.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub composite_weights: Option<CompositeWeights>,
+    // Tried in order when no region matches. A single `fallback` is also accepted.
+    #[serde(alias = "fallback", deserialize_with = "one_or_many")]
+    pub fallbacks: Vec<Server>,
+    // Region used for LAN and loopback clients, which can't be geolocated.
+    // Defaults to the fallback.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_region: Option<String>,
+    // Regions pinned to IP ranges (e.g. `"10.8.0.0/16": EU`), checked before
+    // any lookup. The most specific range wins.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub overrides: HashMap<IpNet, String>,
+    // Nearby regions to try, by weight, when a client's country and continent
+    // have no live server (e.g. `SA: { NA: 1 }`). Consulted before "*" and the
+    // fallbacks. Keys are country or continent codes; targets must be regions.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub adjacent_regions: HashMap<String, HashMap<String, u32>>,
+    // Server list version names by region key, shown to clients that region
+    // serves instead of the global one.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub version_names: HashMap<String, String>,
+    // Server list motd while this mode is active, instead of the global one.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+    // Server list motds by region key, shown to clients that region serves
+    // instead of the mode's or the global one.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub motds: HashMap<String, String>,
+    // Server icon paths by region key, shown to clients that region serves
+    // instead of the global `favicon`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub favicons: HashMap<String, String>,
+    // Show each client the player count of the backend it would be sent to,
+    // instead of the total across every region.
+    #[serde(default)]
+    pub regional_player_count: bool,
+    // Limit on requests to the ipinfo provider. Clients looked up past it go
+    // to the fallbacks.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_lookups_per_second: Option<u32>,
+    // After the ipinfo token is rejected this many times in a row, lookups
+    // stop and every player goes to the fallbacks until the config is
+    // reloaded. Defaults to 3.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_failure_limit: Option<u32>,
+    // Where the ipinfo provider caches its answers. Defaults to cache/geo.redb.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_file: Option<String>,
+}
+
+impl GeoConfig {
+    pub fn region_algorithm(&self) -> Algorithm {
+        self.region_algorithm.unwrap_or(Algorithm::RoundRobin)
+    }
+
+    pub fn max_lookups_per_second(&self) -> u32 {
+        self.max_lookups_per_second.unwrap_or(20)
+    }
+
+    pub fn auth_failure_limit(&self) -> u32 {
+        self.auth_failure_limit.unwrap_or(3)
+    }
+
+    pub fn cache_file(&self) -> &str {
+        self.cache_file.as_deref().unwrap_or("cache/geo.redb")
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct HttpConfig {
     pub endpoint: String,
     #[serde(default)]
     pub request_method: HttpMethod,
     #[serde(default)]
     pub headers: HashMap<String, String>,
-    pub fallback: Server,
+    #[serde(alias = "fallback", deserialize_with = "one_or_many")]
+    pub fallbacks: Vec<Server>,
+    // Server list motd while this mode is active, instead of the global one.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
 }
 
-/* ---------------- Root Config ---------------- */
+enum OneOrMany {
+    One(Server),
+    Many(Vec<Server>),
+}
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct Config {
-    pub mode: Mode,
-    pub motd: String,
+// Told apart by shape rather than with `untagged`, which reports any bad key
+// inside a server as "data did not match any variant" without naming it.
+impl<'de> Deserialize<'de> for OneOrMany {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let servers = if value.is_array() {
+            serde_json::from_value(value).map(OneOrMany::Many)
+        } else {
+            serde_json::from_value(value).map(OneOrMany::One)
+        };
+        servers.map_err(serde::de::Error::custom)
+    }
+}
 
-    // "static" and "http" are reserved words in Rust, so use rename.
-    #[serde(rename = "static")]
-    pub static_cfg: Option<StaticConfig>,
+impl From<OneOrMany> for Vec<Server> {
+    fn from(servers: OneOrMany) -> Self {
+        match servers {
+            OneOrMany::One(server) => vec![server],
+            OneOrMany::Many(servers) => servers,
+        }
+    }
+}
+
+// Accepts either a single server or a list, so configs written before
+// fallback chains existed keep loading.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<Server>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(OneOrMany::deserialize(deserializer)?.into())
+}
+
+// The same for every value of a map, so single-server geo regions keep loading.
+fn map_of_one_or_many<'de, D>(deserializer: D) -> Result<HashMap<String, Vec<Server>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let map = HashMap::<String, OneOrMany>::deserialize(deserializer)?;
+    Ok(map
+        .into_iter()
+        .map(|(key, servers)| (key, servers.into()))
+        .collect())
+}
+
+/// Routes players by the hostname they typed into their client. Patterns are
+/// either exact hostnames or `*.` wildcards matching any subdomain.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostnameConfig {
+    pub routes: HashMap<String, Server>, // keys like "na.example.com", "*.example.com"
+    #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    #[serde(rename = "geo")]
-    pub geo_cfg: Option<GeoConfig>,
-    #[serde(rename = "http")]
+    pub fallback: Option<Server>,
+    // Server list motd while this mode is active, instead of the global one.
+    #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub http_cfg: Option<HttpConfig>,
+    pub motd: Option<String>,
+}
 
+/// Selects a finder registered with `finder::register_finder`. The finder
+/// reads its own settings from `options`, which may hold anything.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomConfig {
+    pub finder: String,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "serde_json::Value::is_null")]
+    pub options: serde_json::Value,
+    // Server list motd while this mode is active, instead of the global one.
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub timeout_seconds: Option<u64>,
+    pub motd: Option<String>,
+}
+
+/// Routes players by the locale their client reports in the configuration
+/// state, ahead of the mode's own selection. Keys are languages like "fr" or
+/// full locales like "pt_br"; the most specific match wins.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct LocaleRoutingConfig {
+    pub routes: HashMap<String, Server>, // keys like "fr", "pt_br"
+}
+
+/// Sends players who reconnect soon after being transferred back to the same
+/// backend, ahead of locale routing and the mode's own selection.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AffinityConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    // How long a player's backend is remembered after the transfer.
+    // Defaults to 120.
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub log_level: Option<LogLevel>,
+    pub ttl_seconds: Option<u64>,
 }
 
-impl Config {
-    // Load from a YAML file path (blocking).
-    pub fn from_yaml_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
-        let raw = fs::read_to_string(path)?;
-        Self::from_yaml_str(&raw)
+impl AffinityConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_seconds.unwrap_or(120))
     }
+}
 
-    // Parse from a YAML string.
-    pub fn from_yaml_str(s: &str) -> Result<Self, ConfigError> {
-        let cfg: Config = serde_yaml::from_str(s)?;
-        cfg.validate()?;
-        Ok(cfg)
-    }
+/// IP ranges and usernames used by the allowlist and blocklist. Empty lists
+/// match nothing, so an allowlist only restricts what it actually lists.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessList {
+    #[serde(default)]
+    pub ips: Vec<IpNet>,
+    #[serde(default)]
+    pub usernames: Vec<String>,
+}
 
-    // (Optional) JSON loader if you ever want it.
-    #[allow(dead_code)]
-    pub fn from_json_str(s: &str) -> Result<Self, ConfigError> {
-        let cfg: Config = serde_json::from_str(s)?;
-        cfg.validate()?;
-        Ok(cfg)
+impl AccessList {
+    pub fn contains_ip(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.ips.iter().any(|net| net.contains(&ip))
     }
 
-    // Validate internal consistency.
-    pub fn validate(&self) -> Result<(), ConfigError> {
-        match self.mode {
-            Mode::Static => {
-                let sc = self.static_cfg.as_ref().ok_or_else(|| {
-                    ConfigError::Invalid("mode 'static' requires a 'static' section".into())
-                })?;
-                if sc.servers.is_empty() {
-                    return Err(ConfigError::Invalid(
-                        "static.servers must contain at least one server".into(),
-                    ));
-                }
-            }
-            Mode::Geo => {
-                let gc = self.geo_cfg.as_ref().ok_or_else(|| {
-                    ConfigError::Invalid("mode 'geo' requires a 'geo' section".into())
-                })?;
-                if gc.regions.is_empty() {
-                    return Err(ConfigError::Invalid(
-                        "geo.regions must contain at least one region entry".into(),
-                    ));
-                }
-            }
-            Mode::Http => {
-                let hc = self.http_cfg.as_ref().ok_or_else(|| {
-                    ConfigError::Invalid("mode 'http' requires an 'http' section".into())
-                })?;
-                if hc.endpoint.trim().is_empty() {
-                    return Err(ConfigError::Invalid("http.endpoint cannot be empty".into()));
-                }
-            }
-        }
-        Ok(())
+    pub fn contains_username(&self, username: &str) -> bool {
+        self.usernames
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(username))
     }
+}
 
-    pub fn timeout(&self) -> u64 {
-        self.timeout_seconds.unwrap_or(5)
-    }
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsUpstream {
+    // The resolver library's built-in public nameservers.
+    #[default]
+    Default,
+    // Whatever /etc/resolv.conf points at.
+    System,
+    // The servers listed in `dns.nameservers`.
+    Custom,
+}
 
-    pub fn log_level(&self) -> LogLevel {
-        self.log_level.unwrap_or_default()
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+    // The first address the resolver returns.
+    #[default]
+    Any,
+    // An A record when the host has one, otherwise whatever is available.
+    Ipv4,
+    // An AAAA record when the host has one, otherwise whatever is available.
+    Ipv6,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsConfig {
+    #[serde(default)]
+    pub upstream: DnsUpstream,
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+    // Which address family to connect to backends over.
+    #[serde(default)]
+    pub ip_preference: IpPreference,
+    // Upper bound on how long a resolved backend address is reused.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_cache_ttl_seconds: Option<u64>,
+}
+
+impl DnsConfig {
+    pub fn max_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.max_cache_ttl_seconds.unwrap_or(300))
     }
+}
 
-    pub fn default_config_str() -> &'static str {
-        r#"# Minecraft Server Load Balancer Configuration
-# --------------------------------------------
-# Select one of the modes below: 'static', 'geo', or 'http'
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    pub bind: String,
+}
 
-mode: static           # Options: static, geo, http
-motd: test123
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    pub bind: String,
+    // Required as a bearer token on every request when set.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
 
-# 1. Static Mode - Predefined list of servers with load balancing algorithm
-static:
-  algorithm: round_robin   # Options: round_robin, lowest_player_count
-  servers:
-    - name: "US-East"
-      address: "hypixel.net"
-    - name: "EU-West"
-      address: "hollowcube.net"
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    pub bind: String,
+}
 
-# 2. Geo Mode - Select server based on user's region (using a geo-location API)
-geo:
-  token: "YOUR-TOKEN"   # Your geolocation API endpoint
-  regions:
-    NA:
-      address: "us.example.com"
-    EU:
-      address: "eu.example.com"
-    ASIA:
-      address: "asia.example.com"
-  fallback:
-    address: "fallback.example.com"
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    // Whether to start in maintenance; it can also be toggled via the admin API.
+    #[serde(default)]
+    pub enabled: bool,
+    // Server list text while in maintenance.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+    // Shown to players who try to join while in maintenance.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
 
-# 3. HTTP Mode - Server address is fetched from a remote HTTP endpoint
-http:
-  endpoint: "https://serverselector.example.com/getserver"
-  request_method: GET      # Typically GET or POST
-  headers:
-    Authorization: "Bearer YOUR_API_TOKEN"
-  fallback:
-    address: "fallback.example.com"
-    port: 25565
+// Cookies stored on the client right before it is transferred, which the
+// backend can request back. Keys are namespaced, like "loadbalancer:origin".
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransferCookiesConfig {
+    // Fixed values, sent in key order.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub values: BTreeMap<String, String>,
+    // Key of a cookie holding an id generated for each player session, which
+    // is also written to the access log.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+}
 
-# Advanced options (optional)
-timeout_seconds: 5         # Maximum time to wait for server selection
-log_level: info            # Options: info, debug, warn, error
+// A server list motd for clients whose handshake protocol falls in a range.
+// Either bound can be left out.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProtocolMotd {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_protocol: Option<i32>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_protocol: Option<i32>,
+    pub motd: String,
+}
 
-"#
+impl ProtocolMotd {
+    pub fn matches(&self, protocol: i32) -> bool {
+        self.min_protocol.is_none_or(|min| protocol >= min)
+            && self.max_protocol.is_none_or(|max| protocol <= max)
     }
 }
 
-/* ---------------- Minimal Tests (can remove) ---------------- */
+// Longest cookie payload clients accept.
+const MAX_COOKIE_PAYLOAD: usize = 5120;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Leaves room for a handshake with the longest hostname, and a login.
+const MIN_PACKET_SIZE_LIMIT: usize = 1024;
 
-    #[test]
-    fn load_static_ok() {
-        let yaml = r#"
-        mode: static
-static:
-  algorithm: round_robin
-  servers:
-    - name: "A"
-      address: "a.example.com"
+/* ---------------- Root Config ---------------- */
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub mode: Mode,
+    // The server list motd. Defaults to "A Minecraft Load Balancer".
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub motd: Option<String>,
+    // Shown in the server list instead of `motd` while every backend is down.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline_motd: Option<String>,
+    // Player limit in the server list when no backend reports one. Otherwise
+    // the limits the backends report are added up.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_max_players: Option<u32>,
+    // The server list motd, icon and version name are mirrored from this
+    // backend, pinged along with the player count polls.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub primary_backend: Option<Server>,
+    // Path to a 64x64 PNG shown as the server icon.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub favicon: Option<String>,
+    // The version name shown in the server list. Defaults to "Loadbalancer".
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version_name: Option<String>,
+
+    // "static" and "http" are reserved words in Rust, so use rename.
+    #[serde(rename = "static")]
+    pub static_cfg: Option<StaticConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "geo")]
+    pub geo_cfg: Option<GeoConfig>,
+    #[serde(rename = "http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_cfg: Option<HttpConfig>,
+    #[serde(rename = "hostname")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname_cfg: Option<HostnameConfig>,
+    #[serde(rename = "custom")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_cfg: Option<CustomConfig>,
+
+    // Addresses players connect to. "[::]:25565" accepts both IPv4 and IPv6
+    // on most systems.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen: Option<Vec<String>>,
+
+    // Read a PROXY protocol header from every accepted connection.
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowlist: Option<AccessList>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocklist: Option<AccessList>,
+
+    // Players are transferred here when no backend can take them.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unrouteable_server: Option<Server>,
+    // Players whose locale has a route go there instead of to the mode's pick.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale_routing: Option<LocaleRoutingConfig>,
+    // Players reconnecting within the TTL go back to the backend they were
+    // last transferred to, while it can take them.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub affinity: Option<AffinityConfig>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer_cookies: Option<TransferCookiesConfig>,
+
+    // Protocol range advertised in status responses. Clients inside the range
+    // see their own protocol mirrored back.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_protocol_version: Option<u32>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_protocol_version: Option<u32>,
+    // Motds by client protocol range, such as an update reminder for
+    // outdated clients. The first matching range wins.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub protocol_motds: Vec<ProtocolMotd>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u64>,
+    // Limit on opening a connection to a backend, per ping attempt.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_ms: Option<u64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ping_retries: Option<u32>,
+    // Protocol version sent in the handshake of status pings to backends.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ping_protocol_version: Option<i32>,
+    // How long a client may take to send its handshake, legacy ping or
+    // PROXY header after connecting.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handshake_timeout_ms: Option<u64>,
+    // Largest packet a client may send, in bytes. Clients announcing a bigger
+    // one are disconnected before it is read.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_packet_size: Option<usize>,
+    // How long a client may take to send each packet after the handshake.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_timeout_seconds: Option<u64>,
+    // Each transfer waits a random delay of up to this long, so players
+    // transferred together, like after a restart, reach the backend spread out.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer_jitter_ms: Option<u64>,
+    // How often the total player count shown in the server list is refreshed.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player_count_poll_seconds: Option<u64>,
+    // Each poll interval is moved by up to this much either way, so balancers
+    // started together don't ping the backends in lockstep.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub player_count_poll_jitter_seconds: Option<u64>,
+    #[serde(default)]
+    pub player_count_source: PlayerCountSource,
+    // Most backends pinged at once by a player count poll or a selection
+    // that compares backends.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_concurrency: Option<NonZeroUsize>,
+    // Poll the player count in the background at startup instead of on the
+    // first status request.
+    #[serde(default)]
+    pub status_warmup: bool,
+    // Status-ping every backend at startup and log which ones answered.
+    #[serde(default)]
+    pub startup_self_test: bool,
+    // Rendered server list responses kept in memory.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_cache_size: Option<NonZeroUsize>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<LogLevel>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutdown_grace_seconds: Option<u64>,
+    // Kick players still logging in when shutting down instead of waiting for them.
+    #[serde(default)]
+    pub disconnect_on_shutdown: bool,
+    // Log one JSON line per player session, under the "access_log" target.
+    #[serde(default)]
+    pub access_log: bool,
+    // Backends run in offline mode, so players are given the UUID derived
+    // from their name, as the backend will, instead of the one they sent.
+    #[serde(default)]
+    pub offline_mode: bool,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns: Option<DnsConfig>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthConfig>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceConfig>,
+}
+
+impl Config {
+    // Load from a file path (blocking), picking the format from the extension:
+    // .toml, .json, or YAML for anything else. `${VAR}` and `${VAR:-default}`
+    // outside comments are replaced with environment variables first.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let raw = substitute_env_vars(&fs::read_to_string(path)?)?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Self::from_toml_str(&raw),
+            Some("json") => Self::from_json_str(&raw),
+            _ => Self::from_yaml_str(&raw),
+        }
+    }
+
+    // Parse from a YAML string.
+    pub fn from_yaml_str(s: &str) -> Result<Self, ConfigError> {
+        let cfg: Config = serde_yaml::from_str(s)?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    // Parse from a JSON string.
+    pub fn from_json_str(s: &str) -> Result<Self, ConfigError> {
+        let cfg: Config = serde_json::from_str(s)?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    // Parse from a TOML string.
+    pub fn from_toml_str(s: &str) -> Result<Self, ConfigError> {
+        let cfg: Config = toml::from_str(s)?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    // Validate internal consistency, reporting every problem at once so a
+    // broken config can be fixed in one pass.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigError::Invalid(format!(
+            "{} problem(s) found:\n  - {}",
+            problems.len(),
+            problems.join("\n  - ")
+        )))
+    }
+
+    /// Every validation problem in this config, each naming the section or
+    /// field it concerns.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        match self.mode {
+            Mode::Static => match &self.static_cfg {
+                None => problems.push("mode 'static' requires a 'static' section".into()),
+                Some(sc) => {
+                    if sc.servers.is_empty() {
+                        problems.push("static.servers must contain at least one server".into());
+                    }
+                    for (index, server) in sc.servers.iter().enumerate() {
+                        validate_server(
+                            &mut problems,
+                            &format!("static.servers[{}]", index),
+                            server,
+                        );
+                    }
+                    if let Some(weights) = &sc.composite_weights {
+                        validate_weights(&mut problems, "static.composite_weights", weights);
+                    }
+                }
+            },
+            Mode::Geo => match &self.geo_cfg {
+                None => problems.push("mode 'geo' requires a 'geo' section".into()),
+                Some(gc) => {
+                    if gc.regions.is_empty() {
+                        problems.push("geo.regions must contain at least one region entry".into());
+                    }
+                    match gc.provider {
+                        GeoProviderKind::Ipinfo if gc.token.trim().is_empty() => {
+                            problems.push("geo.token is required by the ipinfo provider".into());
+                        }
+                        GeoProviderKind::Maxmind if gc.database.is_none() => {
+                            problems
+                                .push("geo.database is required by the maxmind provider".into());
+                        }
+                        _ => {}
+                    }
+                    if gc.max_lookups_per_second == Some(0) {
+                        problems.push("geo.max_lookups_per_second must be at least 1".into());
+                    }
+                    if gc.auth_failure_limit == Some(0) {
+                        problems.push("geo.auth_failure_limit must be at least 1".into());
+                    }
+                    for (region, servers) in &gc.regions {
+                        let field = format!("geo.regions.{}", region);
+                        match servers.as_slice() {
+                            [server] => validate_server(&mut problems, &field, server),
+                            servers => validate_servers(&mut problems, &field, servers),
+                        }
+                    }
+                    let mut codes: Vec<String> = gc
+                        .regions
+                        .keys()
+                        .map(|key| key.to_ascii_uppercase())
+                        .collect();
+                    codes.sort();
+                    let mut repeated: Vec<&String> = codes
+                        .windows(2)
+                        .filter(|pair| pair[0] == pair[1])
+                        .map(|pair| &pair[0])
+                        .collect();
+                    repeated.dedup();
+                    for code in repeated {
+                        problems.push(format!("geo.regions lists '{}' more than once", code));
+                    }
+                    validate_servers(&mut problems, "geo.fallbacks", &gc.fallbacks);
+                    if let Some(weights) = &gc.composite_weights {
+                        validate_weights(&mut problems, "geo.composite_weights", weights);
+                    }
+                    let unknown_region = gc.local_region.as_ref().filter(|region| {
+                        !gc.regions
+                            .keys()
+                            .any(|key| key.eq_ignore_ascii_case(region))
+                    });
+                    if let Some(region) = unknown_region {
+                        problems.push(format!(
+                            "geo.local_region '{}' is not one of geo.regions",
+                            region
+                        ));
+                    }
+                    for (range, region) in &gc.overrides {
+                        if !gc
+                            .regions
+                            .keys()
+                            .any(|key| key.eq_ignore_ascii_case(region))
+                        {
+                            problems.push(format!(
+                                "geo.overrides.{} pins '{}', which is not one of geo.regions",
+                                range, region
+                            ));
+                        }
+                    }
+                    let per_region = [
+                        ("version_names", &gc.version_names),
+                        ("motds", &gc.motds),
+                        ("favicons", &gc.favicons),
+                    ];
+                    for (field, map) in per_region {
+                        for region in map.keys() {
+                            if !gc
+                                .regions
+                                .keys()
+                                .any(|key| key.eq_ignore_ascii_case(region))
+                            {
+                                problems.push(format!(
+                                    "geo.{}.{} is not one of geo.regions",
+                                    field, region
+                                ));
+                            }
+                        }
+                    }
+                    for (code, adjacent) in &gc.adjacent_regions {
+                        for (region, weight) in adjacent {
+                            if !gc
+                                .regions
+                                .keys()
+                                .any(|key| key.eq_ignore_ascii_case(region))
+                            {
+                                problems.push(format!(
+                                    "geo.adjacent_regions.{} lists '{}', which is not one of geo.regions",
+                                    code, region
+                                ));
+                            }
+                            if *weight == 0 {
+                                problems.push(format!(
+                                    "geo.adjacent_regions.{}.{} must have a weight of at least 1",
+                                    code, region
+                                ));
+                            }
+                        }
+                    }
+                }
+            },
+            Mode::Http => match &self.http_cfg {
+                None => problems.push("mode 'http' requires an 'http' section".into()),
+                Some(hc) => {
+                    if hc.endpoint.trim().is_empty() {
+                        problems.push("http.endpoint cannot be empty".into());
+                    }
+                    validate_servers(&mut problems, "http.fallbacks", &hc.fallbacks);
+                }
+            },
+            Mode::Hostname => match &self.hostname_cfg {
+                None => problems.push("mode 'hostname' requires a 'hostname' section".into()),
+                Some(hc) => {
+                    if hc.routes.is_empty() {
+                        problems.push("hostname.routes must contain at least one route".into());
+                    }
+                    for (pattern, server) in &hc.routes {
+                        validate_server(
+                            &mut problems,
+                            &format!("hostname.routes.{}", pattern),
+                            server,
+                        );
+                    }
+                    if let Some(server) = &hc.fallback {
+                        validate_server(&mut problems, "hostname.fallback", server);
+                    }
+                }
+            },
+            // Whether the finder is registered is only known once it is built.
+            Mode::Custom => match &self.custom_cfg {
+                None => problems.push("mode 'custom' requires a 'custom' section".into()),
+                Some(cc) => {
+                    if cc.finder.trim().is_empty() {
+                        problems.push("custom.finder cannot be empty".into());
+                    }
+                }
+            },
+        }
+        if let Some(server) = &self.unrouteable_server {
+            validate_server(&mut problems, "unrouteable_server", server);
+        }
+        if let Some(server) = &self.primary_backend {
+            validate_server(&mut problems, "primary_backend", server);
+        }
+        if let Some(routing) = &self.locale_routing {
+            for (locale, server) in &routing.routes {
+                if locale.trim().is_empty() {
+                    problems.push("locale_routing.routes cannot have an empty locale".into());
+                }
+                validate_server(
+                    &mut problems,
+                    &format!("locale_routing.routes.{}", locale),
+                    server,
+                );
+            }
+        }
+        if self
+            .affinity
+            .as_ref()
+            .is_some_and(|affinity| affinity.ttl_seconds == Some(0))
+        {
+            problems.push("affinity.ttl_seconds must be at least 1".into());
+        }
+        if let Some(cookies) = &self.transfer_cookies {
+            for (key, value) in &cookies.values {
+                validate_cookie_key(&mut problems, "transfer_cookies.values", key);
+                if value.len() > MAX_COOKIE_PAYLOAD {
+                    problems.push(format!(
+                        "transfer_cookies.values.{} is longer than {} bytes",
+                        key, MAX_COOKIE_PAYLOAD
+                    ));
+                }
+            }
+            if let Some(key) = &cookies.session_id {
+                validate_cookie_key(&mut problems, "transfer_cookies.session_id", key);
+            }
+        }
+        for (index, variant) in self.protocol_motds.iter().enumerate() {
+            let min = variant.min_protocol.unwrap_or(i32::MIN);
+            let max = variant.max_protocol.unwrap_or(i32::MAX);
+            if min > max {
+                problems.push(format!(
+                    "protocol_motds[{}] has min_protocol {} above max_protocol {}",
+                    index, min, max
+                ));
+            }
+        }
+        if self.max_packet_size() < MIN_PACKET_SIZE_LIMIT {
+            problems.push(format!(
+                "max_packet_size must be at least {} bytes",
+                MIN_PACKET_SIZE_LIMIT
+            ));
+        }
+        let jitter = self.player_count_poll_jitter();
+        if !jitter.is_zero() && jitter >= self.player_count_poll_interval() {
+            problems.push(
+                "player_count_poll_jitter_seconds must be less than player_count_poll_seconds"
+                    .into(),
+            );
+        }
+        let transfer_jitter = self.transfer_jitter();
+        if !transfer_jitter.is_zero() && transfer_jitter >= self.read_timeout() {
+            problems.push("transfer_jitter_ms must be less than read_timeout_seconds".into());
+        }
+        if let Some(listen) = &self.listen {
+            if listen.is_empty() {
+                problems.push("listen must contain at least one address".into());
+            }
+            for address in listen {
+                if address.parse::<SocketAddr>().is_err() {
+                    problems.push(format!(
+                        "listen address '{}' is not an ip:port pair",
+                        address
+                    ));
+                }
+            }
+        }
+        problems
+    }
+
+    pub fn is_ip_allowed(&self, ip: IpAddr) -> bool {
+        if self.blocklist.as_ref().is_some_and(|list| list.contains_ip(ip)) {
+            return false;
+        }
+        match &self.allowlist {
+            Some(list) if !list.ips.is_empty() => list.contains_ip(ip),
+            _ => true,
+        }
+    }
+
+    pub fn is_username_allowed(&self, username: &str) -> bool {
+        if self
+            .blocklist
+            .as_ref()
+            .is_some_and(|list| list.contains_username(username))
+        {
+            return false;
+        }
+        match &self.allowlist {
+            Some(list) if !list.usernames.is_empty() => list.contains_username(username),
+            _ => true,
+        }
+    }
+
+    /// The protocol to advertise to a client that sent `client_protocol` in
+    /// its handshake.
+    pub fn advertised_protocol(&self, client_protocol: i32) -> u32 {
+        let min = self.min_protocol_version.unwrap_or(766);
+        let protocol = u32::try_from(client_protocol).unwrap_or(0).max(min);
+        match self.max_protocol_version {
+            Some(max) => protocol.min(max.max(min)),
+            None => protocol,
+        }
+    }
+
+    pub fn listen_addresses(&self) -> Vec<String> {
+        self.listen
+            .clone()
+            .unwrap_or_else(|| vec!["0.0.0.0:25565".to_string()])
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout_seconds.unwrap_or(5)
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms.unwrap_or(2000))
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_millis(self.handshake_timeout_ms.unwrap_or(5000))
+    }
+
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size.unwrap_or(2 * 1024 * 1024)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout_seconds.unwrap_or(30))
+    }
+
+    pub fn transfer_jitter(&self) -> Duration {
+        Duration::from_millis(self.transfer_jitter_ms.unwrap_or(0))
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.shutdown_grace_seconds.unwrap_or(10))
+    }
+
+    pub fn ping_retries(&self) -> u32 {
+        self.ping_retries.unwrap_or(2)
+    }
+
+    pub fn ping_protocol_version(&self) -> i32 {
+        self.ping_protocol_version.unwrap_or(772)
+    }
+
+    pub fn player_count_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.player_count_poll_seconds.unwrap_or(15))
+    }
+
+    pub fn player_count_poll_jitter(&self) -> Duration {
+        Duration::from_secs(self.player_count_poll_jitter_seconds.unwrap_or(0))
+    }
+
+    pub fn poll_concurrency(&self) -> usize {
+        self.poll_concurrency.map_or(8, NonZeroUsize::get)
+    }
+
+    pub fn status_cache_size(&self) -> NonZeroUsize {
+        self.status_cache_size
+            .unwrap_or(NonZeroUsize::new(256).unwrap())
+    }
+
+    pub fn version_name(&self) -> String {
+        self.version_name
+            .clone()
+            .unwrap_or_else(|| "Loadbalancer".to_string())
+    }
+
+    /// The server list motd: the active mode's, if it sets one, or the global one.
+    pub fn status_motd(&self) -> String {
+        let mode_motd = match self.mode {
+            Mode::Static => self.static_cfg.as_ref().and_then(|c| c.motd.as_ref()),
+            Mode::Geo => self.geo_cfg.as_ref().and_then(|c| c.motd.as_ref()),
+            Mode::Http => self.http_cfg.as_ref().and_then(|c| c.motd.as_ref()),
+            Mode::Hostname => self.hostname_cfg.as_ref().and_then(|c| c.motd.as_ref()),
+            Mode::Custom => self.custom_cfg.as_ref().and_then(|c| c.motd.as_ref()),
+        };
+        mode_motd
+            .or(self.motd.as_ref())
+            .cloned()
+            .unwrap_or_else(|| "A Minecraft Load Balancer".to_string())
+    }
+
+    pub fn log_level(&self) -> LogLevel {
+        self.log_level.unwrap_or_default()
+    }
+
+    pub fn default_config_str() -> &'static str {
+        r#"# Minecraft Server Load Balancer Configuration
+# --------------------------------------------
+# Select one of the modes below: 'static', 'geo', 'http', 'hostname', or 'custom'
+# Values can come from environment variables: ${NAME:-default} falls back to 'default' when NAME is unset
+# Write $${ for a literal ${; variables in comments are never substituted
+# Unknown keys are rejected, so a misspelled option fails to load instead of being ignored
+
+mode: static           # Options: static, geo, http, hostname, custom (set custom.finder and custom.options)
+motd: "A Minecraft Load Balancer"   # Server list text
+# offline_motd: "Down for maintenance"   # Server list text while every backend is down
+# favicon: "server-icon.png"             # 64x64 PNG shown as the server icon
+# status_max_players: 1000               # Server list player limit when no backend reports one; otherwise theirs are added up
+# primary_backend:                       # Mirror this backend's motd, icon and version name in the server list
+#   address: "hub.example.com"
+# version_name: "Loadbalancer"           # Version shown in the server list; static and geo mode can override it
+
+# 1. Static Mode - Predefined list of servers with load balancing algorithm
+static:
+  algorithm: round_robin   # Options: round_robin, lowest_player_count, lowest_latency, lowest_load_ratio, least_connections, composite
+  # composite_weights:       # For composite: lowest weighted sum of player count and latency, each scaled 0-1 across the servers
+  #   player_count: 1
+  #   latency: 2
+  default_max_players: 100 # Capacity used by lowest_load_ratio and tiers when a server sets no max_players
+  # state_file: "cache/rotation.json"   # Keep the round-robin position across restarts
+  # version_name: "Network A"           # Server list version name for this group
+  # motd: "Network A"                   # Server list motd in this mode; geo, http and hostname mode take one too
+  servers:
+    - name: "US-East"
+      address: "hypixel.net"
+      max_players: 200
+    - name: "EU-West"
+      address: "hollowcube.net"
+      # ping_with_proxy_protocol: true   # Status pings only, naming the balancer; players are transferred, not proxied
+      # count_source: query         # Options: status, query (UDP Query protocol, on the game port by default)
+      # query_port: 25575           # Query on this UDP port instead, if the backend's query.port differs
+      # forced_host: "play.example.com"   # Hostname sent when pinging, for backends routing by virtual host
+      # tier: 1                     # Overflow: only used while every tier 0 server is full or down
+      # max_connections: 500        # Skipped while this many players are routed here; all full kicks with "server full"
+
+# 2. Geo Mode - Select server based on user's region (using a geo-location API)
+geo:
+  provider: ipinfo      # Options: ipinfo, maxmind
+  token: "YOUR-TOKEN"   # Your ipinfo.io token
+  # database: "GeoLite2-Country.mmdb"   # Used by the maxmind provider instead of a token
+  regions:
+    NA:
+      address: "us.example.com"
+    # US:               # With a maxmind city database, clients go to the nearest server with coordinates
+    #   - address: "us-east.example.com"
+    #     coordinates: { latitude: 39.0, longitude: -77.5 }
+    #   - address: "us-west.example.com"
+    #     coordinates: { latitude: 45.6, longitude: -121.2 }
+    EU:                 # A region can also list several servers
+      - address: "eu1.example.com"
+      - address: "eu2.example.com"
+    AS:
+      address: "asia.example.com"
+    GB:                 # Country codes take precedence over continent codes; case doesn't matter
+      address: "uk.example.com"
+    "*":                # Any other location, before falling back
+      address: "us.example.com"
+  fallbacks:            # Tried in order; unreachable ones are skipped
+    - address: "fallback.example.com"
+    - address: "fallback2.example.com"
+  # local_region: EU    # Region for LAN/loopback clients instead of the fallback
+  # overrides:          # Regions pinned to IP ranges, skipping the lookup; the most specific range wins
+  #   "10.8.0.0/16": EU
+  # adjacent_regions:   # Nearby regions by weight, for clients whose own region has no live server
+  #   SA: { NA: 3, EU: 1 }
+  # motds:              # Server list motd by region; other clients see the mode's or the global one
+  #   EU: "Welcome, Europe"
+  # favicons:           # Server icon by region; other clients see the global one
+  #   EU: "icons/eu.png"
+  # version_names:      # Server list version name by region; other clients see the global one
+  #   EU: "Loadbalancer EU"
+  #   NA: "Loadbalancer NA"
+  # regional_player_count: false   # Show clients their own region's player count instead of the total
+  # max_lookups_per_second: 20     # ipinfo requests allowed per second; clients past it go to the fallbacks
+  # auth_failure_limit: 3          # Rejected ipinfo tokens in a row before lookups stop and everyone goes to the fallbacks
+  # cache_file: "cache/geo.redb"   # Where ipinfo answers are cached, so each address is looked up once
+  # region_algorithm: round_robin   # Spreads players over a region's servers; same options as static mode
+  # composite_weights: { player_count: 1, latency: 2 }   # For a composite region_algorithm
+
+# 3. HTTP Mode - Server address is fetched from a remote HTTP endpoint
+# It is sent the player's ip, hostname, username and locale, as the query string of a GET or the JSON body of a POST,
+# and answers { "address": "mc.example.com", "port": 25565 }, or several servers to pick between by weight:
+# { "servers": [{ "address": "a.example.com", "weight": 2 }, { "address": "b.example.com:25566" }] }
+http:
+  endpoint: "https://serverselector.example.com/getserver"
+  request_method: GET      # Typically GET or POST
+  headers:
+    Authorization: "Bearer YOUR_API_TOKEN"
+  fallbacks:
+    - address: "fallback.example.com"
+
+# 4. Hostname Mode - Select server based on the address the player connected to
+hostname:
+  routes:
+    "na.example.com":
+      address: "us.example.com"
+    "*.eu.example.com":    # Any subdomain of eu.example.com
+      address: "eu.example.com"
+  fallback:                # Optional, players on unknown hostnames are otherwise kicked
+    address: "fallback.example.com"
+
+# 5. Custom Mode - A finder registered by an application embedding the load balancer
+# custom:
+#   finder: "my_finder"    # The name it was registered under
+#   options:               # Passed to the finder as is
+#     servers: ["a.example.com", "b.example.com"]
+
+# Access control (optional). IP entries are CIDR ranges, use /32 for a single address.
+# blocklist:
+#   ips: ["203.0.113.0/24"]
+#   usernames: ["Griefer"]
+# allowlist:               # Only listed players may join, e.g. during maintenance
+#   usernames: ["Admin"]
+
+# Advanced options (optional)
+# listen: ["[::]:25565"]   # Defaults to 0.0.0.0:25565; "[::]" also accepts IPv6 players
+min_protocol_version: 766  # Lowest protocol advertised in the server list (766 = 1.20.5)
+# max_protocol_version: 772
+# protocol_motds:   # Server list motd by client protocol; the first matching range wins
+#   - max_protocol: 766
+#     motd: "Please update to 1.21\nOlder versions can't join"   # \n starts a second line
+# accept_proxy_protocol: true   # Set when running behind a proxy that sends a PROXY header
+# unrouteable_server:      # Lobby for players no backend can take, instead of kicking them
+#   address: "lobby.example.com"
+# locale_routing:          # Send players by client language, before the mode picks a backend
+#   routes:
+#     "fr":
+#       address: "fr.example.com"
+#     "pt_br":             # A full locale wins over its language
+#       address: "br.example.com"
+# affinity:                # Send players who reconnect soon after a transfer back to the same backend
+#   enabled: true
+#   ttl_seconds: 120       # How long the backend is remembered; a backend that is down or full is skipped
+# transfer_cookies:        # Stored on the client before each transfer, for the backend to read back
+#   values:
+#     "loadbalancer:origin": "eu-1"
+#   session_id: "loadbalancer:session"   # Random per-session id, also written to the access log
+timeout_seconds: 5         # Maximum time to wait for server selection
+ping_retries: 2            # Extra status ping attempts per backend, within timeout_seconds
+# ping_protocol_version: -1  # Protocol sent when pinging backends, defaults to 772; -1 is what vanilla clients send to ask for any version
+connect_timeout_ms: 2000   # Limit on connecting to a backend, per ping attempt
+handshake_timeout_ms: 5000 # Clients sending no handshake within this are dropped
+read_timeout_seconds: 30   # Clients are dropped when a packet takes longer than this after the handshake
+# transfer_jitter_ms: 500  # Random delay of up to this before each transfer, spreading out reconnect storms
+max_packet_size: 2097152   # Clients announcing a bigger packet, in bytes, are dropped before it is read
+player_count_poll_seconds: 15   # How often the server list player count is refreshed
+player_count_poll_jitter_seconds: 3   # Each refresh comes up to this much earlier or later, spreading out polls
+player_count_source: ping  # Options: ping, transfers (players routed in the last two minutes, no backend pings)
+poll_concurrency: 8        # Most backends pinged at once when polling player counts or comparing backends
+status_cache_size: 256     # Server list responses kept in memory
+status_warmup: true        # Poll player counts at startup so the first server list ping is fast
+startup_self_test: true    # Status-ping every backend at startup and log which are unreachable; --check --strict fails on them
+log_level: info            # Options: trace, debug, info, warn, error
+shutdown_grace_seconds: 10 # How long to wait for open connections on SIGINT/SIGTERM
+disconnect_on_shutdown: false
+access_log: false          # Log a JSON line with the client, username and backend of every player session
+offline_mode: false        # Backends are offline-mode: log players in with the UUID derived from their name
+
+# DNS resolution of backend addresses (optional)
+# dns:
+#   upstream: system             # Options: default, system, custom
+#   nameservers: ["9.9.9.9", "149.112.112.112:53"]   # Used with 'custom'
+#   max_cache_ttl_seconds: 300   # Never reuse a resolved address for longer than this
+#   ip_preference: any          # Options: any, ipv4, ipv6 (falls back to the other family if the host has no such address)
+
+# Prometheus metrics (optional)
+# metrics:
+#   enabled: true
+#   bind: "127.0.0.1:9100"
+
+# Admin HTTP API (optional): GET /backends, POST /backends/{address}/drain and /backends/{address}/enable,
+# POST /maintenance/enable and /maintenance/disable
+# admin:
+#   enabled: true
+#   bind: "127.0.0.1:9101"
+#   token: "change-me"           # Sent as 'Authorization: Bearer change-me'
+
+# Health probes (optional): GET /healthz while the process runs, GET /readyz once a backend
+# answered its last player count poll, or with player_count_source: transfers, once one takes players
+# and hasn't failed its last ping
+# health:
+#   enabled: true
+#   bind: "0.0.0.0:9102"
+
+# Maintenance mode (optional): show a maintenance motd and turn every player away
+# maintenance:
+#   enabled: false
+#   motd: "Under maintenance"
+#   message: "The server is under maintenance, please try again later."
+
+"#
+    }
+}
+
+// Expands `${VAR}` from the environment, and `${VAR:-default}` to `default`
+// when VAR is unset or empty. An unset variable without a default is an
+// error rather than an empty value, which would fail in less obvious ways.
+// Comments are left alone, and `$${` is a literal `${`.
+fn substitute_env_vars(raw: &str) -> Result<String, ConfigError> {
+    let mut expanded = String::with_capacity(raw.len());
+    for line in raw.split_inclusive('\n') {
+        let (text, comment) = line.split_at(comment_start(line));
+        substitute_line(&mut expanded, text)?;
+        expanded.push_str(comment);
+    }
+    Ok(expanded)
+}
+
+// Where the `#` comment on `line` starts, or its length when it has none. In
+// both YAML and TOML a `#` starts one at the start of a line or after
+// whitespace, outside quotes.
+fn comment_start(line: &str) -> usize {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (index, c) in line.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(open) if c == open => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' && previous.is_whitespace() => return index,
+            None => {}
+        }
+        previous = c;
+    }
+    line.len()
+}
+
+fn substitute_line(expanded: &mut String, mut rest: &str) -> Result<(), ConfigError> {
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let Some(length) = rest[start..].find('}') else {
+            break;
+        };
+        expanded.push_str(&rest[..start]);
+        let expression = &rest[start + 2..start + length];
+        let (name, default) = match expression.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (expression, None),
+        };
+        let value = std::env::var(name)
+            .ok()
+            .filter(|value| !value.is_empty() || default.is_none())
+            .or_else(|| default.map(str::to_string))
+            .ok_or_else(|| {
+                ConfigError::Invalid(format!("environment variable {} is not set", name))
+            })?;
+        expanded.push_str(&value);
+        rest = &rest[start + length + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(())
+}
+
+// Checks the address is a well-formed host or host:port, without resolving it.
+fn validate_server(problems: &mut Vec<String>, field: &str, server: &Server) {
+    if let Err(error) = validate_address(&server.address) {
+        problems.push(format!(
+            "{}.address '{}' is invalid: {}",
+            field, server.address, error
+        ));
+    }
+    if let Some(coordinates) = server.coordinates {
+        if !(-90.0..=90.0).contains(&coordinates.latitude) {
+            problems.push(format!(
+                "{}.coordinates.latitude must be between -90 and 90",
+                field
+            ));
+        }
+        if !(-180.0..=180.0).contains(&coordinates.longitude) {
+            problems.push(format!(
+                "{}.coordinates.longitude must be between -180 and 180",
+                field
+            ));
+        }
+    }
+}
+
+fn validate_weights(problems: &mut Vec<String>, field: &str, weights: &CompositeWeights) {
+    for (name, weight) in [
+        ("player_count", weights.player_count()),
+        ("latency", weights.latency()),
+    ] {
+        if !weight.is_finite() || weight < 0.0 {
+            problems.push(format!("{}.{} must be a number of at least 0", field, name));
+        }
+    }
+    if weights.player_count() == 0.0 && weights.latency() == 0.0 {
+        problems.push(format!("{} cannot both be 0", field));
+    }
+}
+
+fn validate_servers(problems: &mut Vec<String>, field: &str, servers: &[Server]) {
+    if servers.is_empty() {
+        problems.push(format!("{} must contain at least one server", field));
+    }
+    for (index, server) in servers.iter().enumerate() {
+        validate_server(problems, &format!("{}[{}]", field, index), server);
+    }
+}
+
+// Cookie keys are resource locations: a namespace and a path, both lowercase.
+fn validate_cookie_key(problems: &mut Vec<String>, field: &str, key: &str) {
+    let valid = key.split_once(':').is_some_and(|(namespace, path)| {
+        let allowed = |c: char| matches!(c, 'a'..='z' | '0'..='9' | '_' | '-' | '.');
+        !namespace.is_empty()
+            && !path.is_empty()
+            && namespace.chars().all(allowed)
+            && path.chars().all(|c| allowed(c) || c == '/')
+    });
+    if !valid {
+        problems.push(format!(
+            "{} key '{}' is not a namespaced key like 'loadbalancer:origin'",
+            field, key
+        ));
+    }
+}
+
+/* ---------------- Minimal Tests (can remove) ---------------- */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_static_ok() {
+        let yaml = r#"
+        mode: static
+static:
+  algorithm: round_robin
+  servers:
+    - name: "A"
+      address: "a.example.com"
     - address: "b.example.com"
 timeout_seconds: 10
 log_level: debug
@@ -252,8 +1574,236 @@ log_level: debug
 mode: http
 timeout_seconds: 3
 "#;
-        let err = Config::from_yaml_str(yaml).unwrap_err();
-        assert!(matches!(err, ConfigError::Invalid(_)));
+        let err = Config::from_yaml_str(yaml).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_problem() {
+        let err = Config::from_yaml_str("mode: statik\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown variant `statik`"), "{}", err);
+        assert!(err.contains("expected one of `static`"), "{}", err);
+        assert!(err.contains("line 1"), "{}", err);
+
+        let err = Config::from_yaml_str("mode: static\ntimeout_seconds: soon\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("timeout_seconds"), "{}", err);
+        assert!(err.contains("expected u64"), "{}", err);
+        assert!(err.contains("line 2"), "{}", err);
+
+        let err = Config::from_yaml_str("mode: static\nstatic: [\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        let err = Config::from_yaml_str(
+            "mode: static\nstatic:\n  algoritm: round_robin\n  servers: []\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("unknown field `algoritm`"), "{}", err);
+        assert!(err.contains("expected one of `algorithm`"), "{}", err);
+        assert!(err.contains("line 3"), "{}", err);
+
+        let typo = format!(
+            "{}timeout_secnds: 5\n",
+            static_with_address("a.example.com")
+        );
+        let err = Config::from_yaml_str(&typo).unwrap_err().to_string();
+        assert!(err.contains("unknown field `timeout_secnds`"), "{}", err);
+
+        let err = Config::from_json_str(r#"{"mode": "static", "serverss": []}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown field `serverss`"), "{}", err);
+    }
+
+    #[test]
+    fn transfer_cookie_keys_are_validated() {
+        let cookies = |key: &str| {
+            format!(
+                "{}transfer_cookies:\n  values:\n    \"{}\": \"eu-1\"\n",
+                static_with_address("a.example.com"),
+                key
+            )
+        };
+        for key in ["loadbalancer:origin", "lb:session/id", "minecraft:a.b-c_d"] {
+            assert!(Config::from_yaml_str(&cookies(key)).is_ok(), "{}", key);
+        }
+        for key in ["origin", ":origin", "lb:", "LB:origin", "lb:with space"] {
+            let err = Config::from_yaml_str(&cookies(key))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("is not a namespaced key"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn max_packet_size_has_a_floor() {
+        let config = |size: usize| {
+            format!(
+                "{}max_packet_size: {}\n",
+                static_with_address("a.example.com"),
+                size
+            )
+        };
+        assert_eq!(
+            Config::from_yaml_str(&config(4096))
+                .unwrap()
+                .max_packet_size(),
+            4096
+        );
+        let err = Config::from_yaml_str(&config(100)).unwrap_err().to_string();
+        assert!(err.contains("max_packet_size must be at least"), "{}", err);
+    }
+
+    #[test]
+    fn composite_weights() {
+        let yaml = r#"
+mode: static
+static:
+  algorithm: composite
+  composite_weights:
+    latency: 2.5
+  servers:
+    - address: "a.example.com"
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        let weights = cfg.static_cfg.unwrap().composite_weights.unwrap();
+        assert_eq!(weights.player_count(), 1.0);
+        assert_eq!(weights.latency(), 2.5);
+
+        let negative = yaml.replace("latency: 2.5", "latency: -1");
+        let err = Config::from_yaml_str(&negative).unwrap_err().to_string();
+        assert!(
+            err.contains("static.composite_weights.latency must be a number of at least 0"),
+            "{}",
+            err
+        );
+
+        let zero = yaml.replace("latency: 2.5", "latency: 0\n    player_count: 0");
+        let err = Config::from_yaml_str(&zero).unwrap_err().to_string();
+        assert!(
+            err.contains("static.composite_weights cannot both be 0"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn transfer_jitter_stays_below_the_read_timeout() {
+        let config = |jitter: u64| {
+            format!(
+                "{}read_timeout_seconds: 2\ntransfer_jitter_ms: {}\n",
+                static_with_address("a.example.com"),
+                jitter
+            )
+        };
+        assert_eq!(
+            Config::from_yaml_str(&config(1500))
+                .unwrap()
+                .transfer_jitter(),
+            Duration::from_millis(1500)
+        );
+        let err = Config::from_yaml_str(&config(2000))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("transfer_jitter_ms must be less than read_timeout_seconds"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn nested_unknown_fields_are_named() {
+        let yaml = "mode: geo\ngeo:\n  token: abc\n  regions:\n    EU:\n      address: eu.example.com\n  fallback:\n    address: fallback.example.com\n    prot: 25565\n";
+        let err = Config::from_yaml_str(yaml).unwrap_err().to_string();
+        assert!(err.contains("unknown field `prot`"), "{}", err);
+
+        let list = yaml.replace("    EU:\n      address", "    EU:\n      - adress");
+        let err = Config::from_yaml_str(&list).unwrap_err().to_string();
+        assert!(err.contains("unknown field `adress`"), "{}", err);
+    }
+
+    // The config written by the first release, which gave the http
+    // fallback's port on its own.
+    const FIRST_DEFAULT_CONFIG: &str = r#"# --------------------------------------------
+# Select one of the modes below: 'static', 'geo', or 'http'
+
+mode: static           # Options: static, geo, http
+motd: test123
+
+# 1. Static Mode - Predefined list of servers with load balancing algorithm
+static:
+  algorithm: round_robin   # Options: round_robin, lowest_player_count
+  servers:
+    - name: "US-East"
+      address: "hypixel.net"
+    - name: "EU-West"
+      address: "hollowcube.net"
+
+# 2. Geo Mode - Select server based on user's region (using a geo-location API)
+geo:
+  token: "YOUR-TOKEN"   # Your geolocation API endpoint
+  regions:
+    NA:
+      address: "us.example.com"
+    EU:
+      address: "eu.example.com"
+    ASIA:
+      address: "asia.example.com"
+  fallback:
+    address: "fallback.example.com"
+
+# 3. HTTP Mode - Server address is fetched from a remote HTTP endpoint
+# It is sent the player's ip, hostname, username and locale, as the query string of a GET or the JSON body of a POST,
+# and answers { "address": "mc.example.com", "port": 25565 }, or several servers to pick between by weight:
+# { "servers": [{ "address": "a.example.com", "weight": 2 }, { "address": "b.example.com:25566" }] }
+http:
+  endpoint: "https://serverselector.example.com/getserver"
+  request_method: GET      # Typically GET or POST
+  headers:
+    Authorization: "Bearer YOUR_API_TOKEN"
+  fallback:
+    address: "fallback.example.com"
+    port: 25565
+
+# Advanced options (optional)
+timeout_seconds: 5         # Maximum time to wait for server selection
+log_level: info            # Options: info, debug, warn, error
+
+"#;
+
+    #[test]
+    fn first_default_config_still_loads() {
+        let cfg = Config::from_yaml_str(FIRST_DEFAULT_CONFIG).unwrap();
+        let fallback = &cfg.http_cfg.unwrap().fallbacks[0];
+        assert_eq!(fallback.address, "fallback.example.com");
+        assert_eq!(fallback.port, Some(25565));
+    }
+
+    #[test]
+    fn proxy_protocol_pings_keep_their_old_name() {
+        let yaml = r#"
+mode: static
+static:
+  algorithm: round_robin
+  servers:
+    - address: "a.example.com"
+      send_proxy_protocol: true
+    - address: "b.example.com"
+      ping_with_proxy_protocol: true
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        let servers = &cfg.static_cfg.unwrap().servers;
+        assert!(servers.iter().all(|server| server.ping_with_proxy_protocol));
     }
 
     #[test]
@@ -271,4 +1821,658 @@ http:
         assert_eq!(cfg.mode, Mode::Http);
         assert!(cfg.http_cfg.is_some());
     }
+
+    #[test]
+    fn custom_mode() {
+        let yaml = r#"
+mode: custom
+motd: test
+custom:
+  finder: "my_finder"
+  options:
+    servers: ["a.example.com", "b.example.com"]
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        let custom = cfg.custom_cfg.unwrap();
+        assert_eq!(custom.finder, "my_finder");
+        assert_eq!(custom.options["servers"][1], "b.example.com");
+
+        for invalid in ["mode: custom\n", "mode: custom\ncustom:\n  finder: \"\"\n"] {
+            let err = Config::from_yaml_str(invalid).unwrap_err();
+            assert!(matches!(err, ConfigError::Invalid(_)), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn hostname_ok() {
+        let yaml = r#"
+mode: hostname
+motd: test
+hostname:
+  routes:
+    "*.example.com":
+      address: "a.example.com"
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        assert_eq!(cfg.mode, Mode::Hostname);
+        assert!(cfg.hostname_cfg.unwrap().fallback.is_none());
+    }
+
+    #[test]
+    fn geo_providers() {
+        let yaml = r#"
+mode: geo
+motd: test
+geo:
+  provider: maxmind
+  database: "GeoLite2-Country.mmdb"
+  regions:
+    EU:
+      address: "eu.example.com"
+  fallback:
+    address: "fallback.example.com"
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        assert_eq!(cfg.geo_cfg.unwrap().provider, GeoProviderKind::Maxmind);
+
+        let without_database = yaml.replace("  database: \"GeoLite2-Country.mmdb\"\n", "");
+        let err = Config::from_yaml_str(&without_database).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+
+        let without_token = without_database.replace("maxmind", "ipinfo");
+        let err = Config::from_yaml_str(&without_token).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+    }
+
+    #[test]
+    fn geo_blank_token_is_rejected() {
+        let yaml = |token: &str, limit: u32| {
+            format!(
+                r#"
+mode: geo
+motd: test
+geo:
+  token: "{}"
+  auth_failure_limit: {}
+  regions:
+    EU:
+      address: "eu.example.com"
+  fallback:
+    address: "fallback.example.com"
+"#,
+                token, limit
+            )
+        };
+        let cfg = Config::from_yaml_str(&yaml("abc123", 5)).unwrap();
+        assert_eq!(cfg.geo_cfg.unwrap().auth_failure_limit(), 5);
+
+        for token in ["", "   "] {
+            let err = Config::from_yaml_str(&yaml(token, 5)).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("geo.token is required by the ipinfo provider"),
+                "{}",
+                err
+            );
+        }
+
+        let err = Config::from_yaml_str(&yaml("abc123", 0)).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+    }
+
+    #[test]
+    fn geo_server_coordinates() {
+        let yaml = |latitude: f64| {
+            format!(
+                r#"
+mode: geo
+motd: test
+geo:
+  token: "abc123"
+  regions:
+    US:
+      - address: "us-east.example.com"
+        coordinates: {{ latitude: {}, longitude: -77.5 }}
+      - address: "us-west.example.com"
+  fallback:
+    address: "fallback.example.com"
+"#,
+                latitude
+            )
+        };
+        let cfg = Config::from_yaml_str(&yaml(39.0)).unwrap();
+        let servers = &cfg.geo_cfg.unwrap().regions["US"];
+        assert_eq!(
+            servers[0].coordinates,
+            Some(Coordinates {
+                latitude: 39.0,
+                longitude: -77.5
+            })
+        );
+        assert!(servers[1].coordinates.is_none());
+
+        let err = Config::from_yaml_str(&yaml(91.0)).unwrap_err();
+        assert!(err.to_string().contains("coordinates.latitude"), "{}", err);
+    }
+
+    #[test]
+    fn geo_fallbacks() {
+        let yaml = r#"
+mode: geo
+motd: test
+geo:
+  provider: maxmind
+  database: "GeoLite2-Country.mmdb"
+  regions:
+    EU:
+      address: "eu.example.com"
+  fallbacks:
+    - address: "first.example.com"
+    - address: "second.example.com"
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        let addresses: Vec<String> = cfg
+            .geo_cfg
+            .unwrap()
+            .fallbacks
+            .into_iter()
+            .map(|server| server.address)
+            .collect();
+        assert_eq!(addresses, vec!["first.example.com", "second.example.com"]);
+
+        let empty = yaml.replace(
+            "\n    - address: \"first.example.com\"\n    - address: \"second.example.com\"",
+            " []",
+        );
+        let err = Config::from_yaml_str(&empty).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+    }
+
+    #[test]
+    fn geo_adjacent_regions() {
+        let yaml = r#"
+mode: geo
+motd: test
+geo:
+  provider: maxmind
+  database: "GeoLite2-Country.mmdb"
+  regions:
+    NA:
+      address: "na.example.com"
+    EU:
+      address: "eu.example.com"
+  adjacent_regions:
+    SA: { na: 3, EU: 1 }
+  fallback:
+    address: "fallback.example.com"
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        let adjacent = &cfg.geo_cfg.unwrap().adjacent_regions["SA"];
+        assert_eq!(adjacent["na"], 3);
+        assert_eq!(adjacent["EU"], 1);
+
+        let unknown = yaml.replace("EU: 1", "AS: 1");
+        let err = Config::from_yaml_str(&unknown).unwrap_err().to_string();
+        assert!(
+            err.contains("geo.adjacent_regions.SA lists 'AS'"),
+            "{}",
+            err
+        );
+
+        let unweighted = yaml.replace("EU: 1", "EU: 0");
+        let err = Config::from_yaml_str(&unweighted).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+    }
+
+    #[test]
+    fn geo_overrides() {
+        let yaml = r#"
+mode: geo
+geo:
+  provider: maxmind
+  database: "GeoLite2-Country.mmdb"
+  regions:
+    EU:
+      address: "eu.example.com"
+  overrides:
+    "10.8.0.0/16": eu
+  fallback:
+    address: "fallback.example.com"
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        let range: IpNet = "10.8.0.0/16".parse().unwrap();
+        assert_eq!(cfg.geo_cfg.unwrap().overrides[&range], "eu");
+
+        let unknown = yaml.replace("/16\": eu", "/16\": na");
+        let err = Config::from_yaml_str(&unknown).unwrap_err().to_string();
+        assert!(
+            err.contains("geo.overrides.10.8.0.0/16 pins 'na'"),
+            "{}",
+            err
+        );
+
+        let invalid = yaml.replace("10.8.0.0/16", "10.8.0.0/33");
+        assert!(Config::from_yaml_str(&invalid).is_err());
+    }
+
+    #[test]
+    fn motd_is_optional() {
+        let yaml = r#"
+mode: static
+static:
+  algorithm: round_robin
+  servers:
+    - address: "a.example.com"
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        assert_eq!(cfg.motd, None);
+        assert_eq!(cfg.status_motd(), "A Minecraft Load Balancer");
+
+        let cfg = Config::from_yaml_str(&format!("motd: \"Welcome\"{}", yaml)).unwrap();
+        assert_eq!(cfg.motd.as_deref(), Some("Welcome"));
+        assert_eq!(cfg.status_motd(), "Welcome");
+    }
+
+    #[test]
+    fn protocol_motd_ranges() {
+        let yaml = r#"
+mode: static
+static:
+  algorithm: round_robin
+  servers:
+    - address: "a.example.com"
+protocol_motds:
+  - max_protocol: 766
+    motd: "Please update"
+  - min_protocol: 773
+    motd: |
+      Snapshot clients
+      may not work
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        let [outdated, snapshot] = &cfg.protocol_motds[..] else {
+            panic!("{:?}", cfg.protocol_motds);
+        };
+        assert!(outdated.matches(765));
+        assert!(!outdated.matches(767));
+        assert!(snapshot.matches(774));
+        assert_eq!(snapshot.motd, "Snapshot clients\nmay not work\n");
+
+        let inverted = yaml.replace(
+            "- max_protocol: 766",
+            "- max_protocol: 766\n    min_protocol: 770",
+        );
+        let err = Config::from_yaml_str(&inverted).unwrap_err().to_string();
+        assert!(
+            err.contains("protocol_motds[0] has min_protocol 770"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn mode_motd_overrides_the_global_one() {
+        let yaml = r#"
+mode: static
+motd: "Global motd"
+static:
+  algorithm: round_robin
+  motd: "Static motd"
+  servers:
+    - address: "a.example.com"
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        assert_eq!(cfg.status_motd(), "Static motd");
+
+        let cfg = Config::from_yaml_str(&yaml.replace("  motd: \"Static motd\"\n", "")).unwrap();
+        assert_eq!(cfg.status_motd(), "Global motd");
+
+        let unknown_region = r#"
+mode: geo
+motd: test
+geo:
+  provider: maxmind
+  database: "GeoLite2-Country.mmdb"
+  regions:
+    NA:
+      address: "na.example.com"
+  motds:
+    EU: "Welcome"
+  fallback:
+    address: "fallback.example.com"
+"#;
+        let err = Config::from_yaml_str(unknown_region).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)));
+    }
+
+    #[test]
+    fn metrics_section() {
+        let yaml = r#"
+mode: static
+motd: test
+static:
+  algorithm: round_robin
+  servers:
+    - address: "a.example.com"
+metrics:
+  enabled: true
+  bind: "127.0.0.1:9100"
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+        let metrics = cfg.metrics.unwrap();
+        assert!(metrics.enabled);
+        assert_eq!(metrics.bind, "127.0.0.1:9100");
+    }
+
+    #[test]
+    fn access_lists() {
+        let yaml = r#"
+mode: static
+motd: test
+static:
+  algorithm: round_robin
+  servers:
+    - address: "a.example.com"
+blocklist:
+  ips: ["10.0.0.0/8", "2001:db8::/32"]
+  usernames: ["Griefer"]
+allowlist:
+  ips: ["10.0.0.0/8", "192.168.1.0/24"]
+"#;
+        let cfg = Config::from_yaml_str(yaml).unwrap();
+
+        assert!(!cfg.is_ip_allowed("10.1.2.3".parse().unwrap()));
+        assert!(!cfg.is_ip_allowed("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cfg.is_ip_allowed("2001:db8::1".parse().unwrap()));
+        assert!(cfg.is_ip_allowed("192.168.1.20".parse().unwrap()));
+        assert!(!cfg.is_ip_allowed("192.168.2.20".parse().unwrap()));
+
+        assert!(!cfg.is_username_allowed("griefer"));
+        assert!(cfg.is_username_allowed("Notch"));
+    }
+
+    fn static_with_address(address: &str) -> String {
+        format!(
+            r#"
+mode: static
+motd: test
+static:
+  algorithm: round_robin
+  servers:
+    - address: "{}"
+"#,
+            address
+        )
+    }
+
+    #[test]
+    fn invalid_server_address() {
+        for address in [":::", "", "host:", "host:port", "[::1", "host name"] {
+            let err = Config::from_yaml_str(&static_with_address(address)).unwrap_err();
+            assert!(matches!(err, ConfigError::Invalid(_)), "{}", address);
+        }
+    }
+
+    #[test]
+    fn valid_server_addresses() {
+        for address in [
+            "[2001:db8::1]:25565",
+            "[2001:db8::1]",
+            "2001:db8::1",
+            "192.0.2.1:25565",
+            "play.example.com",
+            "play.example.com:25570",
+        ] {
+            assert!(
+                Config::from_yaml_str(&static_with_address(address)).is_ok(),
+                "{}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn advertised_protocol() {
+        let mut cfg = Config::from_yaml_str(&static_with_address("a.example.com")).unwrap();
+        for (client, advertised) in [(-1, 766), (0, 766), (47, 766), (766, 766), (772, 772)] {
+            assert_eq!(cfg.advertised_protocol(client), advertised, "{}", client);
+        }
+
+        cfg.min_protocol_version = Some(770);
+        cfg.max_protocol_version = Some(771);
+        for (client, advertised) in [(766, 770), (770, 770), (771, 771), (772, 771)] {
+            assert_eq!(cfg.advertised_protocol(client), advertised, "{}", client);
+        }
+    }
+
+    #[test]
+    fn listen_addresses() {
+        let base = static_with_address("a.example.com");
+        let cfg = Config::from_yaml_str(&base).unwrap();
+        assert_eq!(cfg.listen_addresses(), vec!["0.0.0.0:25565"]);
+
+        let cfg = Config::from_yaml_str(&format!(
+            "{}listen: [\"0.0.0.0:25565\", \"[::]:25565\"]\n",
+            base
+        ))
+        .unwrap();
+        assert_eq!(cfg.listen_addresses(), vec!["0.0.0.0:25565", "[::]:25565"]);
+
+        for listen in ["[]", "[\"localhost:25565\"]", "[\"::1\"]"] {
+            let yaml = format!("{}listen: {}\n", base, listen);
+            assert!(Config::from_yaml_str(&yaml).is_err(), "{}", listen);
+        }
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let yaml = r#"
+mode: static
+motd: test
+listen: ["localhost:25565"]
+unrouteable_server:
+  address: "bad host:25565"
+static:
+  algorithm: round_robin
+  servers: []
+"#;
+        let cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        let problems = cfg.problems();
+        assert_eq!(problems.len(), 3, "{:?}", problems);
+        assert!(problems[0].starts_with("static.servers"));
+        assert!(problems[1].starts_with("unrouteable_server.address"));
+        assert!(problems[2].starts_with("listen address 'localhost:25565'"));
+
+        let err = Config::from_yaml_str(yaml).unwrap_err().to_string();
+        assert!(err.contains("3 problem(s) found"), "{}", err);
+        for problem in &problems {
+            assert!(err.contains(problem.as_str()), "{}", err);
+        }
+    }
+
+    #[test]
+    fn locale_routes_are_validated() {
+        let yaml = r#"
+mode: static
+static:
+  algorithm: round_robin
+  servers:
+    - address: "mc.example.com"
+locale_routing:
+  routes:
+    "fr":
+      address: "bad host:25565"
+"#;
+        let cfg: Config = serde_yaml::from_str(yaml).unwrap();
+        let problems = cfg.problems();
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(problems[0].starts_with("locale_routing.routes.fr.address"));
+    }
+
+    const LOGICAL_CONFIG_YAML: &str = r#"
+mode: geo
+motd: test
+listen: ["0.0.0.0:25565", "[::]:25565"]
+static:
+  algorithm: lowest_player_count
+  servers:
+    - name: "US-East"
+      address: "us.example.com"
+      max_players: 200
+    - address: "eu.example.com:25566"
+geo:
+  provider: maxmind
+  database: "GeoLite2-Country.mmdb"
+  regions:
+    EU:
+      address: "eu.example.com"
+    "*":
+      address: "us.example.com"
+  fallback:
+    address: "fallback.example.com"
+dns:
+  upstream: custom
+  nameservers: ["9.9.9.9"]
+"#;
+
+    const LOGICAL_CONFIG_TOML: &str = r#"
+mode = "geo"
+motd = "test"
+listen = ["0.0.0.0:25565", "[::]:25565"]
+
+[static]
+algorithm = "lowest_player_count"
+
+[[static.servers]]
+name = "US-East"
+address = "us.example.com"
+max_players = 200
+
+[[static.servers]]
+address = "eu.example.com:25566"
+
+[geo]
+provider = "maxmind"
+database = "GeoLite2-Country.mmdb"
+fallback = { address = "fallback.example.com" }
+
+[geo.regions.EU]
+address = "eu.example.com"
+
+[geo.regions."*"]
+address = "us.example.com"
+
+[dns]
+upstream = "custom"
+nameservers = ["9.9.9.9"]
+"#;
+
+    fn as_json(cfg: &Config) -> serde_json::Value {
+        serde_json::to_value(cfg).unwrap()
+    }
+
+    #[test]
+    fn toml_matches_yaml() {
+        let from_yaml = Config::from_yaml_str(LOGICAL_CONFIG_YAML).unwrap();
+        let from_toml = Config::from_toml_str(LOGICAL_CONFIG_TOML).unwrap();
+        assert_eq!(as_json(&from_toml), as_json(&from_yaml));
+        assert!(from_toml.static_cfg.is_some());
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let cfg = Config::from_yaml_str(LOGICAL_CONFIG_YAML).unwrap();
+        let serialized = toml::to_string(&cfg).unwrap();
+        let reparsed = Config::from_toml_str(&serialized).unwrap();
+        assert_eq!(as_json(&reparsed), as_json(&cfg));
+    }
+
+    #[test]
+    fn from_file_picks_format_from_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, raw) in [
+            ("config.yaml", LOGICAL_CONFIG_YAML),
+            ("config.toml", LOGICAL_CONFIG_TOML),
+        ] {
+            let path = dir.path().join(name);
+            fs::write(&path, raw).unwrap();
+            let cfg = Config::from_file(&path).unwrap();
+            assert_eq!(cfg.mode, Mode::Geo, "{}", name);
+        }
+    }
+    #[test]
+    fn environment_variables_are_substituted() {
+        // SAFETY: no other test reads or writes these variables.
+        unsafe {
+            std::env::set_var("LB_TEST_GEO_TOKEN", "secret-token");
+            std::env::remove_var("LB_TEST_UNSET");
+        }
+        let yaml = r#"
+mode: geo
+motd: "${LB_TEST_UNSET:-A test server}"
+geo:
+  provider: ipinfo
+  token: "${LB_TEST_GEO_TOKEN}"
+  regions:
+    EU:
+      address: "eu.example.com"
+  fallback:
+    address: "fallback.example.com"
+"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        fs::write(&path, yaml).unwrap();
+
+        let cfg = Config::from_file(&path).unwrap();
+        assert_eq!(cfg.geo_cfg.unwrap().token, "secret-token");
+        assert_eq!(cfg.status_motd(), "A test server");
+
+        fs::write(&path, yaml.replace("LB_TEST_GEO_TOKEN", "LB_TEST_UNSET")).unwrap();
+        let err = Config::from_file(&path).unwrap_err();
+        assert!(
+            err.to_string().contains("LB_TEST_UNSET is not set"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn text_without_variables_is_untouched() {
+        for raw in [
+            "motd: \"$5 off\"",
+            "motd: \"{braces}\"",
+            "motd: \"${unterminated\"",
+            "motd: \"${unterminated\"\nname: \"}\"",
+        ] {
+            assert_eq!(substitute_env_vars(raw).unwrap(), raw);
+        }
+    }
+
+    #[test]
+    fn comments_and_escaped_variables_are_not_substituted() {
+        // SAFETY: no other test reads or writes this variable.
+        unsafe {
+            std::env::remove_var("LB_TEST_COMMENTED");
+        }
+        for (raw, expected) in [
+            ("# token: \"${LB_TEST_COMMENTED}\"", None),
+            ("  # token: ${LB_TEST_COMMENTED}", None),
+            ("motd: test # or ${LB_TEST_COMMENTED}", None),
+            (
+                "motd: \"Costs $${LB_TEST_COMMENTED}\"",
+                Some("motd: \"Costs ${LB_TEST_COMMENTED}\""),
+            ),
+            (
+                "motd: \"#1 ${LB_TEST_COMMENTED:-server}\" # ${LB_TEST_COMMENTED}",
+                Some("motd: \"#1 server\" # ${LB_TEST_COMMENTED}"),
+            ),
+            (
+                "motd: \"say \\\" #${LB_TEST_COMMENTED:-hi}\"",
+                Some("motd: \"say \\\" #hi\""),
+            ),
+        ] {
+            let expanded = substitute_env_vars(raw).unwrap();
+            assert_eq!(expanded, expected.unwrap_or(raw), "{}", raw);
+        }
+    }
 }
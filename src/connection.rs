@@ -1,14 +1,22 @@
-use crate::finder::ServerFinder;
-use crate::status::StatusCache;
+use crate::access_log::AccessLogRecord;
+use crate::backend::{MinecraftServer, PingOptions, RoutedConnection};
+use crate::config::Config as LoadBalancerConfig;
+use crate::finder::{FinderError, ServerFinder};
+use crate::maintenance::Maintenance;
+use crate::metrics::metrics;
+use crate::packet_limit::PacketSizeLimit;
+use crate::status::{StatusCache, refresh_player_count, refresh_primary, status_overrides};
 use ConnectionState::{Config, Status};
-use log::{debug, info};
+use md5::{Digest, Md5};
+use rand::Rng;
+use serde::Serialize;
 use pumpkin_protocol::{
     ClientPacket, ConnectionState,
     ConnectionState::{HandShake, Login},
-    RawPacket, ServerPacket,
+    PacketDecodeError, RawPacket, ServerPacket,
     codec::var_int::VarInt,
-    java::client::config::CTransfer,
-    java::client::login::CLoginSuccess,
+    java::client::config::{CConfigDisconnect, CTransfer},
+    java::client::login::{CLoginDisconnect, CLoginSuccess},
     java::client::status::CPingResponse,
     java::packet_decoder::TCPNetworkDecoder,
     java::packet_encoder::TCPNetworkEncoder,
@@ -16,11 +24,11 @@ use pumpkin_protocol::{
     java::server::login::{SLoginAcknowledged, SLoginStart},
     java::server::status::{SStatusPingRequest, SStatusRequest},
     packet::Packet,
-    ser::{NetworkWriteExt, WritingError},
+    ser::{NetworkReadExt, NetworkWriteExt, WritingError},
 };
 use std::{
-    cmp::max, error::Error, io::Write, sync::Arc, sync::atomic::AtomicUsize,
-    sync::atomic::Ordering::SeqCst,
+    error::Error, io::Write, sync::Arc, sync::atomic::AtomicUsize,
+    sync::atomic::Ordering::SeqCst, time::Duration, time::Instant,
 };
 use std::net::SocketAddr;
 use tokio::{
@@ -28,83 +36,155 @@ use tokio::{
     net::tcp::{OwnedReadHalf, OwnedWriteHalf},
     sync::Mutex,
 };
+use tracing::{Instrument, Span, debug, field, info, info_span};
+use uuid::Uuid;
 
 pub struct Connection {
     state: ConnectionState,
     network_writer: TCPNetworkEncoder<BufWriter<OwnedWriteHalf>>,
-    network_reader: TCPNetworkDecoder<BufReader<OwnedReadHalf>>,
+    network_reader: TCPNetworkDecoder<BufReader<PacketSizeLimit<OwnedReadHalf>>>,
     server_finder: Arc<Mutex<Box<dyn ServerFinder>>>,
     status_cache: Arc<Mutex<StatusCache>>,
-    motd: String,
+    maintenance: Arc<Maintenance>,
+    config: Arc<LoadBalancerConfig>,
     pub addr: SocketAddr,
-    context_id: usize,
+    /// The hostname the client connected to, as sent in its handshake.
+    pub hostname: String,
+    /// The mod loader announced in the handshake address.
+    pub mod_loader: ModLoader,
+    /// The locale the client reported in the configuration state, like "fr_fr".
+    pub locale: Option<String>,
+    /// The name the player logged in with.
+    pub username: Option<String>,
+    /// Backends already found unreachable while picking one for this
+    /// connection, which finders skip.
+    pub unreachable: Vec<String>,
     protocol_version: i32,
+    // Only held for its drop, which releases the chosen backend's connection count.
+    _routed: Option<RoutedConnection>,
+    access_log: AccessLogRecord,
+    // Carries the context id, client address and state into every event
+    // logged while handling this connection.
+    span: Span,
 }
 
 static COUNTER: AtomicUsize = AtomicUsize::new(0);
 
+/// The first protocol with the transfer packet, 1.20.5.
+const TRANSFER_MIN_PROTOCOL: i32 = 766;
+
+/// How many picks the finder gets before a player is treated as unroutable.
+const MAX_SELECTION_ATTEMPTS: usize = 3;
+
+/// Store Cookie in the configuration state. Written by hand, as the
+/// pumpkin_protocol packet takes a key type from a crate this one doesn't use.
+const CONFIG_STORE_COOKIE_ID: i32 = 0x0A;
+
+/// Client Information in the configuration state, which vanilla clients send
+/// right after their brand. Only its leading locale is read.
+const CONFIG_CLIENT_INFORMATION_ID: i32 = 0x00;
+
+/// How long other configuration packets are skipped while waiting for Client
+/// Information, before transferring without a locale.
+const CLIENT_INFORMATION_WAIT: Duration = Duration::from_secs(1);
+
 impl Connection {
     pub fn new(
         owned_read_half: OwnedReadHalf,
         owned_write_half: OwnedWriteHalf,
         server_finder: Arc<Mutex<Box<dyn ServerFinder>>>,
         status_cache: Arc<Mutex<StatusCache>>,
+        maintenance: Arc<Maintenance>,
         addr: SocketAddr,
-        motd: String,
+        config: Arc<LoadBalancerConfig>,
     ) -> Connection {
+        metrics().connection_opened();
+        let context_id = COUNTER.fetch_add(1, SeqCst);
         Connection {
             state: HandShake,
             server_finder,
-            context_id: COUNTER.fetch_add(1, SeqCst),
             network_writer: TCPNetworkEncoder::new(BufWriter::new(owned_write_half)),
-            network_reader: TCPNetworkDecoder::new(BufReader::new(owned_read_half)),
+            network_reader: TCPNetworkDecoder::new(BufReader::new(PacketSizeLimit::new(
+                owned_read_half,
+                config.max_packet_size(),
+            ))),
             protocol_version: 0,
+            hostname: String::new(),
+            mod_loader: ModLoader::Vanilla,
+            locale: None,
+            username: None,
+            unreachable: Vec::new(),
+            _routed: None,
+            access_log: AccessLogRecord::new(context_id, addr),
+            span: info_span!("connection", context_id, %addr, state = ?HandShake),
             status_cache,
+            maintenance,
+            config,
             addr,
-            motd
         }
     }
 
     pub async fn process_packets(&mut self) -> bool {
-        let packet = self.get_packet().await;
+        let span = self.span.clone();
+        self.process_next_packet().instrument(span).await
+    }
 
-        let Some(mut packet) = packet else {
-            debug!("Failed to read next packet.");
-            return false;
-        };
+    /// The span events about this connection are logged in.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
 
-        if let Err(error) = self.handle_packet(&mut packet).await {
-            log::error!(
-                "({}) Failed to read incoming packet with id {} (State: {:?}): {}",
-                self.context_id,
-                packet.id,
-                self.state,
-                error
-            );
-            return false;
+    async fn process_next_packet(&mut self) -> bool {
+        let mut packet = match self.get_packet().await {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return false,
+            Err(error) => {
+                tracing::error!("Failed to read packet (State: {:?}): {}", self.state, error);
+                return false;
+            }
         };
-        true
+
+        match self.handle_packet(&mut packet).await {
+            Ok(PacketOutcome::KeepOpen) => true,
+            Ok(PacketOutcome::Close) => false,
+            Err(error) => {
+                tracing::error!(
+                    "Failed to read incoming packet with id {} (State: {:?}): {}",
+                    packet.id,
+                    self.state,
+                    error
+                );
+                false
+            }
+        }
     }
 
-    async fn handle_packet(&mut self, packet: &mut RawPacket) -> Result<(), Box<dyn Error>> {
+    async fn handle_packet(
+        &mut self,
+        packet: &mut RawPacket,
+    ) -> Result<PacketOutcome, Box<dyn Error>> {
         match self.state {
             HandShake => {
                 self.handle_handshake_packet(packet).await?;
             }
             Status => {
-                // debug!("({}) Handling status packet", self.context_id);
+                // debug!("Handling status packet");
                 self.handle_status_packet(packet).await?;
             }
             Config => {
+                if !self.await_client_information(packet).await? {
+                    return Ok(PacketOutcome::Close);
+                }
                 self.handle_config_packet().await?;
-                return Err("Disconnect".into());
+                // The client reconnects to the backend on its own.
+                return Ok(PacketOutcome::Close);
             }
             Login => {
-                self.handle_login_packet(packet).await?;
+                return self.handle_login_packet(packet).await;
             }
             _ => {}
         }
-        Ok(())
+        Ok(PacketOutcome::KeepOpen)
     }
 
     async fn handle_handshake_packet(
@@ -114,14 +194,17 @@ impl Connection {
         let bytebuf = &packet.payload[..];
         if packet.id == SHandShake::PACKET_ID {
             let result = SHandShake::read(bytebuf)?;
-            debug!(
-                "({}) Switched from {:?} to {:?}",
-                self.context_id, self.state, result.next_state
-            );
-            self.state = result.next_state;
+            debug!("Switched from {:?} to {:?}", self.state, result.next_state);
+            self.set_state(result.next_state);
             self.protocol_version = result.protocol_version.0;
+            (self.hostname, self.mod_loader) = parse_handshake_address(&result.server_address);
+            self.access_log.protocol_version = self.protocol_version;
+            self.access_log.hostname = self.hostname.clone();
+            self.access_log.mod_loader = self.mod_loader;
             return Ok(());
         }
+        // Nothing else is valid before the handshake, and guessing at the
+        // client's protocol would only produce garbage replies.
         Err("Incompatible handshake packet received".into())
     }
 
@@ -131,18 +214,19 @@ impl Connection {
 
         match packet.id {
             SStatusRequest::PACKET_ID => {
-                let protocol = max(766, self.protocol_version) as u32;
-
-                let status = self
-                    .status_cache
-                    .lock()
-                    .await
-                    .get_status_response(
-                        self.motd.clone(),
-                        protocol,
-                        self.server_finder.lock().await,
-                    )
-                    .await;
+                let protocol = self.config.advertised_protocol(self.protocol_version);
+                // All before the status cache is locked, since they may poll
+                // the backends, ping the primary one and look the client up.
+                refresh_player_count(&self.status_cache, &self.server_finder).await;
+                refresh_primary(&self.status_cache).await;
+                let overrides = status_overrides(&self.server_finder, self.addr.ip()).await;
+
+                let status = self.status_cache.lock().await.get_status_response(
+                    self.config.status_motd(),
+                    self.protocol_version,
+                    protocol,
+                    overrides,
+                );
                 return self.send_packet(&status).await;
             }
             SStatusPingRequest::PACKET_ID => {
@@ -150,46 +234,282 @@ impl Connection {
                 return self.send_packet(&CPingResponse::new(payload)).await;
             }
             _ => {
-                Err("Unknown packet id")?
+                debug!("Ignoring unknown status packet with id {}", packet.id);
             }
         }
         Ok(())
     }
 
-    async fn handle_login_packet(&mut self, packet: &mut RawPacket) -> Result<(), Box<dyn Error>> {
+    async fn handle_login_packet(
+        &mut self,
+        packet: &mut RawPacket,
+    ) -> Result<PacketOutcome, Box<dyn Error>> {
         let bytebuf = &packet.payload[..];
         match packet.id {
             SLoginStart::PACKET_ID => {
                 debug!("Received login start packet");
+                // The name leads the packet in every version. What follows
+                // it differs in older ones, which aren't transferred.
+                let mut leading = bytebuf;
+                let name = leading.get_string()?;
+                self.access_log.username = Some(name.clone());
+                self.username = Some(name.clone());
+                if self.maintenance.is_enabled() {
+                    let message = self.maintenance.message.clone();
+                    self.disconnect(&message).await?;
+                    info!("Turned {} away during maintenance", name);
+                    return Ok(PacketOutcome::Close);
+                }
+                if !self.config.is_username_allowed(&name) {
+                    self.disconnect("You are not allowed to join this server.")
+                        .await?;
+                    info!("Username {} is not allowed", name);
+                    return Ok(PacketOutcome::Close);
+                }
+                if self.protocol_version < TRANSFER_MIN_PROTOCOL {
+                    return self.redirect_without_transfer().await;
+                }
                 let login = SLoginStart::read(bytebuf)?;
-                self.send_packet(&CLoginSuccess::new(&login.uuid, &login.name, &[]))
+                let uuid = if self.config.offline_mode {
+                    offline_uuid(&login.name)
+                } else {
+                    login.uuid
+                };
+                self.send_packet(&CLoginSuccess::new(&uuid, &login.name, &[]))
                     .await?;
-                Ok(())
+                Ok(PacketOutcome::KeepOpen)
             }
             SLoginAcknowledged::PACKET_ID => {
                 debug!("Received login acknowledged packet");
-                self.state = Config;
-                Ok(())
+                self.set_state(Config);
+                Ok(PacketOutcome::KeepOpen)
+            }
+            // Plugin and cookie responses, which the balancer never asks for.
+            _ => {
+                debug!("Ignoring unknown login packet with id {}", packet.id);
+                Ok(PacketOutcome::KeepOpen)
             }
-            _ => Err("Unknown packet id".into()),
         }
     }
 
-    async fn handle_config_packet(&mut self) -> Result<(), Box<dyn Error>> {
-        let mut finder = self
-            .server_finder
-            .lock()
-            .await;
+    // Skips configuration packets, like the brand, until Client Information
+    // arrives and its locale is read, or `CLIENT_INFORMATION_WAIT` passes.
+    // False when the client went away in the meantime.
+    async fn await_client_information(
+        &mut self,
+        packet: &RawPacket,
+    ) -> Result<bool, PacketDecodeError> {
+        let deadline = tokio::time::Instant::now() + CLIENT_INFORMATION_WAIT;
+        let mut id = packet.id;
+        if id == CONFIG_CLIENT_INFORMATION_ID {
+            self.read_client_information(packet);
+            return Ok(true);
+        }
+        loop {
+            debug!("Skipping config packet {} before Client Information", id);
+            let next = tokio::time::timeout_at(deadline, self.network_reader.get_raw_packet());
+            let packet = match next.await {
+                Ok(Ok(packet)) => packet,
+                Ok(Err(error)) if is_disconnect(&error) => {
+                    debug!("Client disconnected: {}", error);
+                    return Ok(false);
+                }
+                Ok(Err(error)) => return Err(error),
+                Err(_) => {
+                    debug!("No Client Information within {:?}", CLIENT_INFORMATION_WAIT);
+                    return Ok(true);
+                }
+            };
+            if packet.id == CONFIG_CLIENT_INFORMATION_ID {
+                self.read_client_information(&packet);
+                return Ok(true);
+            }
+            id = packet.id;
+        }
+    }
 
-        let server =finder.find_server(self).await?;
-        drop(finder);
+    // Keeps the client's locale for the finder to route by. A malformed
+    // packet leaves it unknown, since routing falls back to the mode's pick
+    // either way.
+    fn read_client_information(&mut self, packet: &RawPacket) {
+        let mut bytebuf = &packet.payload[..];
+        match bytebuf.get_string() {
+            Ok(locale) => {
+                debug!("Client locale is {}", locale);
+                self.access_log.locale = Some(locale.clone());
+                self.locale = Some(locale);
+            }
+            Err(error) => debug!("Failed to read the client locale: {}", error),
+        }
+    }
 
+    /// Picks a backend and transfers the client to it. The client then opens
+    /// its own connection to the backend, so online-mode backends run the
+    /// encryption handshake with the client directly and nothing is relayed.
+    async fn handle_config_packet(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(server) = self.select_server().await? else {
+            return Ok(());
+        };
         let (hostname, port) = server.get_host_and_port().await?;
 
-        info!("Transferring to {}:{}", hostname, port);
+        match &server.name {
+            Some(name) => info!("Transferring to {} ({}:{})", name, hostname, port),
+            None => info!("Transferring to {}:{}", hostname, port),
+        }
 
+        let delay = transfer_delay(self.config.transfer_jitter());
+        if !delay.is_zero() {
+            debug!("Delaying the transfer by {}ms", delay.as_millis());
+            tokio::time::sleep(delay).await;
+        }
+
+        self.store_transfer_cookies().await?;
         self.send_packet(&CTransfer::new(&hostname, &VarInt(port as i32)))
+            .await?;
+        metrics().record_transfer();
+        self.server_finder
+            .lock()
             .await
+            .record_transfer(self, &server);
+        Ok(())
+    }
+
+    // Cookies stay with the client through the transfer, so the backend can
+    // request them to learn where the player came from.
+    async fn store_transfer_cookies(&mut self) -> Result<(), Box<dyn Error>> {
+        let config = self.config.clone();
+        let Some(cookies) = &config.transfer_cookies else {
+            return Ok(());
+        };
+        for (key, value) in &cookies.values {
+            self.store_cookie(key, value.as_bytes()).await?;
+        }
+        if let Some(key) = &cookies.session_id {
+            let session_id = format!("{:032x}", rand::random::<u128>());
+            self.store_cookie(key, session_id.as_bytes()).await?;
+            self.access_log.session_id = Some(session_id);
+        }
+        Ok(())
+    }
+
+    async fn store_cookie(&mut self, key: &str, payload: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut buffer = Vec::new();
+        buffer.write_var_int(&VarInt(CONFIG_STORE_COOKIE_ID))?;
+        buffer.write_string(key)?;
+        buffer.write_var_int(&VarInt(payload.len() as i32))?;
+        buffer.write_all(payload)?;
+
+        self.network_writer.write_packet(buffer.into()).await?;
+        Ok(())
+    }
+
+    // Clients older than the transfer packet are still sent to a backend,
+    // by telling them which address to join instead.
+    async fn redirect_without_transfer(&mut self) -> Result<PacketOutcome, Box<dyn Error>> {
+        let Some(server) = self.select_server().await? else {
+            return Ok(PacketOutcome::Close);
+        };
+        info!(
+            "Protocol {} cannot be transferred, pointing the player at {}",
+            self.protocol_version, server.address
+        );
+        self.disconnect(&format!(
+            "Your client is too old to be transferred. Please connect to {} directly.",
+            server.address
+        ))
+        .await?;
+        Ok(PacketOutcome::Close)
+    }
+
+    // Asks the finder for a backend, falling back to the unrouteable server.
+    // Players nothing can be found for are disconnected, and get None.
+    async fn select_server(&mut self) -> Result<Option<MinecraftServer>, Box<dyn Error>> {
+        let started = Instant::now();
+        let result = self.find_reachable_server().await;
+        self.access_log.set_selection_latency(started.elapsed());
+
+        let server = match result {
+            Ok(server) => server,
+            Err(error) => {
+                let Some(lobby) = &self.config.unrouteable_server else {
+                    self.disconnect(disconnect_reason(&error)).await?;
+                    info!("No backend available for the player: {}", error);
+                    return Ok(None);
+                };
+                info!(
+                    "No backend available ({}), sending player to {}",
+                    error, lobby.address
+                );
+                MinecraftServer::from_config(lobby, PingOptions::from_config(&self.config))
+            }
+        };
+        metrics().record_selection(&server.address);
+        self._routed = Some(server.route_connection());
+        self.access_log.backend = Some(server.address.clone());
+        self.access_log.backend_name = server.name.clone();
+        self.access_log.region = server.region.clone();
+        Ok(Some(server))
+    }
+
+    // Probes each pick of the finder, asking again when it can't be reached.
+    // The finder skips the picks in `unreachable`, and one that picks such a
+    // backend again anyway just uses up an attempt.
+    async fn find_reachable_server(&mut self) -> Result<MinecraftServer, FinderError> {
+        self.unreachable.clear();
+        for _ in 0..MAX_SELECTION_ATTEMPTS {
+            let found = self.server_finder.lock().await.find_server(self).await;
+            let server = match found {
+                Ok(server) => server,
+                // Nothing is left but the backends already found unreachable.
+                Err(_) if !self.unreachable.is_empty() => {
+                    return Err(FinderError::AllBackendsDown);
+                }
+                Err(error) => return Err(error),
+            };
+            if self.unreachable.contains(&server.address) {
+                continue;
+            }
+            match server.probe().await {
+                Ok(()) => return Ok(server),
+                Err(error) => {
+                    info!(
+                        "Backend {} is unreachable ({}), picking another",
+                        server.address, error
+                    );
+                    server.record_error(error.to_string());
+                    self.unreachable.push(server.address);
+                }
+            }
+        }
+        Err(FinderError::AllBackendsDown)
+    }
+
+    /// Tells a client that is still logging in that the balancer is going away.
+    pub async fn disconnect_for_shutdown(&mut self) {
+        if !matches!(self.state, Login) {
+            return;
+        }
+        let span = self.span.clone();
+        async {
+            if let Err(error) = self.disconnect("The load balancer is restarting.").await {
+                debug!("Failed to send shutdown disconnect: {}", error);
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        self.state = state;
+        self.span.record("state", field::debug(&self.state));
+    }
+
+    async fn disconnect(&mut self, reason: &str) -> Result<(), Box<dyn Error>> {
+        let reason = serde_json::json!({ "text": reason }).to_string();
+        match self.state {
+            Config => self.send_packet(&CConfigDisconnect::new(&reason)).await,
+            _ => self.send_packet(&CLoginDisconnect::new(&reason)).await,
+        }
     }
 
     async fn send_packet<PACKET>(&mut self, packet: &PACKET) -> Result<(), Box<dyn Error>>
@@ -211,7 +531,828 @@ impl Connection {
         packet.write_packet_data(write)
     }
 
-    async fn get_packet(&mut self) -> Option<RawPacket> {
-        self.network_reader.get_raw_packet().await.ok()
+    // Bounded, so a client that stops sending partway through a packet, or
+    // never sends one, can't hold its task and socket open forever. None when
+    // the client went away or timed out; errors are left to the caller.
+    async fn get_packet(&mut self) -> Result<Option<RawPacket>, PacketDecodeError> {
+        let limit = if matches!(self.state, HandShake) {
+            self.config.handshake_timeout()
+        } else {
+            self.config.read_timeout()
+        };
+        match tokio::time::timeout(limit, self.network_reader.get_raw_packet()).await {
+            Ok(Ok(packet)) => Ok(Some(packet)),
+            Ok(Err(error)) if is_disconnect(&error) => {
+                debug!("Client disconnected: {}", error);
+                Ok(None)
+            }
+            Ok(Err(error)) => Err(error),
+            Err(_) => {
+                info!("Dropping {}, no packet within {:?}", self.addr, limit);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Whether a connection is kept open after a packet was handled. Closing is
+/// the normal end of a connection, unlike an error.
+#[derive(Debug, PartialEq, Eq)]
+enum PacketOutcome {
+    KeepOpen,
+    Close,
+}
+
+// Read errors that only mean the client went away, even partway through a
+// packet. Apart from a close between packets, the decoder keeps just the
+// text of the underlying IO error.
+const DISCONNECT_CAUSES: [&str; 5] = [
+    "end of file",
+    "early eof",
+    "reset",
+    "broken pipe",
+    "aborted",
+];
+
+fn is_disconnect(error: &PacketDecodeError) -> bool {
+    if matches!(error, PacketDecodeError::ConnectionClosed) {
+        return true;
+    }
+    let message = error.to_string().to_ascii_lowercase();
+    DISCONNECT_CAUSES
+        .iter()
+        .any(|cause| message.contains(cause))
+}
+
+// A random delay of at most `max`, spreading out transfers sent together.
+fn transfer_delay(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+    rand::thread_rng().gen_range(Duration::ZERO..=max)
+}
+
+/// The UUID an offline-mode server gives `username`: a version 3 UUID from
+/// the MD5 hash of "OfflinePlayer:<username>".
+pub fn offline_uuid(username: &str) -> Uuid {
+    let hash = Md5::digest(format!("OfflinePlayer:{}", username));
+    uuid::Builder::from_md5_bytes(hash.into()).into_uuid()
+}
+
+// What a player is told when no backend could be picked for them.
+fn disconnect_reason(error: &FinderError) -> &'static str {
+    match error {
+        FinderError::NoServersAvailable | FinderError::AllBackendsDown => {
+            "No servers are available right now, please try again later."
+        }
+        FinderError::Full => "The server is full, please try again later.",
+        FinderError::NoRoute(_) => "This server address is not in use.",
+        FinderError::GeoLookup(_) => {
+            "Could not find a server for your region, please try again later."
+        }
+    }
+}
+
+/// A mod loader that marks the handshake address of its clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModLoader {
+    #[default]
+    Vanilla,
+    /// Forge before 1.13, "\0FML\0".
+    Fml,
+    /// Forge 1.13 to 1.17, "\0FML2\0".
+    Fml2,
+    /// Forge 1.18 onwards and early NeoForge, "\0FML3\0".
+    Fml3,
+}
+
+impl ModLoader {
+    fn from_marker(marker: &str) -> Self {
+        match marker {
+            "FML" => ModLoader::Fml,
+            "FML2" => ModLoader::Fml2,
+            "FML3" => ModLoader::Fml3,
+            _ => ModLoader::Vanilla,
+        }
+    }
+}
+
+// Splits the mod loader marker off a handshake address and normalizes the
+// hostname, which some clients send in the fully qualified form with a
+// trailing dot. Players are transferred rather than proxied, so the marker
+// never has to be put back: the client sends it to the backend itself.
+fn parse_handshake_address(server_address: &str) -> (String, ModLoader) {
+    let mut parts = server_address.split('\0');
+    let hostname = parts.next().unwrap_or_default();
+    let mod_loader = parts
+        .next()
+        .map(ModLoader::from_marker)
+        .unwrap_or(ModLoader::Vanilla);
+    (
+        hostname.trim_end_matches('.').to_ascii_lowercase(),
+        mod_loader,
+    )
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        metrics().connection_closed();
+        // Status pings never log in, so only player sessions are logged.
+        if self.config.access_log && self.access_log.username.is_some() {
+            self.access_log.emit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::finder::get_server_finder;
+    use crate::test_support::{
+        NoServerFinder, client_decoder, connection_pair, spawn_player_count_backend, test_config,
+    };
+    use async_trait::async_trait;
+    use pumpkin_protocol::java::client::status::CStatusResponse;
+    use pumpkin_protocol::ser::NetworkReadExt;
+    use tracing_test::traced_test;
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_transfer_names_the_backend() {
+        let config =
+            test_config("unrouteable_server:\n  name: \"Lobby\"\n  address: \"127.0.0.1:25570\"");
+        let (mut connection, _client) = connection_pair(config, Box::new(NoServerFinder)).await;
+
+        connection.handle_config_packet().await.unwrap();
+        assert!(logs_contain("Transferring to Lobby (127.0.0.1:25570)"));
+        assert_eq!(connection.access_log.backend_name.as_deref(), Some("Lobby"));
+    }
+
+    // Hands out its servers in order, one per call.
+    struct SequenceFinder(Vec<MinecraftServer>);
+
+    #[async_trait]
+    impl ServerFinder for SequenceFinder {
+        async fn get_player_count(&self) -> Option<u32> {
+            None
+        }
+
+        fn backends(&self) -> Vec<MinecraftServer> {
+            self.0.clone()
+        }
+
+        async fn find_server(
+            &mut self,
+            _connection: &Connection,
+        ) -> Result<MinecraftServer, FinderError> {
+            if self.0.is_empty() {
+                return Err(FinderError::NoServersAvailable);
+            }
+            Ok(self.0.remove(0))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_pick_is_replaced() {
+        let down = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let live = spawn_player_count_backend(0).await;
+        let finder = SequenceFinder(vec![
+            MinecraftServer::new(down.to_string()),
+            MinecraftServer::new(live.to_string()),
+        ]);
+        let (mut connection, client) = connection_pair(test_config(""), Box::new(finder)).await;
+
+        connection.handle_config_packet().await.unwrap();
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CTransfer::PACKET_ID);
+        let mut payload = &packet.payload[..];
+        assert_eq!(payload.get_string().unwrap(), "127.0.0.1");
+        assert_eq!(payload.get_var_int().unwrap().0, live.port() as i32);
+        assert_eq!(connection.access_log.backend, Some(live.to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_dead_geo_region_falls_back() {
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let live = spawn_player_count_backend(0).await;
+        let yaml = format!(
+            r#"
+mode: geo
+motd: test
+geo:
+  provider: maxmind
+  database: "{}/testdata/geo-country-test.mmdb"
+  local_region: EU
+  regions:
+    EU:
+      address: "{}"
+  fallback:
+    address: "{}"
+"#,
+            env!("CARGO_MANIFEST_DIR"),
+            dead,
+            live
+        );
+        let config = LoadBalancerConfig::from_yaml_str(&yaml).unwrap();
+        let finder = get_server_finder(config.clone()).unwrap();
+        let (mut connection, _client) = connection_pair(config, finder).await;
+
+        connection.handle_config_packet().await.unwrap();
+        assert_eq!(connection.access_log.backend, Some(live.to_string()));
+        assert_eq!(connection.unreachable, [dead.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_is_sent_within_the_jitter() {
+        let jitter = Duration::from_millis(300);
+        for _ in 0..100 {
+            assert!(transfer_delay(jitter) <= jitter);
+        }
+        assert_eq!(transfer_delay(Duration::ZERO), Duration::ZERO);
+
+        let config = test_config(
+            "unrouteable_server:\n  address: \"127.0.0.1:25570\"\ntransfer_jitter_ms: 300",
+        );
+        let (mut connection, client) = connection_pair(config, Box::new(NoServerFinder)).await;
+
+        let started = Instant::now();
+        connection.handle_config_packet().await.unwrap();
+        // Selecting the lobby is near instant, so nearly all of this is the delay.
+        assert!(started.elapsed() < jitter + Duration::from_millis(200));
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CTransfer::PACKET_ID);
+    }
+
+    #[tokio::test]
+    async fn test_unrouteable_player_is_sent_to_lobby() {
+        let config = test_config("unrouteable_server:\n  address: \"127.0.0.1:25570\"");
+        let (mut connection, client) = connection_pair(config, Box::new(NoServerFinder)).await;
+
+        connection.handle_config_packet().await.unwrap();
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CTransfer::PACKET_ID);
+
+        let mut payload = &packet.payload[..];
+        assert_eq!(payload.get_string().unwrap(), "127.0.0.1");
+        assert_eq!(payload.get_var_int().unwrap().0, 25570);
+    }
+
+    #[tokio::test]
+    async fn test_cookies_are_stored_before_the_transfer() {
+        let config = test_config(
+            r#"
+unrouteable_server:
+  address: "127.0.0.1:25570"
+transfer_cookies:
+  values:
+    "loadbalancer:origin": "eu-1"
+  session_id: "loadbalancer:session"
+"#,
+        );
+        let (mut connection, client) = connection_pair(config, Box::new(NoServerFinder)).await;
+
+        connection.handle_config_packet().await.unwrap();
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let mut cookies = Vec::new();
+        for _ in 0..2 {
+            let packet = reader.get_raw_packet().await.unwrap();
+            assert_eq!(packet.id, CONFIG_STORE_COOKIE_ID);
+            let mut payload = &packet.payload[..];
+            let key = payload.get_string().unwrap();
+            let length = payload.get_var_int().unwrap().0 as usize;
+            assert_eq!(payload.len(), length);
+            cookies.push((key, String::from_utf8(payload.to_vec()).unwrap()));
+        }
+        assert_eq!(
+            cookies[0],
+            ("loadbalancer:origin".to_string(), "eu-1".to_string())
+        );
+        assert_eq!(cookies[1].0, "loadbalancer:session");
+        assert_eq!(cookies[1].1.len(), 32);
+        assert_eq!(
+            connection.access_log.session_id.as_ref(),
+            Some(&cookies[1].1)
+        );
+
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CTransfer::PACKET_ID);
+    }
+
+    #[test]
+    fn test_parse_handshake_address() {
+        for (address, hostname, mod_loader) in [
+            ("na.example.com", "na.example.com", ModLoader::Vanilla),
+            ("NA.Example.com.", "na.example.com", ModLoader::Vanilla),
+            ("na.example.com\0FML\0", "na.example.com", ModLoader::Fml),
+            ("na.example.com\0FML2\0", "na.example.com", ModLoader::Fml2),
+            ("na.example.com.\0FML3\0", "na.example.com", ModLoader::Fml3),
+            ("na.example.com\0OTHER\0", "na.example.com", ModLoader::Vanilla),
+        ] {
+            assert_eq!(
+                parse_handshake_address(address),
+                (hostname.to_string(), mod_loader),
+                "{:?}",
+                address
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake_records_hostname() {
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let mut payload = Vec::new();
+        payload.write_var_int(&VarInt(772)).unwrap();
+        payload.write_string("eu.example.com\0FML3\0").unwrap();
+        payload.extend_from_slice(&25565u16.to_be_bytes());
+        payload.write_var_int(&VarInt(2)).unwrap();
+        let mut packet = RawPacket {
+            id: SHandShake::PACKET_ID,
+            payload: payload.into(),
+        };
+
+        connection.handle_handshake_packet(&mut packet).await.unwrap();
+        assert_eq!(connection.hostname, "eu.example.com");
+        assert_eq!(connection.mod_loader, ModLoader::Fml3);
+        assert_eq!(connection.protocol_version, 772);
+    }
+
+    fn login_start_packet(name: &str) -> RawPacket {
+        let mut payload = vec![name.len() as u8];
+        payload.extend_from_slice(name.as_bytes());
+        payload.extend_from_slice(&[0u8; 16]);
+        RawPacket {
+            id: SLoginStart::PACKET_ID,
+            payload: payload.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_username_is_disconnected() {
+        let config = test_config("blocklist:\n  usernames: [\"Griefer\"]");
+        let (mut connection, client) = connection_pair(config, Box::new(NoServerFinder)).await;
+        connection.state = Login;
+        connection.protocol_version = 772;
+
+        let result = connection
+            .handle_login_packet(&mut login_start_packet("Griefer"))
+            .await;
+        assert_eq!(result.unwrap(), PacketOutcome::Close);
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CLoginDisconnect::PACKET_ID);
+    }
+
+    #[test]
+    fn test_offline_uuid_matches_the_vanilla_one() {
+        assert_eq!(
+            offline_uuid("Notch").to_string(),
+            "b50ad385-829d-3141-a216-7e7d7539ba7f"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_offline_mode_logs_in_with_the_offline_uuid() {
+        let (mut connection, client) =
+            connection_pair(test_config("offline_mode: true"), Box::new(NoServerFinder)).await;
+        connection.state = Login;
+        connection.protocol_version = 772;
+
+        // The client sent an all-zero UUID.
+        connection
+            .handle_login_packet(&mut login_start_packet("Notch"))
+            .await
+            .unwrap();
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CLoginSuccess::PACKET_ID);
+        assert_eq!(&packet.payload[..16], offline_uuid("Notch").as_bytes());
+    }
+
+    fn unknown_packet() -> RawPacket {
+        RawPacket {
+            id: 0x7f,
+            payload: vec![1, 2, 3].into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_silent_client_is_dropped() {
+        let config = test_config("handshake_timeout_ms: 100");
+        let (mut connection, _client) = connection_pair(config, Box::new(NoServerFinder)).await;
+
+        let started = Instant::now();
+        let keep_going = tokio::time::timeout(Duration::from_secs(5), connection.process_packets())
+            .await
+            .expect("the handshake timeout should end the read");
+        assert!(!keep_going);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_events_carry_the_context_id() {
+        let config = test_config("handshake_timeout_ms: 50");
+        let (mut connection, _client) = connection_pair(config, Box::new(NoServerFinder)).await;
+
+        assert!(!connection.process_packets().await);
+        let context_id = format!("context_id={}", connection.access_log.context_id);
+        logs_assert(|lines: &[&str]| {
+            let found = lines
+                .iter()
+                .any(|line| line.contains("no packet within") && line.contains(&context_id));
+            if found {
+                Ok(())
+            } else {
+                Err(format!("no timeout event in a span with {}", context_id))
+            }
+        });
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_closing_mid_packet_is_not_an_error() {
+        let (mut connection, mut client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        // The first byte of a length prefix that promises another.
+        tokio::io::AsyncWriteExt::write_all(&mut client, &[0x80])
+            .await
+            .unwrap();
+        drop(client);
+
+        assert!(!connection.process_packets().await);
+        assert!(logs_contain("Client disconnected"));
+        logs_assert(
+            |lines: &[&str]| match lines.iter().find(|line| line.contains("ERROR")) {
+                Some(line) => Err(format!("logged as an error: {}", line)),
+                None => Ok(()),
+            },
+        );
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_transfer_closes_the_connection_without_an_error() {
+        let config = test_config("unrouteable_server:\n  address: \"127.0.0.1:25570\"");
+        let (mut connection, mut client) = connection_pair(config, Box::new(NoServerFinder)).await;
+        connection.state = Config;
+
+        // An empty Client Information packet, whose locale can't be read.
+        tokio::io::AsyncWriteExt::write_all(&mut client, &[0x01, 0x00])
+            .await
+            .unwrap();
+
+        assert!(!connection.process_packets().await);
+        assert!(logs_contain("Transferring to 127.0.0.1:25570"));
+        assert!(!logs_contain("Failed"));
+        assert!(!logs_contain("Disconnect"));
+    }
+
+    // A framed Login Start holding only the name, which every refusal reads
+    // before anything else.
+    fn framed_login_start(name: &str) -> Vec<u8> {
+        let mut frame = vec![(name.len() + 2) as u8, SLoginStart::PACKET_ID as u8];
+        frame.push(name.len() as u8);
+        frame.extend_from_slice(name.as_bytes());
+        frame
+    }
+
+    fn no_errors_logged(lines: &[&str]) -> Result<(), String> {
+        match lines.iter().find(|line| line.contains("ERROR")) {
+            Some(line) => Err(format!("logged as an error: {}", line)),
+            None => Ok(()),
+        }
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_refused_logins_close_the_connection_without_an_error() {
+        let cases = [
+            ("maintenance:\n  enabled: true", 772),
+            ("blocklist:\n  usernames: [\"Notch\"]", 772),
+            (
+                "unrouteable_server:\n  address: \"lobby.example.com\"",
+                TRANSFER_MIN_PROTOCOL - 1,
+            ),
+            // An old client with nowhere to go.
+            ("", TRANSFER_MIN_PROTOCOL - 1),
+        ];
+        for (extra, protocol_version) in cases {
+            let (mut connection, mut client) =
+                connection_pair(test_config(extra), Box::new(NoServerFinder)).await;
+            connection.state = Login;
+            connection.protocol_version = protocol_version;
+
+            tokio::io::AsyncWriteExt::write_all(&mut client, &framed_login_start("Notch"))
+                .await
+                .unwrap();
+
+            assert!(!connection.process_packets().await, "{}", extra);
+            let (mut reader, _client_write) = client_decoder(client);
+            let packet = reader.get_raw_packet().await.unwrap();
+            assert_eq!(packet.id, CLoginDisconnect::PACKET_ID, "{}", extra);
+        }
+        logs_assert(no_errors_logged);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_unrouteable_player_is_kicked_without_an_error() {
+        let (mut connection, mut client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        connection.state = Config;
+
+        // An empty Client Information packet, whose locale can't be read.
+        tokio::io::AsyncWriteExt::write_all(&mut client, &[0x01, 0x00])
+            .await
+            .unwrap();
+
+        assert!(!connection.process_packets().await);
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CConfigDisconnect::PACKET_ID);
+        logs_assert(no_errors_logged);
+    }
+
+    #[tokio::test]
+    async fn test_client_information_sets_the_locale() {
+        let config = test_config("unrouteable_server:\n  address: \"127.0.0.1:25570\"");
+        let (mut connection, mut client) = connection_pair(config, Box::new(NoServerFinder)).await;
+        connection.state = Config;
+
+        // The brand, as vanilla clients send it first, then Client
+        // Information cut short after its locale.
+        let mut packets = brand_packet();
+        packets.extend_from_slice(&[0x07, 0x00, 0x05, b'f', b'r', b'_', b'F', b'R']);
+        tokio::io::AsyncWriteExt::write_all(&mut client, &packets)
+            .await
+            .unwrap();
+
+        assert!(!connection.process_packets().await);
+        assert_eq!(connection.locale.as_deref(), Some("fr_FR"));
+        assert_eq!(connection.access_log.locale.as_deref(), Some("fr_FR"));
+    }
+
+    // A framed Plugin Message carrying the client's brand.
+    fn brand_packet() -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.write_var_int(&VarInt(0x02)).unwrap();
+        packet.write_string("minecraft:brand").unwrap();
+        packet.write_string("vanilla").unwrap();
+        let mut framed = Vec::new();
+        framed.write_var_int(&VarInt(packet.len() as i32)).unwrap();
+        framed.extend(packet);
+        framed
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_client_without_client_information_is_still_transferred() {
+        let config = test_config("unrouteable_server:\n  address: \"127.0.0.1:25570\"");
+        let (mut connection, mut client) = connection_pair(config, Box::new(NoServerFinder)).await;
+        connection.state = Config;
+
+        tokio::io::AsyncWriteExt::write_all(&mut client, &brand_packet())
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        assert!(!connection.process_packets().await);
+        assert!(started.elapsed() >= CLIENT_INFORMATION_WAIT);
+        assert!(logs_contain("Transferring to 127.0.0.1:25570"));
+        assert_eq!(connection.locale, None);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_malformed_length_prefix_is_logged() {
+        let (mut connection, mut client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        // Every byte has the continuation bit set, longer than any VarInt.
+        tokio::io::AsyncWriteExt::write_all(&mut client, &[0xFF; 6])
+            .await
+            .unwrap();
+
+        assert!(!connection.process_packets().await);
+        assert!(logs_contain("Failed to read packet (State: HandShake)"));
+        assert!(logs_contain("VarInt"));
+        assert!(!logs_contain("Client disconnected"));
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_oversized_packet_closes_the_connection() {
+        let (mut connection, mut client) = connection_pair(
+            test_config("max_packet_size: 1024"),
+            Box::new(NoServerFinder),
+        )
+        .await;
+
+        // Claims a 1 GiB packet, and never sends it.
+        tokio::io::AsyncWriteExt::write_all(&mut client, &[0x80, 0x80, 0x80, 0x80, 0x04])
+            .await
+            .unwrap();
+
+        assert!(!connection.process_packets().await);
+        assert!(logs_contain(
+            "packet of 1073741824 bytes is over the 1024 byte limit"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_handshake_packet_is_fatal() {
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let result = connection.handle_packet(&mut unknown_packet()).await;
+        assert!(result.is_err());
+        assert!(matches!(connection.state, HandShake));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_status_and_login_packets_are_ignored() {
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        connection.state = Status;
+        connection.handle_packet(&mut unknown_packet()).await.unwrap();
+        assert!(matches!(connection.state, Status));
+
+        connection.state = Login;
+        connection.handle_packet(&mut unknown_packet()).await.unwrap();
+        assert!(matches!(connection.state, Login));
+    }
+
+    #[tokio::test]
+    async fn test_ping_is_echoed_without_a_status_request() {
+        let (mut connection, client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        connection.state = Status;
+
+        let payload = 0x0102_0304_0506_0708_i64.to_be_bytes();
+        let mut ping = RawPacket {
+            id: SStatusPingRequest::PACKET_ID,
+            payload: payload.to_vec().into(),
+        };
+        connection.handle_packet(&mut ping).await.unwrap();
+        // A status request after the ping is still answered.
+        let mut request = RawPacket {
+            id: SStatusRequest::PACKET_ID,
+            payload: Vec::new().into(),
+        };
+        connection.handle_packet(&mut request).await.unwrap();
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let pong = reader.get_raw_packet().await.unwrap();
+        assert_eq!(pong.id, CPingResponse::PACKET_ID);
+        assert_eq!(&pong.payload[..], &payload[..]);
+        let status = reader.get_raw_packet().await.unwrap();
+        assert_eq!(status.id, CStatusResponse::PACKET_ID);
+    }
+
+    #[tokio::test]
+    async fn test_status_shows_the_configured_motd() {
+        let mut config = test_config("");
+        config.static_cfg.as_mut().unwrap().motd = Some("Static motd".to_string());
+        let (mut connection, client) = connection_pair(config, Box::new(NoServerFinder)).await;
+        connection.state = Status;
+
+        let mut request = RawPacket {
+            id: SStatusRequest::PACKET_ID,
+            payload: Vec::new().into(),
+        };
+        connection.handle_packet(&mut request).await.unwrap();
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CStatusResponse::PACKET_ID);
+        let mut payload = &packet.payload[..];
+        let json: serde_json::Value = serde_json::from_str(&payload.get_string().unwrap()).unwrap();
+        assert_eq!(json["description"], "Static motd");
+    }
+
+    #[tokio::test]
+    async fn test_old_clients_are_told_where_to_connect() {
+        let config = test_config("unrouteable_server:\n  address: \"lobby.example.com\"");
+        let (mut connection, client) = connection_pair(config, Box::new(NoServerFinder)).await;
+        connection.state = Login;
+        connection.protocol_version = TRANSFER_MIN_PROTOCOL - 1;
+
+        let result = connection
+            .handle_login_packet(&mut login_start_packet("Notch"))
+            .await;
+        assert_eq!(result.unwrap(), PacketOutcome::Close);
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CLoginDisconnect::PACKET_ID);
+        let reason = String::from_utf8_lossy(&packet.payload);
+        assert!(reason.contains("lobby.example.com"), "{}", reason);
+        assert_eq!(connection.access_log.username.as_deref(), Some("Notch"));
+    }
+
+    #[tokio::test]
+    async fn test_old_clients_are_checked_against_the_blocklist() {
+        let config = test_config(
+            "unrouteable_server:\n  address: \"lobby.example.com\"\nblocklist:\n  usernames: [\"Griefer\"]",
+        );
+        let (mut connection, client) = connection_pair(config, Box::new(NoServerFinder)).await;
+        connection.state = Login;
+        connection.protocol_version = TRANSFER_MIN_PROTOCOL - 1;
+
+        // Before 1.19 the name is all the packet holds.
+        let mut packet = RawPacket {
+            id: SLoginStart::PACKET_ID,
+            payload: [&[7u8][..], b"Griefer"].concat().into(),
+        };
+        let result = connection.handle_login_packet(&mut packet).await;
+        assert_eq!(result.unwrap(), PacketOutcome::Close);
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CLoginDisconnect::PACKET_ID);
+        let reason = String::from_utf8_lossy(&packet.payload);
+        assert!(reason.contains("not allowed"), "{}", reason);
+        assert!(!reason.contains("lobby.example.com"), "{}", reason);
+    }
+
+    #[tokio::test]
+    async fn test_players_are_turned_away_during_maintenance() {
+        let config = test_config("maintenance:\n  enabled: true\n  message: \"Back soon\"");
+        let (mut connection, client) = connection_pair(config, Box::new(NoServerFinder)).await;
+        connection.state = Login;
+        connection.protocol_version = 772;
+
+        let result = connection
+            .handle_login_packet(&mut login_start_packet("Notch"))
+            .await;
+        assert_eq!(result.unwrap(), PacketOutcome::Close);
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CLoginDisconnect::PACKET_ID);
+        let reason = String::from_utf8_lossy(&packet.payload);
+        assert!(reason.contains("Back soon"), "{}", reason);
+    }
+
+    #[tokio::test]
+    async fn test_unrouteable_player_is_kicked_without_lobby() {
+        let (mut connection, client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        connection.state = Config;
+
+        connection.handle_config_packet().await.unwrap();
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CConfigDisconnect::PACKET_ID);
+        let reason = String::from_utf8_lossy(&packet.payload);
+        assert!(reason.contains("No servers are available"), "{}", reason);
+    }
+
+    #[tokio::test]
+    async fn test_player_is_kicked_when_every_backend_is_full() {
+        let config = LoadBalancerConfig::from_yaml_str(
+            r#"
+mode: static
+static:
+  algorithm: round_robin
+  servers:
+    - address: "127.0.0.1:25570"
+      max_connections: 1
+"#,
+        )
+        .unwrap();
+        let finder = get_server_finder(config.clone()).unwrap();
+        let _player = finder.backends()[0].route_connection();
+        let (mut connection, client) = connection_pair(config, finder).await;
+        connection.state = Config;
+
+        connection.handle_config_packet().await.unwrap();
+
+        let (mut reader, _client_write) = client_decoder(client);
+        let packet = reader.get_raw_packet().await.unwrap();
+        assert_eq!(packet.id, CConfigDisconnect::PACKET_ID);
+        let reason = String::from_utf8_lossy(&packet.payload);
+        assert!(reason.contains("The server is full"), "{}", reason);
     }
 }
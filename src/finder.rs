@@ -1,38 +1,288 @@
-use crate::backend::MinecraftServer;
-use crate::config::{Algorithm, Config, GeoConfig, Mode, Server, StaticConfig};
+use crate::address_resolver::with_port;
+use crate::backend::{MinecraftServer, PingOptions};
+use crate::config::{
+    AffinityConfig, Algorithm, BackendCountSource, CompositeWeights, Config, Coordinates,
+    GeoConfig, HostnameConfig, HttpConfig, HttpMethod, LocaleRoutingConfig, Mode, Server,
+    StaticConfig,
+};
 use crate::connection::Connection;
-use crate::geo_api::GeoCache;
+use crate::geo_api::{GeoLookupError, GeoProvider, IpInfo, provider_from_config};
+use crate::metrics::metrics;
+use crate::status::favicon_or_warn;
 use async_trait::async_trait;
-use futures::{StreamExt, future::join_all, stream};
-use log::info;
+use futures::{StreamExt, stream};
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use lru::LruCache;
+use rand::Rng;
 use reqwest::Client;
-use std::{collections::HashMap, error::Error, time::Duration};
-use tokio::time::timeout;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use std::{collections::HashMap, error::Error, fs, net::IpAddr};
+use thiserror::Error;
+
+/// Why a finder could not pick a backend for a player.
+#[derive(Debug, Error)]
+pub enum FinderError {
+    #[error("No servers are configured")]
+    NoServersAvailable,
+    #[error("Every backend is unreachable")]
+    AllBackendsDown,
+    #[error("Every backend is at its connection limit")]
+    Full,
+    #[error("No route for hostname '{0}'")]
+    NoRoute(String),
+    #[error("Geo lookup failed: {0}")]
+    GeoLookup(String),
+}
+
+/// What the server list shows a particular client in place of the configured
+/// motd, version name and icon. Fields left None keep the configured value.
+#[derive(Debug, Clone, Default)]
+pub struct StatusOverrides {
+    pub version_name: Option<String>,
+    pub motd: Option<String>,
+    /// A data URI.
+    pub favicon: Option<String>,
+    /// The backend whose player count is shown instead of the total.
+    pub backend: Option<MinecraftServer>,
+}
 
 #[async_trait]
 pub trait ServerFinder: Send + Sync {
-    async fn get_player_count(&self) -> u32;
+    /// The total player count across backends, or None when none of them answered.
+    async fn get_player_count(&self) -> Option<u32>;
+
+    /// Every backend this finder can select, for diagnostics.
+    fn backends(&self) -> Vec<MinecraftServer>;
+
+    /// Spaces out the player count polls of the `backends` that keep failing,
+    /// for finders that keep track of them.
+    fn poll_backoff(&self) -> Option<Arc<PollBackoff>> {
+        None
+    }
+
+    /// The same backends, mutably, so a reload can hand them the state of
+    /// the ones they replace. Finders that return none start afresh.
+    fn backends_mut(&mut self) -> Vec<&mut MinecraftServer> {
+        Vec::new()
+    }
 
     async fn find_server(
         &mut self,
         connection: &Connection,
-    ) -> Result<MinecraftServer, Box<dyn Error>>;
+    ) -> Result<MinecraftServer, FinderError>;
+
+    /// The provider to look a client at `ip` up with before asking for its
+    /// `status_overrides`, when they depend on where the client is.
+    fn status_geo(&self, _ip: IpAddr) -> Option<Arc<dyn GeoProvider>> {
+        None
+    }
+
+    /// What the server list shows a client at `ip`, given what the
+    /// `status_geo` lookup found out about it, if anything.
+    fn status_overrides(&self, _ip: IpAddr, _ip_info: Option<&IpInfo>) -> StatusOverrides {
+        StatusOverrides::default()
+    }
+
+    /// Called once the client of `connection` was transferred to `server`.
+    fn record_transfer(&mut self, _connection: &Connection, _server: &MinecraftServer) {}
+
+    /// Writes any state worth keeping across restarts.
+    fn save_state(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Releases anything held open, at shutdown after the last `save_state`.
+    fn close(&self) {}
+}
+
+// Most servers an HTTP endpoint named that are kept track of at once.
+const MAX_ROUTED_SERVERS: NonZeroUsize = NonZeroUsize::new(256).unwrap();
+
+// Most poll cycles a failing backend sits out, however many polls it failed.
+// Kept low so a backend that comes back is polled again within four cycles,
+// about a minute at the default interval.
+const MAX_SKIPPED_POLLS: u32 = 3;
+
+/// Spaces out the player count polls of backends that keep failing, so a dead
+/// backend doesn't cost a timeout and a log line every cycle. After `n` failed
+/// polls in a row a backend sits out `2^(n-1) - 1` cycles, up to
+/// `MAX_SKIPPED_POLLS`, and its first successful poll clears the backoff.
+#[derive(Debug, Default)]
+pub struct PollBackoff {
+    backends: Mutex<HashMap<String, FailedPolls>>,
+}
+
+#[derive(Debug, Default)]
+struct FailedPolls {
+    failures: u32,
+    skip: u32,
+}
+
+impl PollBackoff {
+    // Whether to poll `address` this cycle, counting down the cycles it sits out.
+    fn should_poll(&self, address: &str) -> bool {
+        let mut backends = self.backends.lock().unwrap();
+        match backends.get_mut(address) {
+            Some(failed) if failed.skip > 0 => {
+                failed.skip -= 1;
+                debug!(
+                    "Skipping player count poll of {} after {} failures",
+                    address, failed.failures
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+
+    // Drops what is known about `address`, once the finder no longer has it.
+    fn forget(&self, address: &str) {
+        self.backends.lock().unwrap().remove(address);
+    }
+
+    fn record(&self, address: &str, ok: bool) {
+        let mut backends = self.backends.lock().unwrap();
+        if ok {
+            backends.remove(address);
+            return;
+        }
+        let failed = backends.entry(address.to_string()).or_default();
+        failed.failures += 1;
+        failed.skip = (2u32.saturating_pow(failed.failures - 1) - 1).min(MAX_SKIPPED_POLLS);
+    }
+}
+
+// Sums the counts of the backends that answered, or None if none did.
+fn total_player_count(counts: Vec<Option<u32>>) -> Option<u32> {
+    counts.into_iter().flatten().reduce(u32::saturating_add)
+}
+
+/// Pings `servers`, at most `concurrency` at once and skipping those `backoff`
+/// holds back, and sums the player counts of those that answered, or None if
+/// none did. Works on copies of a finder's backends, so the finder needn't
+/// stay locked during the pings.
+pub async fn poll_backends(
+    servers: &[MinecraftServer],
+    concurrency: usize,
+    backoff: Option<&PollBackoff>,
+) -> Option<u32> {
+    let start_time = Instant::now();
+    let total = poll_servers(servers, concurrency, backoff).await;
+    let elapsed = start_time.elapsed();
+    debug!("Getting player counts took {:?}", elapsed);
+    metrics().record_poll_latency(elapsed);
+    total
+}
+
+async fn poll_servers(
+    servers: &[MinecraftServer],
+    concurrency: usize,
+    backoff: Option<&PollBackoff>,
+) -> Option<u32> {
+    let counts: Vec<Option<u32>> = stream::iter(servers)
+        .map(|server| async move {
+            if backoff.is_some_and(|backoff| !backoff.should_poll(&server.address)) {
+                return None;
+            }
+            let result = server.get_player_count().await;
+            if let Some(backoff) = backoff {
+                backoff.record(&server.address, result.is_ok());
+            }
+            metrics().set_backend_up(&server.address, result.is_ok());
+            if let Err(error) = &result {
+                info!(
+                    "Error getting player count from server {}: {}",
+                    server.address, error
+                );
+            }
+            result.ok()
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    total_player_count(counts)
+}
+
+/// Builds a finder for `mode: custom` from the whole config. Its own settings
+/// are under `custom.options`. It may build other finders, including custom
+/// ones, with `get_server_finder`.
+pub type FinderConstructor =
+    Arc<dyn Fn(&Config) -> Result<Box<dyn ServerFinder>, Box<dyn Error>> + Send + Sync>;
+
+// Finders registered for `mode: custom`, by name. Constructors are cloned out
+// and run with the lock released, so they can look up others.
+static CUSTOM_FINDERS: LazyLock<Mutex<HashMap<String, FinderConstructor>>> =
+    LazyLock::new(Default::default);
+
+/// Makes `constructor` available to configs with `mode: custom` and
+/// `custom.finder` set to `name`, replacing any finder registered under that
+/// name. Finders already built are unaffected, so register before loading
+/// the config.
+pub fn register_finder(
+    name: impl Into<String>,
+    constructor: impl Fn(&Config) -> Result<Box<dyn ServerFinder>, Box<dyn Error>>
+    + Send
+    + Sync
+    + 'static,
+) {
+    CUSTOM_FINDERS
+        .lock()
+        .unwrap()
+        .insert(name.into(), Arc::new(constructor));
 }
 
 pub fn get_server_finder(config: Config) -> Result<Box<dyn ServerFinder>, Box<dyn Error>> {
+    let ping = PingOptions::from_config(&config);
+    let locale_routing = config.locale_routing.clone();
+    let affinity = config.affinity.clone().filter(|affinity| affinity.enabled);
+    let mut finder = mode_finder(config, ping)?;
+    if let Some(routing) = locale_routing {
+        finder = Box::new(LocaleServerFinder::new(routing, finder, ping));
+    }
+    if let Some(affinity) = affinity {
+        finder = Box::new(AffinityServerFinder::new(affinity, finder));
+    }
+    Ok(finder)
+}
+
+fn mode_finder(config: Config, ping: PingOptions) -> Result<Box<dyn ServerFinder>, Box<dyn Error>> {
     match config.mode {
         Mode::Static => match config.static_cfg {
             None => Err("Invalid static server find config.".into()),
-            Some(config) => Ok(Box::new(StaticServerFiner::new(config))),
+            Some(config) => Ok(Box::new(StaticServerFiner::new(config, ping))),
         },
         Mode::Geo => match config.geo_cfg {
             None => Err("Invalid geo location config".into()),
             Some(config) => {
-                let finder = GeoServerFinder::new(config)?;
+                let finder = GeoServerFinder::new(config, ping)?;
                 Ok(Box::new(finder))
             }
         },
-        Mode::Http => Err("TODO".into()),
+        Mode::Http => match config.http_cfg {
+            None => Err("Invalid http routing config".into()),
+            Some(config) => Ok(Box::new(HttpServerFinder::new(config, ping))),
+        },
+        Mode::Hostname => match config.hostname_cfg {
+            None => Err("Invalid hostname routing config".into()),
+            Some(config) => Ok(Box::new(HostnameServerFinder::new(config, ping))),
+        },
+        Mode::Custom => match &config.custom_cfg {
+            None => Err("Invalid custom finder config".into()),
+            Some(custom) => {
+                let constructor = CUSTOM_FINDERS
+                    .lock()
+                    .unwrap()
+                    .get(&custom.finder)
+                    .cloned()
+                    .ok_or_else(|| format!("No finder is registered as '{}'", custom.finder))?;
+                constructor(&config)
+            }
+        },
     }
 }
 
@@ -40,159 +290,2582 @@ struct StaticServerFiner {
     servers: Vec<MinecraftServer>,
     mode: Algorithm,
     last_index: usize,
+    default_max_players: u32,
+    composite_weights: CompositeWeights,
+    state_file: Option<PathBuf>,
+    version_name: Option<String>,
+    poll_concurrency: usize,
+    poll_backoff: Arc<PollBackoff>,
+}
+
+/// What `StaticServerFiner` writes to its state file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RotationState {
+    last_index: usize,
 }
 
 impl StaticServerFiner {
-    pub fn new(config: StaticConfig) -> Self {
+    pub fn new(config: StaticConfig, ping: PingOptions) -> Self {
         let servers = config
             .servers
             .iter()
-            .map(|x| MinecraftServer::new(x.address.clone()))
+            .map(|server| MinecraftServer::from_config(server, ping))
             .collect();
+        let state_file = config.state_file.as_ref().map(PathBuf::from);
+        let state = state_file
+            .as_deref()
+            .map(load_rotation_state)
+            .unwrap_or_default();
+
         StaticServerFiner {
             servers,
             mode: config.algorithm,
-            last_index: 0,
+            last_index: state.last_index,
+            default_max_players: config.default_max_players(),
+            composite_weights: config.composite_weights.unwrap_or_default(),
+            state_file,
+            version_name: config.version_name,
+            poll_concurrency: ping.poll_concurrency,
+            poll_backoff: Arc::default(),
+        }
+    }
+
+    // Servers that may receive new players: those of the active tier that
+    // aren't draining or at capacity.
+    fn selectable(&self) -> Vec<MinecraftServer> {
+        let tier = self.active_tier();
+        self.servers
+            .iter()
+            .filter(|server| in_tier(server, tier))
+            .cloned()
+            .collect()
+    }
+
+    // The selectable servers, less those already found unreachable for the
+    // connection being placed.
+    fn candidates(&self, unreachable: &[String]) -> Vec<MinecraftServer> {
+        let mut candidates = self.selectable();
+        candidates.retain(|server| !unreachable.contains(&server.address));
+        candidates
+    }
+
+    // The lowest tier with a server that isn't draining or at capacity and,
+    // going by the last poll, is up and has room. None when no server
+    // qualifies, in which case every tier is used.
+    fn active_tier(&self) -> Option<u32> {
+        self.servers
+            .iter()
+            .filter(|server| server.accepts_players())
+            .filter(|server| match server.last_player_count() {
+                Some(count) => count < server.max_players.unwrap_or(self.default_max_players),
+                // Not polled yet, or the last poll failed.
+                None => !server.is_down(),
+            })
+            .map(|server| server.tier)
+            .min()
+    }
+
+    // The server nearest to `client` among those with coordinates in the
+    // active tier, leaving out any that are down or in `unreachable`.
+    fn nearest(&self, client: Coordinates, unreachable: &[String]) -> Option<MinecraftServer> {
+        let tier = self.active_tier();
+        self.servers
+            .iter()
+            .filter(|server| in_tier(server, tier) && !server.is_down())
+            .filter(|server| !unreachable.contains(&server.address))
+            .filter_map(|server| Some((server, distance_km(server.coordinates?, client))))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(server, _)| server.clone())
+    }
+
+    // Pings every candidate server, leaving out the unreachable ones.
+    async fn player_counts(&self, unreachable: &[String]) -> Vec<(MinecraftServer, u32)> {
+        stream::iter(self.candidates(unreachable))
+            .map(|server| async move {
+                let count = server.get_player_count().await.ok();
+                count.map(|count| (server, count))
+            })
+            .buffer_unordered(self.poll_concurrency)
+            .filter_map(|result| async move { result })
+            .collect()
+            .await
+    }
+
+    // Measures the latency of every candidate server, with the player count
+    // read by the same ping, leaving out the unreachable ones.
+    async fn loads_and_latencies(
+        &self,
+        unreachable: &[String],
+    ) -> Vec<(MinecraftServer, u32, Duration)> {
+        stream::iter(self.candidates(unreachable))
+            .map(|server| async move {
+                let latency = server.get_latency().await.ok()?;
+                let count = server.last_player_count()?;
+                Some((server, count, latency))
+            })
+            .buffer_unordered(self.poll_concurrency)
+            .filter_map(|result| async move { result })
+            .collect()
+            .await
+    }
+
+    // The error for when no candidate backend is left.
+    fn unavailable(&self) -> FinderError {
+        if self.selectable().is_empty() {
+            none_accepting(&self.servers)
+        } else {
+            FinderError::AllBackendsDown
         }
     }
 }
 
 #[async_trait]
 impl ServerFinder for StaticServerFiner {
-    async fn get_player_count(&self) -> u32 {
-        let start_time = std::time::Instant::now();
+    async fn get_player_count(&self) -> Option<u32> {
+        poll_backends(
+            &self.servers,
+            self.poll_concurrency,
+            Some(&self.poll_backoff),
+        )
+        .await
+    }
 
-        let futures: Vec<_> = self
-            .servers
-            .iter()
-            .map(|x| async move {
-                let result: Result<u32, Box<dyn Error>> =
-                    timeout(Duration::from_secs(5), x.get_player_count())
-                        .await
-                        .map_err(|x| x.into())
-                        .flatten();
-                if result.is_err() {
-                    info!(
-                        "Error getting player count from server {}: {}",
-                        x.address,
-                        result.as_ref().err().unwrap()
-                    );
-                }
-                result.unwrap_or(0)
-            })
-            .collect();
+    fn backends(&self) -> Vec<MinecraftServer> {
+        self.servers.clone()
+    }
+
+    fn poll_backoff(&self) -> Option<Arc<PollBackoff>> {
+        Some(self.poll_backoff.clone())
+    }
+
+    fn backends_mut(&mut self) -> Vec<&mut MinecraftServer> {
+        self.servers.iter_mut().collect()
+    }
+
+    fn status_overrides(&self, _ip: IpAddr, _ip_info: Option<&IpInfo>) -> StatusOverrides {
+        StatusOverrides {
+            version_name: self.version_name.clone(),
+            ..StatusOverrides::default()
+        }
+    }
 
-        let total = join_all(futures).await.iter().sum();
-        let elapsed = start_time.elapsed();
-        info!("Getting player counts took {:?}", elapsed);
-        total
+    fn save_state(&self) -> Result<(), Box<dyn Error>> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let state = RotationState {
+            last_index: self.last_index,
+        };
+        fs::write(path, serde_json::to_string(&state)?)?;
+        Ok(())
     }
 
     async fn find_server(
         &mut self,
         connection: &Connection,
-    ) -> Result<MinecraftServer, Box<dyn Error>> {
+    ) -> Result<MinecraftServer, FinderError> {
         match self.mode {
             Algorithm::RoundRobin => {
-                let index = self.last_index + 1;
-                if index >= self.servers.len() {
-                    self.last_index = 0;
-                } else {
-                    self.last_index = index;
-                }
+                let tier = self.active_tier();
+                for _ in 0..self.servers.len() {
+                    let index = self.last_index + 1;
+                    if index >= self.servers.len() {
+                        self.last_index = 0;
+                    } else {
+                        self.last_index = index;
+                    }
 
-                let server = self
-                    .servers
-                    .get(self.last_index)
-                    .ok_or("Couldn't find server")?
-                    .clone();
-
-                Ok(server)
+                    let server = &self.servers[self.last_index];
+                    if in_tier(server, tier) && !connection.unreachable.contains(&server.address) {
+                        return Ok(server.clone());
+                    }
+                }
+                Err(none_accepting(&self.servers))
+            }
+            Algorithm::LowestPlayerCount => self
+                .player_counts(&connection.unreachable)
+                .await
+                .into_iter()
+                .min_by_key(|(_, count)| *count)
+                .map(|x| x.0)
+                .ok_or_else(|| self.unavailable()),
+            Algorithm::LowestLoadRatio => {
+                let default_max_players = self.default_max_players;
+                self.player_counts(&connection.unreachable)
+                    .await
+                    .into_iter()
+                    .map(|(server, count)| {
+                        let max_players = server.max_players.unwrap_or(default_max_players);
+                        let ratio = count as f64 / max_players.max(1) as f64;
+                        (server, ratio)
+                    })
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|x| x.0)
+                    .ok_or_else(|| self.unavailable())
+            }
+            Algorithm::LeastConnections => self
+                .candidates(&connection.unreachable)
+                .into_iter()
+                .min_by_key(|server| server.active_connections())
+                .ok_or_else(|| none_accepting(&self.servers)),
+            Algorithm::Composite => {
+                let mut measured = self.loads_and_latencies(&connection.unreachable).await;
+                let loads: Vec<(u32, Duration)> = measured
+                    .iter()
+                    .map(|(_, count, latency)| (*count, *latency))
+                    .collect();
+                match lowest_composite_score(&loads, self.composite_weights) {
+                    Some(index) => Ok(measured.swap_remove(index).0),
+                    None => Err(self.unavailable()),
+                }
             }
-            Algorithm::LowestPlayerCount => {
-                let result: Vec<_> = stream::iter(self.servers.clone())
+            Algorithm::LowestLatency => {
+                let result: Vec<_> = stream::iter(self.candidates(&connection.unreachable))
                     .map(|server| async move {
-                        (
-                            server.clone(),
-                            server.get_player_count().await.unwrap_or(u32::MAX),
-                        )
+                        let latency = server.get_latency().await.ok();
+                        latency.map(|latency| (server, latency))
                     })
-                    .buffer_unordered(5)
+                    .buffer_unordered(self.poll_concurrency)
+                    .filter_map(|result| async move { result })
                     .collect()
                     .await;
 
                 result
                     .into_iter()
-                    .min_by_key(|(_, count)| *count)
+                    .min_by_key(|(_, latency)| *latency)
                     .map(|x| x.0)
-                    .ok_or("No servers available".into())
+                    .ok_or_else(|| self.unavailable())
             }
         }
     }
 }
 
+fn in_tier(server: &MinecraftServer, tier: Option<u32>) -> bool {
+    server.accepts_players() && tier.is_none_or(|tier| server.tier == tier)
+}
+
+// The index of the lowest weighted sum of player count and latency, each
+// scaled to 0..=1 between the lowest and highest in `loads`.
+fn lowest_composite_score(loads: &[(u32, Duration)], weights: CompositeWeights) -> Option<usize> {
+    let counts = scaled(loads.iter().map(|(count, _)| *count as f64).collect());
+    let latencies = scaled(
+        loads
+            .iter()
+            .map(|(_, latency)| latency.as_secs_f64())
+            .collect(),
+    );
+    let score = |index: usize| {
+        weights.player_count() * counts[index] + weights.latency() * latencies[index]
+    };
+    (0..loads.len()).min_by(|&a, &b| score(a).total_cmp(&score(b)))
+}
+
+// The great-circle distance between two points, by the haversine formula.
+fn distance_km(a: Coordinates, b: Coordinates) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let half_lat = (lat_b - lat_a) / 2.0;
+    let half_lon = (b.longitude - a.longitude).to_radians() / 2.0;
+    let h = half_lat.sin().powi(2) + lat_a.cos() * lat_b.cos() * half_lon.sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+// `values` moved and stretched onto 0..=1; all 0 when they are equal.
+fn scaled(values: Vec<f64>) -> Vec<f64> {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    values
+        .into_iter()
+        .map(|value| {
+            if max > min {
+                (value - min) / (max - min)
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+// The error for when none of `servers` may receive new players: Full when
+// the ones that aren't draining are all at capacity.
+fn none_accepting(servers: &[MinecraftServer]) -> FinderError {
+    let mut open = servers
+        .iter()
+        .filter(|server| !server.is_draining())
+        .peekable();
+    if open.peek().is_some() && open.all(MinecraftServer::is_at_capacity) {
+        FinderError::Full
+    } else {
+        FinderError::NoServersAvailable
+    }
+}
+
+// A missing or unreadable state file just restarts the rotation.
+fn load_rotation_state(path: &Path) -> RotationState {
+    let Ok(raw) = fs::read_to_string(path) else {
+        return RotationState::default();
+    };
+    serde_json::from_str(&raw).unwrap_or_else(|error| {
+        warn!("Ignoring invalid state file {}: {}", path.display(), error);
+        RotationState::default()
+    })
+}
+
+/// Picks a backend from the client's location. Region keys are matched in
+/// order of precedence: the country code (e.g. `GB`), then the continent code
+/// (e.g. `EU`), then a weighted pick among the regions listed as adjacent to
+/// either, then the `"*"` wildcard region, and finally the `fallbacks`
+/// chain. Clients in an `overrides` range skip the lookup and go to the
+/// region it pins. Clients on private or loopback addresses skip it too and
+/// go to `local_region`, or the fallbacks if it is unset. Each region
+/// balances its own servers like a static group, except that clients the
+/// provider gives coordinates for go to the nearest server that has them.
 struct GeoServerFinder {
-    pub regions: HashMap<String, MinecraftServer>,
-    pub fallback: MinecraftServer,
-    pub geo_cache: GeoCache,
+    pub regions: HashMap<String, StaticServerFiner>,
+    pub fallbacks: Vec<MinecraftServer>,
+    pub local_region: Option<String>,
+    // IP ranges and the uppercase region keys they are pinned to, most
+    // specific range first.
+    pub overrides: Vec<(IpNet, String)>,
+    // Uppercase country or continent code to uppercase region keys and weights.
+    pub adjacent_regions: HashMap<String, Vec<(String, u32)>>,
+    // Keyed by uppercase region key, like `regions`.
+    pub version_names: HashMap<String, String>,
+    pub motds: HashMap<String, String>,
+    // Loaded as data URIs; regions whose icon failed to load are left out.
+    pub favicons: HashMap<String, String>,
+    pub regional_player_count: bool,
+    pub geo: Arc<dyn GeoProvider>,
     pub client: Client,
+    poll_concurrency: usize,
+    poll_backoff: Arc<PollBackoff>,
 }
 
 impl GeoServerFinder {
-    pub fn new(config: GeoConfig) -> Result<Self, Box<dyn Error>> {
+    pub fn new(config: GeoConfig, ping: PingOptions) -> Result<Self, Box<dyn Error>> {
         let client = Client::new();
+        let geo = provider_from_config(&config)?.into();
 
-        let regions: HashMap<String, MinecraftServer> = config
+        let algorithm = config.region_algorithm();
+        let composite_weights = config.composite_weights;
+        let regions: HashMap<String, StaticServerFiner> = config
             .regions
             .into_iter()
-            .map(|(key, server)| {
-                // transform server to ServerInfo
-                (key, MinecraftServer::new(server.address))
+            .map(|(key, servers)| {
+                // Lookups return uppercase codes, so match keys case-insensitively.
+                let key = key.to_ascii_uppercase();
+                let group = StaticConfig {
+                    algorithm,
+                    servers,
+                    default_max_players: None,
+                    composite_weights,
+                    state_file: None,
+                    version_name: None,
+                    motd: None,
+                };
+                let mut finder = StaticServerFiner::new(group, ping);
+                for server in &mut finder.servers {
+                    server.region = Some(key.clone());
+                }
+                (key, finder)
             })
             .collect();
 
-        let fallback = MinecraftServer::new(config.fallback.address);
-        let geo_cache = GeoCache::new(config.token)?;
+        let fallbacks = config
+            .fallbacks
+            .iter()
+            .map(|server| MinecraftServer::from_config(server, ping))
+            .collect();
+
+        let mut overrides: Vec<(IpNet, String)> = config
+            .overrides
+            .into_iter()
+            .map(|(range, region)| (range, region.to_ascii_uppercase()))
+            .collect();
+        overrides.sort_by_key(|(range, _)| std::cmp::Reverse(range.prefix_len()));
 
         Ok(GeoServerFinder {
             regions,
-            fallback,
+            fallbacks,
+            local_region: config
+                .local_region
+                .map(|region| region.to_ascii_uppercase()),
+            overrides,
+            adjacent_regions: config
+                .adjacent_regions
+                .into_iter()
+                .map(|(code, adjacent)| {
+                    let adjacent = adjacent
+                        .into_iter()
+                        .map(|(region, weight)| (region.to_ascii_uppercase(), weight))
+                        .collect();
+                    (code.to_ascii_uppercase(), adjacent)
+                })
+                .collect(),
+            version_names: config
+                .version_names
+                .into_iter()
+                .map(|(key, name)| (key.to_ascii_uppercase(), name))
+                .collect(),
+            motds: config
+                .motds
+                .into_iter()
+                .map(|(key, motd)| (key.to_ascii_uppercase(), motd))
+                .collect(),
+            favicons: config
+                .favicons
+                .into_iter()
+                .filter_map(|(key, path)| Some((key.to_ascii_uppercase(), favicon_or_warn(&path)?)))
+                .collect(),
+            regional_player_count: config.regional_player_count,
             client,
-            geo_cache,
+            geo,
+            poll_concurrency: ping.poll_concurrency,
+            poll_backoff: Arc::default(),
         })
     }
+
+    // The region a client at `ip`, which `ip_info` locates, would be sent
+    // to, without pinging anything. None when it would go to a fallback or
+    // the client couldn't be located.
+    fn region_key(&self, ip: IpAddr, ip_info: Option<&IpInfo>) -> Option<String> {
+        if let Some(region) = self.override_region(ip) {
+            return self.live_region(region);
+        }
+        if is_local_address(ip) {
+            return self
+                .local_region
+                .as_deref()
+                .and_then(|region| self.live_region(region));
+        }
+        select_region(&self.regions, &self.adjacent_regions, ip_info?)
+    }
+
+    // The region an override pins `ip` to, skipping the lookup.
+    fn override_region(&self, ip: IpAddr) -> Option<&str> {
+        let ip = ip.to_canonical();
+        self.overrides
+            .iter()
+            .find(|(range, _)| range.contains(&ip))
+            .map(|(_, region)| region.as_str())
+    }
+
+    // `region`, if it has a server that isn't draining.
+    fn live_region(&self, region: &str) -> Option<String> {
+        self.regions
+            .get(region)
+            .filter(|group| !group.selectable().is_empty())
+            .map(|_| region.to_string())
+    }
 }
 
 #[async_trait]
 impl ServerFinder for GeoServerFinder {
-    async fn get_player_count(&self) -> u32 {
-        let mut all_servers: Vec<MinecraftServer> = self.regions.values().cloned().collect();
-        all_servers.push(self.fallback.clone());
+    async fn get_player_count(&self) -> Option<u32> {
+        poll_backends(
+            &self.backends(),
+            self.poll_concurrency,
+            Some(&self.poll_backoff),
+        )
+        .await
+    }
+
+    fn backends(&self) -> Vec<MinecraftServer> {
+        let mut all_servers: Vec<MinecraftServer> = self
+            .regions
+            .values()
+            .flat_map(|group| group.backends())
+            .collect();
+        all_servers.extend(self.fallbacks.iter().cloned());
+        all_servers
+    }
+
+    fn poll_backoff(&self) -> Option<Arc<PollBackoff>> {
+        Some(self.poll_backoff.clone())
+    }
+
+    fn backends_mut(&mut self) -> Vec<&mut MinecraftServer> {
+        let mut all_servers: Vec<&mut MinecraftServer> = self
+            .regions
+            .values_mut()
+            .flat_map(|group| group.servers.iter_mut())
+            .collect();
+        all_servers.extend(self.fallbacks.iter_mut());
+        all_servers
+    }
+
+    // Only clients that would be located anyway, and only when some part of
+    // the server list differs by region.
+    fn status_geo(&self, ip: IpAddr) -> Option<Arc<dyn GeoProvider>> {
+        let regional = self.regional_player_count
+            || !self.version_names.is_empty()
+            || !self.motds.is_empty()
+            || !self.favicons.is_empty();
+        if !regional || is_local_address(ip) || self.override_region(ip).is_some() {
+            return None;
+        }
+        Some(self.geo.clone())
+    }
+
+    // Clients see the name, motd and icon of the region they would be sent
+    // to. Those headed for a fallback, or that couldn't be located, see the
+    // global ones. With `regional_player_count` they see the count of the
+    // first server of their region that accepts players, or of the first
+    // such fallback, which is the one they most likely end up on.
+    fn status_overrides(&self, ip: IpAddr, ip_info: Option<&IpInfo>) -> StatusOverrides {
+        let region = self.region_key(ip, ip_info);
+        let regional = |values: &HashMap<String, String>| values.get(region.as_ref()?).cloned();
+        let backend = if !self.regional_player_count {
+            None
+        } else if let Some(region) = &region {
+            self.regions[region].selectable().into_iter().next()
+        } else {
+            self.fallbacks
+                .iter()
+                .find(|server| server.accepts_players())
+                .cloned()
+        };
+        StatusOverrides {
+            version_name: regional(&self.version_names),
+            motd: regional(&self.motds),
+            favicon: regional(&self.favicons),
+            backend,
+        }
+    }
+
+    fn close(&self) {
+        self.geo.close();
+    }
+
+    async fn find_server(
+        &mut self,
+        connection: &Connection,
+    ) -> Result<MinecraftServer, FinderError> {
+        let ip = connection.addr.ip();
+        let mut coordinates = None;
+        let region = if is_local_address(ip) || self.override_region(ip).is_some() {
+            self.region_key(ip, None)
+        } else {
+            // Past the lookup rate limit, or when the provider rejects the
+            // token, players go to the fallbacks rather than waiting or
+            // being turned away.
+            match self.geo.lookup(ip).await {
+                Ok(ip_info) => {
+                    coordinates = ip_info.coordinates;
+                    select_region(&self.regions, &self.adjacent_regions, &ip_info)
+                }
+                Err(error) => match error.downcast_ref::<GeoLookupError>() {
+                    Some(GeoLookupError::Saturated) => {
+                        info!(
+                            "Geo lookups are rate limited, sending {} to the fallbacks",
+                            ip
+                        );
+                        None
+                    }
+                    Some(GeoLookupError::Unauthorized(_) | GeoLookupError::Disabled) => {
+                        info!("{}, sending {} to the fallbacks", error, ip);
+                        None
+                    }
+                    _ => return Err(FinderError::GeoLookup(error.to_string())),
+                },
+            }
+        };
+
+        if let Some(region) = region {
+            let group = self.regions.get_mut(&region).unwrap();
+            // Located clients go to the nearest server with coordinates,
+            // others to the region's algorithm.
+            if let Some(server) =
+                coordinates.and_then(|client| group.nearest(client, &connection.unreachable))
+            {
+                return Ok(server);
+            }
+            match group.find_server(connection).await {
+                Ok(server) => return Ok(server),
+                Err(error) => info!(
+                    "No backend in region {} ({}), using the fallbacks",
+                    region, error
+                ),
+            }
+        }
+        first_live_fallback(&self.fallbacks, &connection.unreachable)
+    }
+}
+
+// Addresses a geolocation API can say nothing useful about.
+fn is_local_address(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xC0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+    }
+}
+
+// The key of the most specific region with a server that isn't draining,
+// falling back to a region adjacent to the client's country or continent
+// before the wildcard.
+fn select_region(
+    regions: &HashMap<String, StaticServerFiner>,
+    adjacent_regions: &HashMap<String, Vec<(String, u32)>>,
+    ip_info: &IpInfo,
+) -> Option<String> {
+    let is_live = |key: &str| {
+        regions
+            .get(key)
+            .is_some_and(|group| !group.selectable().is_empty())
+    };
+    let codes = [
+        ip_info.country_code.as_str(),
+        ip_info.continent_code.as_str(),
+    ];
+    if let Some(key) = codes.into_iter().find(|key| is_live(key)) {
+        return Some(key.to_string());
+    }
+    let adjacent = codes.into_iter().find_map(|code| {
+        let live: Vec<&(String, u32)> = adjacent_regions
+            .get(code)?
+            .iter()
+            .filter(|(region, _)| is_live(region))
+            .collect();
+        pick_weighted(&live)
+    });
+    if adjacent.is_some() {
+        return adjacent;
+    }
+    is_live("*").then(|| "*".to_string())
+}
+
+fn pick_weighted(candidates: &[&(String, u32)]) -> Option<String> {
+    let total: u32 = candidates.iter().map(|(_, weight)| weight).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut pick = rand::thread_rng().gen_range(0..total);
+    for (region, weight) in candidates {
+        if pick < *weight {
+            return Some(region.clone());
+        }
+        pick -= weight;
+    }
+    None
+}
+
+// Returns the first fallback that isn't draining, at capacity or in
+// `unreachable`, and didn't fail its last player count poll. Nothing is pinged
+// here, so the finder isn't held up. When every such fallback failed, the last
+// one is used anyway, so a single fallback behaves as it always has.
+fn first_live_fallback(
+    fallbacks: &[MinecraftServer],
+    unreachable: &[String],
+) -> Result<MinecraftServer, FinderError> {
+    let candidates: Vec<&MinecraftServer> = fallbacks
+        .iter()
+        .filter(|server| server.accepts_players() && !unreachable.contains(&server.address))
+        .collect();
+    let Some(last) = candidates.last() else {
+        return Err(none_accepting(fallbacks));
+    };
+    let live = candidates.iter().find(|server| !server.is_down());
+    Ok((*live.unwrap_or(last)).clone())
+}
+
+/// Asks an HTTP endpoint where each player goes. It answers with one server,
+/// `{ "address", "port" }`, or with several to pick between by weight,
+/// `{ "servers": [{ "address", "port", "weight" }] }`. Players go to the
+/// fallbacks when the request fails or names no server that takes players.
+struct HttpServerFinder {
+    endpoint: String,
+    method: HttpMethod,
+    headers: HashMap<String, String>,
+    client: Client,
+    fallbacks: Vec<MinecraftServer>,
+    // The servers the endpoint named most recently, by address, so the
+    // players routed to them are counted across responses. Capped, so an
+    // endpoint naming ever new addresses doesn't grow the polls without end.
+    routed: LruCache<String, MinecraftServer>,
+    ping: PingOptions,
+    poll_concurrency: usize,
+    poll_backoff: Arc<PollBackoff>,
+}
 
-        let result: Vec<u32> = stream::iter(all_servers)
-            .map(async |x| x.get_player_count().await.unwrap_or(0))
-            .buffer_unordered(8)
+/// What `HttpServerFinder` tells the endpoint about the player. Sent as the
+/// query string of a GET, or as the JSON body of a POST.
+#[derive(Debug, Serialize)]
+struct HttpRouteRequest<'a> {
+    ip: String,
+    hostname: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locale: Option<&'a str>,
+}
+
+/// The endpoint's answer. Several servers are told apart from one by the
+/// `servers` key.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum HttpRoute {
+    Many { servers: Vec<RoutedServer> },
+    One(RoutedServer),
+}
+
+#[derive(Debug, Deserialize)]
+struct RoutedServer {
+    address: String,
+    // Added to `address` when that names no port.
+    #[serde(default)]
+    port: Option<u16>,
+    // Only matters between several servers.
+    #[serde(default = "default_route_weight")]
+    weight: u32,
+}
+
+fn default_route_weight() -> u32 {
+    1
+}
+
+impl HttpRoute {
+    // The addresses named, with their ports, and their weights.
+    fn servers(self) -> Vec<(String, u32)> {
+        let servers = match self {
+            HttpRoute::Many { servers } => servers,
+            HttpRoute::One(server) => vec![server],
+        };
+        servers
+            .into_iter()
+            .map(|server| {
+                let address = match server.port {
+                    Some(port) => with_port(&server.address, port),
+                    None => server.address,
+                };
+                (address, server.weight)
+            })
             .collect()
-            .await;
+    }
+}
+
+impl HttpServerFinder {
+    pub fn new(config: HttpConfig, ping: PingOptions) -> Self {
+        HttpServerFinder {
+            endpoint: config.endpoint,
+            method: config.request_method,
+            headers: config.headers,
+            client: Client::new(),
+            fallbacks: config
+                .fallbacks
+                .iter()
+                .map(|server| MinecraftServer::from_config(server, ping))
+                .collect(),
+            routed: LruCache::new(MAX_ROUTED_SERVERS),
+            ping,
+            poll_concurrency: ping.poll_concurrency,
+            poll_backoff: Arc::default(),
+        }
+    }
+
+    async fn request_route(&self, connection: &Connection) -> Result<HttpRoute, Box<dyn Error>> {
+        let request = HttpRouteRequest {
+            ip: connection.addr.ip().to_string(),
+            hostname: &connection.hostname,
+            username: connection.username.as_deref(),
+            locale: connection.locale.as_deref(),
+        };
+        let mut builder = match self.method {
+            HttpMethod::GET => self.client.get(&self.endpoint).query(&request),
+            HttpMethod::POST => self.client.post(&self.endpoint).json(&request),
+        };
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+        let response = builder
+            .timeout(self.ping.timeout)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json().await?)
+    }
+
+    // The backend for a server the endpoint named, keeping its state from
+    // earlier responses. Making room forgets the server routed to least
+    // recently, along with its poll backoff and metrics.
+    fn routed_server(&mut self, address: String) -> MinecraftServer {
+        if let Some(server) = self.routed.get(&address) {
+            return server.clone();
+        }
+        let server = Server {
+            name: None,
+            address: address.clone(),
+            port: None,
+            ping_with_proxy_protocol: false,
+            count_source: BackendCountSource::default(),
+            query_port: None,
+            max_players: None,
+            max_connections: None,
+            forced_host: None,
+            tier: 0,
+            coordinates: None,
+        };
+        let server = MinecraftServer::from_config(&server, self.ping);
+        if let Some((evicted, _)) = self.routed.push(address, server.clone()) {
+            debug!(
+                "Forgetting {}, which {} named least recently",
+                evicted, self.endpoint
+            );
+            self.poll_backoff.forget(&evicted);
+            metrics().forget_backend(&evicted);
+        }
+        server
+    }
+}
+
+#[async_trait]
+impl ServerFinder for HttpServerFinder {
+    async fn get_player_count(&self) -> Option<u32> {
+        poll_backends(
+            &self.backends(),
+            self.poll_concurrency,
+            Some(&self.poll_backoff),
+        )
+        .await
+    }
+
+    fn backends(&self) -> Vec<MinecraftServer> {
+        let mut all_servers: Vec<MinecraftServer> = self
+            .routed
+            .iter()
+            .map(|(_, server)| server.clone())
+            .collect();
+        all_servers.extend(self.fallbacks.iter().cloned());
+        all_servers
+    }
+
+    fn poll_backoff(&self) -> Option<Arc<PollBackoff>> {
+        Some(self.poll_backoff.clone())
+    }
 
-        result.iter().sum()
+    fn backends_mut(&mut self) -> Vec<&mut MinecraftServer> {
+        let mut all_servers: Vec<&mut MinecraftServer> =
+            self.routed.iter_mut().map(|(_, server)| server).collect();
+        all_servers.extend(self.fallbacks.iter_mut());
+        all_servers
     }
 
     async fn find_server(
         &mut self,
         connection: &Connection,
-    ) -> Result<MinecraftServer, Box<dyn Error>> {
-        let ip_info = self
-            .geo_cache
-            .get_geo_data(&connection.addr.to_string())
-            .await?;
-        if let Some(server) = self.regions.get(&ip_info.continent_code) {
-            return Ok(server.clone());
+    ) -> Result<MinecraftServer, FinderError> {
+        let route = match self.request_route(connection).await {
+            Ok(route) => route,
+            Err(error) => {
+                warn!(
+                    "Could not ask {} where to send {}, using the fallbacks: {}",
+                    self.endpoint, connection.addr, error
+                );
+                return first_live_fallback(&self.fallbacks, &connection.unreachable);
+            }
         };
-        if let Some(server) = self.regions.get(&ip_info.country_code) {
-            return Ok(server.clone());
+        // Servers the endpoint names for the first time are assumed to take
+        // players.
+        let usable: Vec<(String, u32)> = route
+            .servers()
+            .into_iter()
+            .filter(|(address, _)| {
+                !connection.unreachable.contains(address)
+                    && self
+                        .routed
+                        .peek(address)
+                        .is_none_or(MinecraftServer::accepts_players)
+            })
+            .collect();
+        let Some(address) = pick_weighted(&usable.iter().collect::<Vec<_>>()) else {
+            info!(
+                "{} named no server taking players for {}, using the fallbacks",
+                self.endpoint, connection.addr
+            );
+            return first_live_fallback(&self.fallbacks, &connection.unreachable);
+        };
+        Ok(self.routed_server(address))
+    }
+}
+
+/// Picks a backend from the hostname in the client's handshake. An exact
+/// route wins over wildcards, and a longer wildcard suffix wins over a shorter one.
+struct HostnameServerFinder {
+    routes: HashMap<String, MinecraftServer>,
+    fallback: Option<MinecraftServer>,
+    poll_concurrency: usize,
+    poll_backoff: Arc<PollBackoff>,
+}
+
+impl HostnameServerFinder {
+    pub fn new(config: HostnameConfig, ping: PingOptions) -> Self {
+        let routes = config
+            .routes
+            .into_iter()
+            .map(|(pattern, server)| {
+                let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+                (pattern, MinecraftServer::from_config(&server, ping))
+            })
+            .collect();
+
+        HostnameServerFinder {
+            routes,
+            fallback: config
+                .fallback
+                .map(|server| MinecraftServer::from_config(&server, ping)),
+            poll_concurrency: ping.poll_concurrency,
+            poll_backoff: Arc::default(),
+        }
+    }
+
+    // Routes failing `usable` are skipped, so their players fall through to
+    // the next match.
+    fn route(
+        &self,
+        hostname: &str,
+        usable: impl Fn(&MinecraftServer) -> bool,
+    ) -> Option<&MinecraftServer> {
+        let route = |pattern: &str| self.routes.get(pattern).filter(|server| usable(server));
+        if let Some(server) = route(hostname) {
+            return Some(server);
+        }
+        // Walk up the labels so "a.b.example.com" tries "*.b.example.com" first.
+        let mut rest = hostname;
+        while let Some((_, parent)) = rest.split_once('.') {
+            if let Some(server) = route(&format!("*.{}", parent)) {
+                return Some(server);
+            }
+            rest = parent;
         }
+        self.fallback.as_ref().filter(|server| usable(server))
+    }
+}
+
+#[async_trait]
+impl ServerFinder for HostnameServerFinder {
+    async fn get_player_count(&self) -> Option<u32> {
+        poll_backends(
+            &self.backends(),
+            self.poll_concurrency,
+            Some(&self.poll_backoff),
+        )
+        .await
+    }
+
+    fn backends(&self) -> Vec<MinecraftServer> {
+        let mut all_servers: Vec<MinecraftServer> = self.routes.values().cloned().collect();
+        all_servers.extend(self.fallback.clone());
+        all_servers
+    }
+
+    fn poll_backoff(&self) -> Option<Arc<PollBackoff>> {
+        Some(self.poll_backoff.clone())
+    }
+
+    fn backends_mut(&mut self) -> Vec<&mut MinecraftServer> {
+        let mut all_servers: Vec<&mut MinecraftServer> = self.routes.values_mut().collect();
+        all_servers.extend(self.fallback.as_mut());
+        all_servers
+    }
+
+    async fn find_server(
+        &mut self,
+        connection: &Connection,
+    ) -> Result<MinecraftServer, FinderError> {
+        let usable = |server: &MinecraftServer| {
+            server.accepts_players() && !connection.unreachable.contains(&server.address)
+        };
+        if let Some(server) = self.route(&connection.hostname, usable) {
+            return Ok(server.clone());
+        }
+        // Tell players apart whose routes are all full from those with none.
+        match self.route(&connection.hostname, |server| !server.is_draining()) {
+            Some(_) => Err(FinderError::Full),
+            None => Err(FinderError::NoRoute(connection.hostname.clone())),
+        }
+    }
+}
+
+/// Sends players whose client locale has a route to that backend, and
+/// everyone else to the mode's finder. A full locale like "pt_br" wins over
+/// its language "pt".
+struct LocaleServerFinder {
+    routes: HashMap<String, MinecraftServer>,
+    inner: Box<dyn ServerFinder>,
+    poll_concurrency: usize,
+    poll_backoff: Arc<PollBackoff>,
+}
+
+impl LocaleServerFinder {
+    pub fn new(
+        config: LocaleRoutingConfig,
+        inner: Box<dyn ServerFinder>,
+        ping: PingOptions,
+    ) -> Self {
+        let routes = config
+            .routes
+            .into_iter()
+            .map(|(locale, server)| {
+                let locale = locale.replace('-', "_").to_ascii_lowercase();
+                (locale, MinecraftServer::from_config(&server, ping))
+            })
+            .collect();
+
+        LocaleServerFinder {
+            routes,
+            inner,
+            poll_concurrency: ping.poll_concurrency,
+            poll_backoff: Arc::default(),
+        }
+    }
+
+    // Draining, full or unreachable routes are skipped, so their players
+    // fall through to the language route and then the mode's finder.
+    fn route(&self, locale: &str, unreachable: &[String]) -> Option<&MinecraftServer> {
+        let locale = locale.replace('-', "_").to_ascii_lowercase();
+        let language = locale.split('_').next().unwrap_or_default();
+        [locale.as_str(), language].into_iter().find_map(|key| {
+            self.routes
+                .get(key)
+                .filter(|server| server.accepts_players())
+                .filter(|server| !unreachable.contains(&server.address))
+        })
+    }
+}
+
+#[async_trait]
+impl ServerFinder for LocaleServerFinder {
+    // The inner finder polls and times its own backends.
+    async fn get_player_count(&self) -> Option<u32> {
+        let routes: Vec<MinecraftServer> = self.routes.values().cloned().collect();
+        let routed = poll_servers(&routes, self.poll_concurrency, Some(&self.poll_backoff)).await;
+        total_player_count(vec![routed, self.inner.get_player_count().await])
+    }
+
+    fn backends(&self) -> Vec<MinecraftServer> {
+        let mut all_servers: Vec<MinecraftServer> = self.routes.values().cloned().collect();
+        all_servers.extend(self.inner.backends());
+        all_servers
+    }
+
+    fn poll_backoff(&self) -> Option<Arc<PollBackoff>> {
+        Some(self.poll_backoff.clone())
+    }
+
+    fn backends_mut(&mut self) -> Vec<&mut MinecraftServer> {
+        let mut all_servers: Vec<&mut MinecraftServer> = self.routes.values_mut().collect();
+        all_servers.extend(self.inner.backends_mut());
+        all_servers
+    }
+
+    async fn find_server(
+        &mut self,
+        connection: &Connection,
+    ) -> Result<MinecraftServer, FinderError> {
+        if let Some(server) = connection
+            .locale
+            .as_deref()
+            .and_then(|locale| self.route(locale, &connection.unreachable))
+        {
+            return Ok(server.clone());
+        }
+        self.inner.find_server(connection).await
+    }
+
+    fn status_geo(&self, ip: IpAddr) -> Option<Arc<dyn GeoProvider>> {
+        self.inner.status_geo(ip)
+    }
+
+    fn status_overrides(&self, ip: IpAddr, ip_info: Option<&IpInfo>) -> StatusOverrides {
+        self.inner.status_overrides(ip, ip_info)
+    }
+
+    fn record_transfer(&mut self, connection: &Connection, server: &MinecraftServer) {
+        self.inner.record_transfer(connection, server)
+    }
+
+    fn save_state(&self) -> Result<(), Box<dyn Error>> {
+        self.inner.save_state()
+    }
+
+    fn close(&self) {
+        self.inner.close()
+    }
+}
+
+/// Sends players who reconnect within `ttl` of a transfer back to the same
+/// backend, so one whose game crashed picks up where they left off. Anyone
+/// else, or anyone whose backend is now down, draining or full, is left to
+/// the inner finder. Players are told apart by username, case-insensitively.
+struct AffinityServerFinder {
+    inner: Box<dyn ServerFinder>,
+    ttl: Duration,
+    // Lowercase usernames to the backend they were last transferred to, and when.
+    recent: HashMap<String, (MinecraftServer, Instant)>,
+}
+
+impl AffinityServerFinder {
+    pub fn new(config: AffinityConfig, inner: Box<dyn ServerFinder>) -> Self {
+        AffinityServerFinder {
+            inner,
+            ttl: config.ttl(),
+            recent: HashMap::new(),
+        }
+    }
+
+    fn forget_expired(&mut self) {
+        let ttl = self.ttl;
+        self.recent
+            .retain(|_, (_, transferred_at)| transferred_at.elapsed() < ttl);
+    }
+
+    // The backend `connection`'s player was last sent to, while it can still
+    // take them and isn't down.
+    fn remembered(&mut self, connection: &Connection) -> Option<MinecraftServer> {
+        let username = connection.username.as_ref()?.to_ascii_lowercase();
+        self.forget_expired();
+        let (server, _) = self.recent.get(&username)?;
+        if !server.accepts_players()
+            || server.is_down()
+            || connection.unreachable.contains(&server.address)
+        {
+            debug!(
+                "{} was last sent to {}, which can't take them now",
+                username, server.address
+            );
+            return None;
+        }
+        Some(server.clone())
+    }
+}
+
+#[async_trait]
+impl ServerFinder for AffinityServerFinder {
+    async fn get_player_count(&self) -> Option<u32> {
+        self.inner.get_player_count().await
+    }
+
+    fn backends(&self) -> Vec<MinecraftServer> {
+        self.inner.backends()
+    }
+
+    fn poll_backoff(&self) -> Option<Arc<PollBackoff>> {
+        self.inner.poll_backoff()
+    }
+
+    fn backends_mut(&mut self) -> Vec<&mut MinecraftServer> {
+        self.inner.backends_mut()
+    }
+
+    async fn find_server(
+        &mut self,
+        connection: &Connection,
+    ) -> Result<MinecraftServer, FinderError> {
+        if let Some(server) = self.remembered(connection) {
+            return Ok(server);
+        }
+        self.inner.find_server(connection).await
+    }
+
+    fn status_geo(&self, ip: IpAddr) -> Option<Arc<dyn GeoProvider>> {
+        self.inner.status_geo(ip)
+    }
+
+    fn status_overrides(&self, ip: IpAddr, ip_info: Option<&IpInfo>) -> StatusOverrides {
+        self.inner.status_overrides(ip, ip_info)
+    }
+
+    // Players sent to the unrouteable server are not remembered, so they get
+    // a real backend as soon as one has room.
+    fn record_transfer(&mut self, connection: &Connection, server: &MinecraftServer) {
+        self.inner.record_transfer(connection, server);
+        let Some(username) = &connection.username else {
+            return;
+        };
+        if !self
+            .inner
+            .backends()
+            .iter()
+            .any(|backend| backend.address == server.address)
+        {
+            return;
+        }
+        self.forget_expired();
+        self.recent.insert(
+            username.to_ascii_lowercase(),
+            (server.clone(), Instant::now()),
+        );
+    }
+
+    fn save_state(&self) -> Result<(), Box<dyn Error>> {
+        self.inner.save_state()
+    }
+
+    fn close(&self) {
+        self.inner.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::status_overrides;
+    use crate::test_support::{
+        NoServerFinder, PingTracker, connection_pair, spawn_delayed_player_count_backend,
+        spawn_flaky_player_count_backend, spawn_player_count_backend,
+        spawn_tracked_player_count_backend, test_config,
+    };
+    use std::net::SocketAddr;
+    use tokio::sync::Mutex as TokioMutex;
+
+    fn static_config(algorithm: &str, addresses: &[SocketAddr]) -> StaticConfig {
+        let servers: Vec<String> = addresses
+            .iter()
+            .map(|address| format!("{{ address: \"{}\" }}", address))
+            .collect();
+        let yaml = format!(
+            "algorithm: {}\nservers: [{}]",
+            algorithm,
+            servers.join(", ")
+        );
+        serde_yaml::from_str(&yaml).unwrap()
+    }
+
+    #[test]
+    fn test_failing_backend_is_polled_less_often_until_it_recovers() {
+        let backoff = PollBackoff::default();
+        // The backend fails its first four polls, then answers again.
+        let mut failures_left = 4;
+        let mut polled_at = Vec::new();
+        for cycle in 0..16 {
+            if !backoff.should_poll("down.backend") {
+                continue;
+            }
+            polled_at.push(cycle);
+            backoff.record("down.backend", failures_left == 0);
+            failures_left = failures_left.saturating_sub(1);
+        }
+
+        let gaps: Vec<u32> = polled_at.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        assert_eq!(gaps, [1, 2, 4, 4, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_poll_backoff_is_capped() {
+        let backoff = PollBackoff::default();
+        for _ in 0..40 {
+            backoff.record("down.backend", false);
+        }
+
+        let skipped = (0..100)
+            .take_while(|_| !backoff.should_poll("down.backend"))
+            .count();
+        assert_eq!(skipped, 3);
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_resumes_from_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("rotation.json");
+        let addresses: Vec<SocketAddr> = (1..=4)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        let mut config = static_config("round_robin", &addresses);
+        config.state_file = Some(state_file.to_string_lossy().into_owned());
+
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let mut finder = StaticServerFiner::new(config.clone(), PingOptions::default());
+        for _ in 0..2 {
+            finder.find_server(&connection).await.unwrap();
+        }
+        finder.save_state().unwrap();
+
+        let mut restarted = StaticServerFiner::new(config, PingOptions::default());
+        let server = restarted.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, addresses[3].to_string());
+    }
+
+    #[tokio::test]
+    async fn test_lowest_player_count_picks_emptiest_server() {
+        let busy = spawn_player_count_backend(50).await;
+        let quiet = spawn_player_count_backend(3).await;
+        let down = closed_port();
+
+        let mut finder = StaticServerFiner::new(
+            static_config("lowest_player_count", &[busy, down, quiet]),
+            PingOptions::default(),
+        );
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, quiet.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_polls_ping_at_most_poll_concurrency_backends_at_once() {
+        let tracker = Arc::new(PingTracker::default());
+        let mut addresses = Vec::new();
+        for _ in 0..10 {
+            let delay = Duration::from_millis(50);
+            addresses.push(spawn_tracked_player_count_backend(1, delay, tracker.clone()).await);
+        }
+        let ping = PingOptions {
+            poll_concurrency: 3,
+            ..PingOptions::default()
+        };
+
+        let finder = StaticServerFiner::new(static_config("round_robin", &addresses), ping);
+        assert_eq!(finder.get_player_count().await, Some(10));
+        assert_eq!(tracker.peak(), 3);
+
+        let mut finder =
+            StaticServerFiner::new(static_config("lowest_player_count", &addresses), ping);
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        finder.find_server(&connection).await.unwrap();
+        assert_eq!(tracker.peak(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_higher_tier_is_used_while_lower_one_is_full_or_down() {
+        let full = spawn_player_count_backend(10).await;
+        let down = closed_port();
+        let overflow = spawn_player_count_backend(0).await;
+        let yaml = format!(
+            r#"
+algorithm: round_robin
+servers:
+  - address: "{}"
+    max_players: 10
+  - address: "{}"
+  - address: "{}"
+    tier: 1
+"#,
+            full, down, overflow
+        );
+        let mut finder =
+            StaticServerFiner::new(serde_yaml::from_str(&yaml).unwrap(), PingOptions::default());
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        // Nothing is known before the first poll, so tier 0 is used.
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.tier, 0);
+
+        finder.get_player_count().await;
+        for _ in 0..3 {
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_eq!(server.address, overflow.to_string());
+        }
+    }
+
+    fn closed_port() -> SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_finder_errors() {
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        for algorithm in ["lowest_player_count", "lowest_load_ratio", "lowest_latency"] {
+            let mut finder = StaticServerFiner::new(
+                static_config(algorithm, &[closed_port(), closed_port()]),
+                PingOptions::default(),
+            );
+            let result = finder.find_server(&connection).await;
+            assert!(
+                matches!(result, Err(FinderError::AllBackendsDown)),
+                "{}: {:?}",
+                algorithm,
+                result
+            );
+
+            let mut finder =
+                StaticServerFiner::new(static_config(algorithm, &[]), PingOptions::default());
+            let result = finder.find_server(&connection).await;
+            assert!(
+                matches!(result, Err(FinderError::NoServersAvailable)),
+                "{}: {:?}",
+                algorithm,
+                result
+            );
+        }
+
+        let mut finder = hostname_finder(false);
+        let mut connection = connection;
+        connection.hostname = "play.example.org".to_string();
+        let result = finder.find_server(&connection).await;
+        assert!(matches!(result, Err(FinderError::NoRoute(host)) if host == "play.example.org"));
+    }
+
+    #[tokio::test]
+    async fn test_round_robin_skips_draining_servers() {
+        let addresses: Vec<SocketAddr> = (1..=3)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        let mut finder = StaticServerFiner::new(
+            static_config("round_robin", &addresses),
+            PingOptions::default(),
+        );
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        finder.servers[1].set_draining(true);
+        for _ in 0..6 {
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_ne!(server.address, addresses[1].to_string());
+        }
+
+        finder.servers[0].set_draining(true);
+        finder.servers[2].set_draining(true);
+        let result = finder.find_server(&connection).await;
+        assert!(matches!(result, Err(FinderError::NoServersAvailable)));
+
+        finder.servers[1].set_draining(false);
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, addresses[1].to_string());
+    }
+
+    #[tokio::test]
+    async fn test_lowest_player_count_skips_draining_servers() {
+        let empty = spawn_player_count_backend(0).await;
+        let busy = spawn_player_count_backend(40).await;
+
+        let mut finder = StaticServerFiner::new(
+            static_config("lowest_player_count", &[empty, busy]),
+            PingOptions::default(),
+        );
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        finder.servers[0].set_draining(true);
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, busy.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_lowest_load_ratio_picks_least_loaded_server() {
+        let small = spawn_player_count_backend(2).await;
+        let large = spawn_player_count_backend(5).await;
+
+        let yaml = format!(
+            "algorithm: lowest_load_ratio\nservers:\n  - address: \"{}\"\n    max_players: 10\n  - address: \"{}\"\n    max_players: 100",
+            small, large
+        );
+        let mut finder =
+            StaticServerFiner::new(serde_yaml::from_str(&yaml).unwrap(), PingOptions::default());
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, large.to_string());
+    }
+
+    fn ip_info(country_code: &str, continent_code: &str) -> IpInfo {
+        IpInfo {
+            ip: "192.0.2.1".to_string(),
+            asn: String::new(),
+            as_name: String::new(),
+            as_domain: String::new(),
+            country_code: country_code.to_string(),
+            country: String::new(),
+            continent_code: continent_code.to_string(),
+            continent: String::new(),
+            coordinates: None,
+        }
+    }
+
+    fn regions(keys: &[&str]) -> HashMap<String, StaticServerFiner> {
+        keys.iter()
+            .map(|key| {
+                let yaml = format!(
+                    "algorithm: round_robin\nservers:\n  - address: \"{}.backend\"",
+                    key
+                );
+                let group = StaticServerFiner::new(
+                    serde_yaml::from_str(&yaml).unwrap(),
+                    PingOptions::default(),
+                );
+                (key.to_string(), group)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_geo_country_overrides_continent() {
+        let regions = regions(&["EU", "GB"]);
+
+        let gb = select_region(&regions, &HashMap::new(), &ip_info("GB", "EU")).unwrap();
+        assert_eq!(gb, "GB");
+        let fr = select_region(&regions, &HashMap::new(), &ip_info("FR", "EU")).unwrap();
+        assert_eq!(fr, "EU");
+    }
+
+    #[test]
+    fn test_geo_skips_draining_regions() {
+        let regions = regions(&["EU", "GB"]);
+
+        regions["GB"].servers[0].set_draining(true);
+        let gb = select_region(&regions, &HashMap::new(), &ip_info("GB", "EU")).unwrap();
+        assert_eq!(gb, "EU");
+    }
+
+    #[test]
+    fn test_geo_wildcard_precedes_fallback() {
+        let with_wildcard = regions(&["EU", "*"]);
+        let us = select_region(&with_wildcard, &HashMap::new(), &ip_info("US", "NA")).unwrap();
+        assert_eq!(us, "*");
+
+        let without_wildcard = regions(&["EU"]);
+        assert!(select_region(&without_wildcard, &HashMap::new(), &ip_info("US", "NA")).is_none());
+    }
+
+    #[test]
+    fn test_geo_adjacent_region_precedes_fallback() {
+        let without_sa = regions(&["NA", "EU"]);
+        let adjacent = HashMap::from([("SA".to_string(), vec![("NA".to_string(), 1)])]);
+
+        let br = select_region(&without_sa, &adjacent, &ip_info("BR", "SA")).unwrap();
+        assert_eq!(br, "NA");
+        assert!(select_region(&without_sa, &HashMap::new(), &ip_info("BR", "SA")).is_none());
+
+        // Adjacency is more specific than the wildcard.
+        let with_wildcard = regions(&["NA", "*"]);
+        let br = select_region(&with_wildcard, &adjacent, &ip_info("BR", "SA")).unwrap();
+        assert_eq!(br, "NA");
+
+        without_sa["NA"].servers[0].set_draining(true);
+        assert!(select_region(&without_sa, &adjacent, &ip_info("BR", "SA")).is_none());
+    }
+
+    #[test]
+    fn test_geo_adjacent_regions_are_picked_by_weight() {
+        let regions = regions(&["NA", "EU"]);
+        let adjacent = HashMap::from([(
+            "SA".to_string(),
+            vec![("NA".to_string(), 3), ("EU".to_string(), 1)],
+        )]);
+
+        let picks: Vec<String> = (0..200)
+            .map(|_| select_region(&regions, &adjacent, &ip_info("BR", "SA")).unwrap())
+            .collect();
+        let na = picks.iter().filter(|key| *key == "NA").count();
+        assert!(na > 100 && na < 200, "{} of 200 picks went to NA", na);
+
+        regions["NA"].servers[0].set_draining(true);
+        let br = select_region(&regions, &adjacent, &ip_info("BR", "SA")).unwrap();
+        assert_eq!(br, "EU");
+    }
+
+    struct UnreachableGeoProvider;
+
+    #[async_trait]
+    impl GeoProvider for UnreachableGeoProvider {
+        async fn lookup(&self, ip: IpAddr) -> Result<IpInfo, Box<dyn Error>> {
+            panic!("looked up local address {}", ip);
+        }
+    }
+
+    fn local_geo_finder(local_region: Option<&str>) -> GeoServerFinder {
+        GeoServerFinder {
+            regions: regions(&["EU", "NA"]),
+            fallbacks: vec![MinecraftServer::new("fallback.backend".to_string())],
+            local_region: local_region.map(str::to_string),
+            overrides: Vec::new(),
+            adjacent_regions: HashMap::new(),
+            version_names: HashMap::new(),
+            motds: HashMap::new(),
+            favicons: HashMap::new(),
+            regional_player_count: false,
+            geo: Arc::new(UnreachableGeoProvider),
+            client: Client::new(),
+            poll_concurrency: PingOptions::default().poll_concurrency,
+            poll_backoff: Arc::default(),
+        }
+    }
+
+    struct SaturatedGeoProvider;
+
+    #[async_trait]
+    impl GeoProvider for SaturatedGeoProvider {
+        async fn lookup(&self, _ip: IpAddr) -> Result<IpInfo, Box<dyn Error>> {
+            Err(GeoLookupError::Saturated.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geo_rate_limited_lookups_use_fallback() {
+        let mut finder = local_geo_finder(Some("EU"));
+        finder.geo = Arc::new(SaturatedGeoProvider);
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        connection.addr = "1.1.1.1:50000".parse().unwrap();
+
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, "fallback.backend");
+    }
+
+    struct FailingGeoProvider(GeoLookupError);
+
+    #[async_trait]
+    impl GeoProvider for FailingGeoProvider {
+        async fn lookup(&self, _ip: IpAddr) -> Result<IpInfo, Box<dyn Error>> {
+            Err(self.0.clone().into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geo_rejected_token_uses_fallback() {
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        connection.addr = "1.1.1.1:50000".parse().unwrap();
+
+        // Whether the token was just rejected or lookups were disabled over it.
+        for error in [
+            GeoLookupError::Unauthorized("401 Unauthorized".to_string()),
+            GeoLookupError::Disabled,
+        ] {
+            let mut finder = local_geo_finder(Some("EU"));
+            finder.geo = Arc::new(FailingGeoProvider(error));
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_eq!(server.address, "fallback.backend");
+        }
+
+        let mut finder = local_geo_finder(Some("EU"));
+        finder.geo = Arc::new(FailingGeoProvider(GeoLookupError::Failed(
+            "timed out".to_string(),
+        )));
+        let result = finder.find_server(&connection).await;
+        assert!(matches!(result, Err(FinderError::GeoLookup(_))));
+    }
+
+    const NEW_YORK: Coordinates = Coordinates {
+        latitude: 40.71,
+        longitude: -74.01,
+    };
+    const LOS_ANGELES: Coordinates = Coordinates {
+        latitude: 34.05,
+        longitude: -118.24,
+    };
+
+    // A US region with a server on each coast.
+    fn coastal_region() -> StaticServerFiner {
+        let mut group = StaticServerFiner::new(
+            serde_yaml::from_str(
+                "algorithm: round_robin\nservers:\n  - address: \"east.backend\"\n  - address: \"west.backend\"",
+            )
+            .unwrap(),
+            PingOptions::default(),
+        );
+        group.servers[0].coordinates = Some(NEW_YORK);
+        group.servers[1].coordinates = Some(LOS_ANGELES);
+        group
+    }
+
+    #[test]
+    fn test_distance_between_coordinates() {
+        let london = Coordinates {
+            latitude: 51.51,
+            longitude: -0.13,
+        };
+        let paris = Coordinates {
+            latitude: 48.86,
+            longitude: 2.35,
+        };
+        assert!((distance_km(london, paris) - 343.0).abs() < 5.0);
+        assert!((distance_km(NEW_YORK, LOS_ANGELES) - 3936.0).abs() < 10.0);
+        assert_eq!(distance_km(paris, paris), 0.0);
+    }
+
+    #[test]
+    fn test_nearest_server_in_region() {
+        let group = coastal_region();
+        let chicago = Coordinates {
+            latitude: 41.88,
+            longitude: -87.63,
+        };
+        let seattle = Coordinates {
+            latitude: 47.61,
+            longitude: -122.33,
+        };
+        assert_eq!(group.nearest(chicago, &[]).unwrap().address, "east.backend");
+        assert_eq!(group.nearest(seattle, &[]).unwrap().address, "west.backend");
+
+        // Past an unreachable or draining server, the next nearest one is used.
+        let unreachable = ["west.backend".to_string()];
+        assert_eq!(
+            group.nearest(seattle, &unreachable).unwrap().address,
+            "east.backend"
+        );
+        group.servers[1].set_draining(true);
+        assert_eq!(group.nearest(seattle, &[]).unwrap().address, "east.backend");
+        group.servers[0].set_draining(true);
+        assert!(group.nearest(seattle, &[]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_nearest_server_is_used_again_once_it_recovers() {
+        let addresses = [
+            spawn_flaky_player_count_backend(0, 1).await,
+            spawn_player_count_backend(0).await,
+        ];
+        let ping = PingOptions {
+            retries: 0,
+            ..PingOptions::default()
+        };
+        let mut group = StaticServerFiner::new(static_config("round_robin", &addresses), ping);
+        group.servers[0].coordinates = Some(NEW_YORK);
+        group.servers[1].coordinates = Some(LOS_ANGELES);
+
+        assert!(group.servers[0].get_player_count().await.is_err());
+        let nearest = group.nearest(NEW_YORK, &[]).unwrap();
+        assert_eq!(nearest.address, addresses[1].to_string());
+
+        group.servers[0].get_player_count().await.unwrap();
+        let nearest = group.nearest(NEW_YORK, &[]).unwrap();
+        assert_eq!(nearest.address, addresses[0].to_string());
+    }
+
+    struct LocatingGeoProvider(Coordinates);
+
+    #[async_trait]
+    impl GeoProvider for LocatingGeoProvider {
+        async fn lookup(&self, _ip: IpAddr) -> Result<IpInfo, Box<dyn Error>> {
+            Ok(IpInfo {
+                coordinates: Some(self.0),
+                ..ip_info("US", "NA")
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geo_located_clients_go_to_the_nearest_server() {
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        connection.addr = "1.1.1.1:50000".parse().unwrap();
+
+        for (client, expected) in [(NEW_YORK, "east.backend"), (LOS_ANGELES, "west.backend")] {
+            let mut finder = local_geo_finder(None);
+            finder.regions.insert("NA".to_string(), coastal_region());
+            finder.geo = Arc::new(LocatingGeoProvider(client));
+            // The same server every time, unlike round robin.
+            for _ in 0..3 {
+                let server = finder.find_server(&connection).await.unwrap();
+                assert_eq!(server.address, expected);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geo_local_addresses_use_fallback() {
+        let mut finder = local_geo_finder(None);
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.10",
+            "::1",
+            "::ffff:10.0.0.1",
+        ] {
+            connection.addr = SocketAddr::new(ip.parse().unwrap(), 50000);
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_eq!(server.address, "fallback.backend", "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geo_local_addresses_use_local_region() {
+        let mut finder = local_geo_finder(Some("EU"));
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        connection.addr = "192.168.1.20:50000".parse().unwrap();
+
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, "EU.backend");
+    }
+
+    #[tokio::test]
+    async fn test_geo_overrides_pin_regions_without_a_lookup() {
+        let yaml = format!(
+            r#"
+provider: maxmind
+database: "{}/testdata/geo-country-test.mmdb"
+regions:
+  eu:
+    address: "eu.backend"
+  na:
+    address: "na.backend"
+fallback:
+  address: "fallback.backend"
+local_region: eu
+overrides:
+  "1.1.0.0/16": eu
+  "1.1.1.0/24": NA
+  "10.8.0.0/16": na
+  "2001:db8::/32": na
+"#,
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut finder =
+            GeoServerFinder::new(serde_yaml::from_str(&yaml).unwrap(), PingOptions::default())
+                .unwrap();
+        finder.geo = Arc::new(UnreachableGeoProvider);
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        for (ip, region) in [
+            // The /24 is more specific than the /16 around it.
+            ("1.1.1.1", "NA"),
+            ("::ffff:1.1.1.1", "NA"),
+            ("1.1.2.3", "EU"),
+            // Overrides also win over the local region.
+            ("10.8.0.5", "NA"),
+            ("10.9.0.5", "EU"),
+            ("2001:db8::1", "NA"),
+        ] {
+            connection.addr = SocketAddr::new(ip.parse().unwrap(), 50000);
+            let server = finder.find_server(&connection).await.unwrap();
+            let backend = format!("{}.backend", region.to_ascii_lowercase());
+            assert_eq!(server.address, backend, "{}", ip);
+            assert_eq!(
+                finder.region_key(connection.addr.ip(), None).as_deref(),
+                Some(region),
+                "{}",
+                ip
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geo_skips_unreachable_fallbacks() {
+        let live = spawn_player_count_backend(0).await;
+        let mut finder = local_geo_finder(None);
+        finder.fallbacks = [closed_port(), live, closed_port()]
+            .iter()
+            .map(|address| MinecraftServer::new(address.to_string()))
+            .collect();
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        // Until polled, every fallback is assumed to be up.
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, finder.fallbacks[0].address);
+
+        finder.get_player_count().await;
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, live.to_string());
+
+        // With nothing answering, the last fallback is still used.
+        finder.fallbacks[1].set_draining(true);
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, finder.fallbacks[2].address);
+    }
+
+    #[tokio::test]
+    async fn test_geo_region_keys_are_case_insensitive() {
+        let yaml = format!(
+            r#"
+provider: maxmind
+database: "{}/testdata/geo-country-test.mmdb"
+regions:
+  gb:
+    address: "gb.backend"
+  Na:
+    address: "na.backend"
+fallback:
+  address: "fallback.backend"
+local_region: na
+"#,
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut finder =
+            GeoServerFinder::new(serde_yaml::from_str(&yaml).unwrap(), PingOptions::default())
+                .unwrap();
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        for (ip, backend) in [
+            ("192.0.2.1", "gb.backend"),
+            ("198.51.100.7", "na.backend"),
+            ("10.0.0.1", "na.backend"),
+        ] {
+            connection.addr = SocketAddr::new(ip.parse().unwrap(), 50000);
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_eq!(server.address, backend, "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_geo_region_rotates_between_its_servers() {
+        let yaml = format!(
+            r#"
+provider: maxmind
+database: "{}/testdata/geo-country-test.mmdb"
+regions:
+  EU:
+    - address: "eu1.backend"
+    - address: "eu2.backend"
+  NA:
+    address: "na.backend"
+fallback:
+  address: "fallback.backend"
+"#,
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let mut finder =
+            GeoServerFinder::new(serde_yaml::from_str(&yaml).unwrap(), PingOptions::default())
+                .unwrap();
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        connection.addr = "192.0.2.1:50000".parse().unwrap();
+        let mut picked = Vec::new();
+        for _ in 0..4 {
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_eq!(server.region.as_deref(), Some("EU"));
+            picked.push(server.address);
+        }
+        assert_eq!(
+            picked,
+            ["eu2.backend", "eu1.backend", "eu2.backend", "eu1.backend"]
+        );
+
+        connection.addr = "198.51.100.7:50000".parse().unwrap();
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, "na.backend");
+    }
+
+    #[tokio::test]
+    async fn test_geo_version_name_follows_client_region() {
+        let yaml = format!(
+            r#"
+provider: maxmind
+database: "{}/testdata/geo-country-test.mmdb"
+regions:
+  EU:
+    address: "eu.backend"
+  NA:
+    address: "na.backend"
+fallback:
+  address: "fallback.backend"
+version_names:
+  eu: "Loadbalancer EU"
+  NA: "Loadbalancer NA"
+"#,
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let finder =
+            GeoServerFinder::new(serde_yaml::from_str(&yaml).unwrap(), PingOptions::default())
+                .unwrap();
+
+        let finder: TokioMutex<Box<dyn ServerFinder>> = TokioMutex::new(Box::new(finder));
+
+        let eu = status_overrides(&finder, "192.0.2.1".parse().unwrap()).await;
+        assert_eq!(eu.version_name.as_deref(), Some("Loadbalancer EU"));
+        let na = status_overrides(&finder, "198.51.100.7".parse().unwrap()).await;
+        assert_eq!(na.version_name.as_deref(), Some("Loadbalancer NA"));
+        // Local clients go to the fallback, so they see the global name.
+        let local = status_overrides(&finder, "10.0.0.1".parse().unwrap()).await;
+        assert_eq!(local.version_name, None);
+    }
+
+    #[test]
+    fn test_status_pings_are_only_located_for_regional_fields() {
+        let yaml = format!(
+            r#"
+provider: maxmind
+database: "{}/testdata/geo-country-test.mmdb"
+regions:
+  EU:
+    address: "eu.backend"
+fallback:
+  address: "fallback.backend"
+"#,
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let config: GeoConfig = serde_yaml::from_str(&yaml).unwrap();
+        let public: IpAddr = "192.0.2.1".parse().unwrap();
+        let finder = GeoServerFinder::new(config.clone(), PingOptions::default()).unwrap();
+        assert!(finder.status_geo(public).is_none());
+
+        let mut finder = GeoServerFinder::new(config, PingOptions::default()).unwrap();
+        finder.regional_player_count = true;
+        assert!(finder.status_geo(public).is_some());
+        // Local clients are never looked up.
+        assert!(finder.status_geo("10.0.0.1".parse().unwrap()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_geo_motd_follows_client_region() {
+        let yaml = format!(
+            r#"
+provider: maxmind
+database: "{}/testdata/geo-country-test.mmdb"
+regions:
+  EU:
+    address: "eu.backend"
+  NA:
+    address: "na.backend"
+fallback:
+  address: "fallback.backend"
+motds:
+  EU: "Welcome to the EU servers"
+"#,
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let finder =
+            GeoServerFinder::new(serde_yaml::from_str(&yaml).unwrap(), PingOptions::default())
+                .unwrap();
+
+        let finder: TokioMutex<Box<dyn ServerFinder>> = TokioMutex::new(Box::new(finder));
+
+        let eu = status_overrides(&finder, "192.0.2.1".parse().unwrap()).await;
+        assert_eq!(eu.motd.as_deref(), Some("Welcome to the EU servers"));
+        // Regions without their own motd keep the configured one.
+        let na = status_overrides(&finder, "198.51.100.7".parse().unwrap()).await;
+        assert_eq!(na.motd, None);
+    }
+
+    #[test]
+    fn test_public_addresses_are_not_local() {
+        for ip in ["1.1.1.1", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!is_local_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_http_route_names_one_or_several_servers() {
+        let one: HttpRoute =
+            serde_json::from_str(r#"{ "address": "mc.example.com", "port": 25566 }"#).unwrap();
+        assert_eq!(one.servers(), [("mc.example.com:25566".to_string(), 1)]);
+
+        let many: HttpRoute = serde_json::from_str(
+            r#"{ "servers": [{ "address": "a.example.com:25565", "weight": 3 }, { "address": "b.example.com" }] }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            many.servers(),
+            [
+                ("a.example.com:25565".to_string(), 3),
+                ("b.example.com".to_string(), 1)
+            ]
+        );
+
+        assert!(serde_json::from_str::<HttpRoute>(r#"{ "port": 25565 }"#).is_err());
+    }
+
+    // Answers every request with `body`, returning the endpoint's URL.
+    async fn spawn_route_endpoint(body: serde_json::Value) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let body = body.to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        format!("http://{}/route", address)
+    }
+
+    fn http_finder(endpoint: &str) -> HttpServerFinder {
+        let yaml = format!(
+            "endpoint: \"{}\"\nfallback:\n  address: \"fallback.backend\"",
+            endpoint
+        );
+        HttpServerFinder::new(serde_yaml::from_str(&yaml).unwrap(), PingOptions::default())
+    }
+
+    #[tokio::test]
+    async fn test_http_finder_picks_among_the_servers_it_is_given() {
+        let endpoint = spawn_route_endpoint(serde_json::json!({
+            "servers": [
+                { "address": "a.backend", "weight": 1 },
+                { "address": "b.backend", "port": 25566, "weight": 0 },
+            ]
+        }))
+        .await;
+        let mut finder = http_finder(&endpoint);
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        for _ in 0..5 {
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_eq!(server.address, "a.backend");
+            server.route_connection();
+        }
+        // The routed players are counted across responses.
+        let routed = finder.routed.peek("a.backend").unwrap();
+        assert_eq!(routed.active_connections(), 5);
+
+        connection.unreachable = vec!["a.backend".to_string()];
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, "fallback.backend");
+    }
+
+    #[tokio::test]
+    async fn test_http_finder_uses_the_fallback_when_the_endpoint_fails() {
+        // Nothing listens there once the listener is dropped.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/route", listener.local_addr().unwrap());
+        drop(listener);
+        let mut finder = http_finder(&endpoint);
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, "fallback.backend");
+        assert!(finder.routed.is_empty());
+    }
+
+    #[test]
+    fn test_http_finder_forgets_the_servers_named_least_recently() {
+        let mut finder = http_finder("http://127.0.0.1:1/route");
+        finder.routed = LruCache::new(NonZeroUsize::new(2).unwrap());
+        metrics().set_backend_up("first.evicted.backend", false);
+        finder.poll_backoff.record("first.evicted.backend", false);
+
+        for address in [
+            "first.evicted.backend",
+            "b.backend",
+            "first.evicted.backend",
+        ] {
+            finder.routed_server(address.to_string());
+        }
+        let routed = finder.routed_server("c.backend".to_string());
+        routed.route_connection();
+        assert_eq!(
+            finder
+                .routed_server("c.backend".to_string())
+                .active_connections(),
+            1
+        );
+
+        let mut addresses: Vec<String> = finder
+            .backends()
+            .into_iter()
+            .map(|server| server.address)
+            .collect();
+        addresses.sort();
+        assert_eq!(
+            addresses,
+            ["c.backend", "fallback.backend", "first.evicted.backend"]
+        );
+
+        finder.routed_server("d.backend".to_string());
+        assert!(finder.routed.peek("first.evicted.backend").is_none());
+        assert!(finder.poll_backoff.should_poll("first.evicted.backend"));
+        assert!(!metrics().render().contains("first.evicted.backend"));
+    }
+
+    fn hostname_finder(fallback: bool) -> HostnameServerFinder {
+        let mut yaml = String::from(
+            r#"
+routes:
+  "na.example.com":
+    address: "na.backend"
+  "*.example.com":
+    address: "any.backend"
+  "*.eu.example.com":
+    address: "eu.backend"
+"#,
+        );
+        if fallback {
+            yaml.push_str("fallback:\n  address: \"fallback.backend\"\n");
+        }
+        HostnameServerFinder::new(serde_yaml::from_str(&yaml).unwrap(), PingOptions::default())
+    }
+
+    async fn route_hostname(finder: &mut HostnameServerFinder, hostname: &str) -> Option<String> {
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        connection.hostname = hostname.to_string();
+        finder
+            .find_server(&connection)
+            .await
+            .ok()
+            .map(|server| server.address)
+    }
+
+    #[tokio::test]
+    async fn test_hostname_routes_exact_match() {
+        let mut finder = hostname_finder(false);
+        assert_eq!(
+            route_hostname(&mut finder, "na.example.com")
+                .await
+                .as_deref(),
+            Some("na.backend")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hostname_routes_wildcard_match() {
+        let mut finder = hostname_finder(false);
+        for (hostname, backend) in [
+            ("play.example.com", Some("any.backend")),
+            ("play.eu.example.com", Some("eu.backend")),
+            ("a.b.eu.example.com", Some("eu.backend")),
+            ("example.com", None),
+            ("play.example.org", None),
+        ] {
+            assert_eq!(
+                route_hostname(&mut finder, hostname).await.as_deref(),
+                backend,
+                "{}",
+                hostname
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hostname_routes_unknown_host_to_fallback() {
+        let mut finder = hostname_finder(true);
+        assert_eq!(
+            route_hostname(&mut finder, "play.example.org")
+                .await
+                .as_deref(),
+            Some("fallback.backend")
+        );
+    }
+
+    fn locale_finder() -> LocaleServerFinder {
+        let yaml = r#"
+routes:
+  "fr":
+    address: "fr.backend"
+  "en_US":
+    address: "us.backend"
+"#;
+        let inner = StaticServerFiner::new(
+            static_config("round_robin", &[SocketAddr::from(([127, 0, 0, 1], 1))]),
+            PingOptions::default(),
+        );
+        LocaleServerFinder::new(
+            serde_yaml::from_str(yaml).unwrap(),
+            Box::new(inner),
+            PingOptions::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_locale_routes_by_language_and_full_locale() {
+        let mut finder = locale_finder();
+        let (mut connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        for (locale, backend) in [
+            (Some("fr_FR"), "fr.backend"),
+            (Some("fr_ca"), "fr.backend"),
+            (Some("en_US"), "us.backend"),
+            (Some("en_gb"), "127.0.0.1:1"),
+            (None, "127.0.0.1:1"),
+        ] {
+            connection.locale = locale.map(str::to_string);
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_eq!(server.address, backend, "{:?}", locale);
+        }
+
+        // A draining route falls through to the mode's pick.
+        finder.routes["fr"].set_draining(true);
+        connection.locale = Some("fr_FR".to_string());
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, "127.0.0.1:1");
+    }
+
+    #[tokio::test]
+    async fn test_backends_at_capacity_are_skipped() {
+        let addresses: Vec<SocketAddr> = (1..=3)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        let mut finder = StaticServerFiner::new(
+            static_config("round_robin", &addresses),
+            PingOptions::default(),
+        );
+        for server in &mut finder.servers {
+            server.max_connections = Some(1);
+        }
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let _routed = finder.servers[0].route_connection();
+        for _ in 0..4 {
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_ne!(server.address, addresses[0].to_string());
+        }
+
+        let _routed = [
+            finder.servers[1].route_connection(),
+            finder.servers[2].route_connection(),
+        ];
+        assert!(matches!(
+            finder.find_server(&connection).await,
+            Err(FinderError::Full)
+        ));
+    }
+
+    #[test]
+    fn test_composite_score_follows_the_weights() {
+        let loads = [
+            (10, Duration::from_millis(100)),
+            (50, Duration::from_millis(20)),
+            (30, Duration::from_millis(25)),
+        ];
+        let weights = |player_count: f64, latency: f64| CompositeWeights {
+            player_count: Some(player_count),
+            latency: Some(latency),
+        };
+
+        // Scaled, the counts are 0, 1 and 0.5 and the latencies 1, 0 and 0.0625.
+        assert_eq!(lowest_composite_score(&loads, weights(1.0, 0.0)), Some(0));
+        assert_eq!(lowest_composite_score(&loads, weights(0.0, 1.0)), Some(1));
+        assert_eq!(lowest_composite_score(&loads, weights(1.0, 1.0)), Some(2));
+        assert_eq!(lowest_composite_score(&loads, weights(3.0, 1.0)), Some(0));
+        assert_eq!(lowest_composite_score(&loads, weights(1.0, 20.0)), Some(1));
+
+        let equal = [(5, Duration::from_millis(10)); 2];
+        assert_eq!(
+            lowest_composite_score(&equal, CompositeWeights::default()),
+            Some(0)
+        );
+        assert_eq!(
+            lowest_composite_score(&[], CompositeWeights::default()),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_least_connections_picks_least_used_server() {
+        let addresses: Vec<SocketAddr> = (1..=3)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        let mut finder = StaticServerFiner::new(
+            static_config("least_connections", &addresses),
+            PingOptions::default(),
+        );
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let _routed = [
+            finder.servers[0].route_connection(),
+            finder.servers[0].route_connection(),
+            finder.servers[2].route_connection(),
+        ];
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, addresses[1].to_string());
+
+        let _routed = server.route_connection();
+        let _routed = server.route_connection();
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, addresses[2].to_string());
+    }
+
+    #[tokio::test]
+    async fn test_lowest_latency_picks_fastest_server() {
+        let slow = spawn_delayed_player_count_backend(1, Duration::from_millis(300)).await;
+        let fast = spawn_player_count_backend(1).await;
+
+        let mut finder = StaticServerFiner::new(
+            static_config("lowest_latency", &[slow, fast]),
+            PingOptions::default(),
+        );
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, fast.to_string());
+
+        // The second selection is served from the cached measurements.
+        let started = std::time::Instant::now();
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, fast.to_string());
+        assert!(started.elapsed() < Duration::from_millis(300));
+    }
+
+    // Sends every player to the address in `custom.options`.
+    struct FixedFinder(MinecraftServer);
+
+    #[async_trait]
+    impl ServerFinder for FixedFinder {
+        async fn get_player_count(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn backends(&self) -> Vec<MinecraftServer> {
+            vec![self.0.clone()]
+        }
+
+        async fn find_server(
+            &mut self,
+            _connection: &Connection,
+        ) -> Result<MinecraftServer, FinderError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_finder_is_built_from_the_registry() {
+        register_finder("fixed", |config| {
+            let options = &config.custom_cfg.as_ref().unwrap().options;
+            let address = options["address"]
+                .as_str()
+                .ok_or("custom.options.address is required")?;
+            Ok(Box::new(FixedFinder(MinecraftServer::new(
+                address.to_string(),
+            ))))
+        });
+        let yaml =
+            "mode: custom\ncustom:\n  finder: fixed\n  options:\n    address: \"custom.backend\"\n";
+        let mut finder = get_server_finder(Config::from_yaml_str(yaml).unwrap()).unwrap();
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, "custom.backend");
+
+        let unregistered = yaml.replace("finder: fixed", "finder: missing");
+        let Err(error) = get_server_finder(Config::from_yaml_str(&unregistered).unwrap()) else {
+            panic!("built an unregistered finder");
+        };
+        assert_eq!(error.to_string(), "No finder is registered as 'missing'");
+    }
+
+    #[tokio::test]
+    async fn test_custom_finder_can_build_other_finders() {
+        register_finder("inner", |_config| {
+            Ok(Box::new(FixedFinder(MinecraftServer::new(
+                "inner.backend".to_string(),
+            ))))
+        });
+        // Wraps another custom finder, built while this one is being built.
+        register_finder("wrapper", |config| {
+            let mut inner = config.clone();
+            inner.custom_cfg.as_mut().unwrap().finder = "inner".to_string();
+            get_server_finder(inner)
+        });
+        let yaml = "mode: custom\ncustom:\n  finder: wrapper\n";
+        let mut finder = get_server_finder(Config::from_yaml_str(yaml).unwrap()).unwrap();
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, "inner.backend");
+    }
+
+    // Round robin over two backends, so consecutive picks differ.
+    fn affinity_finder() -> AffinityServerFinder {
+        let inner = StaticServerFiner::new(
+            serde_yaml::from_str(
+                "algorithm: round_robin\nservers:\n  - address: \"a.backend\"\n  - address: \"b.backend\"",
+            )
+            .unwrap(),
+            PingOptions::default(),
+        );
+        AffinityServerFinder::new(AffinityConfig::default(), Box::new(inner))
+    }
+
+    async fn player_connection(username: &str) -> (Connection, tokio::net::TcpStream) {
+        let (mut connection, client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        connection.username = Some(username.to_string());
+        (connection, client)
+    }
+
+    #[tokio::test]
+    async fn test_affinity_returns_players_to_their_backend() {
+        let mut finder = affinity_finder();
+        let (mut connection, _client) = player_connection("Notch").await;
+        let first = finder.find_server(&connection).await.unwrap();
+        finder.record_transfer(&connection, &first);
+
+        connection.username = Some("notch".to_string());
+        for _ in 0..3 {
+            let server = finder.find_server(&connection).await.unwrap();
+            assert_eq!(server.address, first.address);
+        }
+
+        // Other players are still balanced as usual.
+        let (other, _other_client) = player_connection("Jeb").await;
+        let server = finder.find_server(&other).await.unwrap();
+        assert_ne!(server.address, first.address);
+
+        // Nor is the unrouteable server remembered.
+        finder.record_transfer(&other, &MinecraftServer::new("lobby.backend".to_string()));
+        assert!(!finder.recent.contains_key("jeb"));
+    }
+
+    #[tokio::test]
+    async fn test_affinity_expires_after_the_ttl() {
+        let mut finder = affinity_finder();
+        let (connection, _client) = player_connection("Notch").await;
+        let first = finder.find_server(&connection).await.unwrap();
+        finder.record_transfer(&connection, &first);
+
+        let (_, transferred_at) = finder.recent.get_mut("notch").unwrap();
+        *transferred_at -= Duration::from_secs(121);
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_ne!(server.address, first.address);
+        assert!(finder.recent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_affinity_skips_a_backend_that_went_down() {
+        let mut finder = affinity_finder();
+        let (connection, _client) = player_connection("Notch").await;
+        let first = finder.find_server(&connection).await.unwrap();
+        finder.record_transfer(&connection, &first);
+
+        first.record_error("connection refused".to_string());
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_ne!(server.address, first.address);
+    }
+
+    #[tokio::test]
+    async fn test_affinity_resumes_once_the_backend_recovers() {
+        let addresses = [
+            spawn_flaky_player_count_backend(0, 1).await,
+            spawn_player_count_backend(0).await,
+        ];
+        let ping = PingOptions {
+            retries: 0,
+            ..PingOptions::default()
+        };
+        let inner = StaticServerFiner::new(static_config("round_robin", &addresses), ping);
+        let mut finder = AffinityServerFinder::new(AffinityConfig::default(), Box::new(inner));
+        let (connection, _client) = player_connection("Notch").await;
+        let first = finder.backends()[0].clone();
+        finder.record_transfer(&connection, &first);
+
+        assert!(first.get_player_count().await.is_err());
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_ne!(server.address, first.address);
 
-        Ok(self.fallback.clone())
+        first.get_player_count().await.unwrap();
+        let server = finder.find_server(&connection).await.unwrap();
+        assert_eq!(server.address, first.address);
     }
 }
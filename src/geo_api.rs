@@ -1,10 +1,67 @@
-use redb::{Database, ReadableDatabase, TableDefinition};
-use reqwest::Client;
+//! Looking up where clients are, for the geo finder and for finders that
+//! tailor the server list to a client's region. Custom finders override
+//! `ServerFinder::status_geo` and `status_overrides` with these types:
+//!
+//! ```
+//! use async_trait::async_trait;
+//! use loadbalancer_rs::backend::MinecraftServer;
+//! use loadbalancer_rs::connection::Connection;
+//! use loadbalancer_rs::finder::{FinderError, ServerFinder, StatusOverrides};
+//! use loadbalancer_rs::geo_api::{GeoProvider, IpInfo};
+//! use std::net::IpAddr;
+//! use std::sync::Arc;
+//!
+//! struct RegionalMotd(Arc<dyn GeoProvider>);
+//!
+//! #[async_trait]
+//! impl ServerFinder for RegionalMotd {
+//!     async fn get_player_count(&self) -> Option<u32> {
+//!         None
+//!     }
+//!
+//!     fn backends(&self) -> Vec<MinecraftServer> {
+//!         Vec::new()
+//!     }
+//!
+//!     async fn find_server(
+//!         &mut self,
+//!         _connection: &Connection,
+//!     ) -> Result<MinecraftServer, FinderError> {
+//!         Err(FinderError::NoServersAvailable)
+//!     }
+//!
+//!     fn status_geo(&self, _ip: IpAddr) -> Option<Arc<dyn GeoProvider>> {
+//!         Some(self.0.clone())
+//!     }
+//!
+//!     fn status_overrides(&self, _ip: IpAddr, ip_info: Option<&IpInfo>) -> StatusOverrides {
+//!         StatusOverrides {
+//!             motd: ip_info.map(|info| format!("Welcome from {}", info.country)),
+//!             ..StatusOverrides::default()
+//!         }
+//!     }
+//! }
+//! ```
+
+use crate::config::{Coordinates, GeoConfig, GeoProviderKind};
+use crate::metrics::metrics;
+use async_trait::async_trait;
+use log::warn;
+use maxminddb::{Reader, geoip2};
+use redb::{Database, DatabaseError, ReadableDatabase, TableDefinition};
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
-use std::path::Path;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::Instant;
+use thiserror::Error;
+use tokio::sync::OnceCell;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IpInfo {
     pub ip: String,
     pub asn: String,
@@ -14,41 +71,287 @@ pub struct IpInfo {
     pub country: String,
     pub continent_code: String,
     pub continent: String,
+    // Only known to the maxmind provider, with a city database.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coordinates: Option<Coordinates>,
 }
 
-const GEO_TABLE: TableDefinition<String, String> = TableDefinition::new("geo_cache");
+/// Why `GeoCache` could not answer a lookup. Cloned to every caller waiting
+/// on the same address.
+#[derive(Debug, Clone, Error)]
+pub enum GeoLookupError {
+    #[error("Too many geo lookups, over the configured rate limit")]
+    Saturated,
+    #[error("The geo provider rejected the token ({0})")]
+    Unauthorized(String),
+    #[error("Geo lookups are disabled, the token was rejected too many times")]
+    Disabled,
+    #[error("{0}")]
+    Failed(String),
+}
 
-pub struct GeoCache {
+/// A source of geolocation data for client addresses.
+#[async_trait]
+pub trait GeoProvider: Send + Sync {
+    async fn lookup(&self, ip: IpAddr) -> Result<IpInfo, Box<dyn Error>>;
+
+    /// Looks `ip` up only if that's free, as for status pings, which shouldn't
+    /// spend the lookups players need. Providers that never make a request
+    /// answer like `lookup`.
+    async fn cached_lookup(&self, ip: IpAddr) -> Option<IpInfo> {
+        self.lookup(ip).await.ok()
+    }
+
+    /// Releases anything held open, at shutdown. Later lookups still work,
+    /// but may skip caching.
+    fn close(&self) {}
+}
+
+/// Builds the provider selected in the geo config.
+pub fn provider_from_config(config: &GeoConfig) -> Result<Box<dyn GeoProvider>, Box<dyn Error>> {
+    match config.provider {
+        GeoProviderKind::Ipinfo => {
+            let client = IpInfoClient::new(config.token.clone());
+            let cache = GeoCache::new(
+                Box::new(client),
+                Path::new(config.cache_file()),
+                config.max_lookups_per_second(),
+                config.auth_failure_limit(),
+            )?;
+            Ok(Box::new(cache))
+        }
+        GeoProviderKind::Maxmind => {
+            let path = config
+                .database
+                .as_ref()
+                .ok_or("The maxmind geo provider requires a database path")?;
+            Ok(Box::new(MaxMindDatabase::open(Path::new(path))?))
+        }
+    }
+}
+
+/// Looks addresses up with the ipinfo.io Lite API.
+pub struct IpInfoClient {
     client: Client,
     token: String,
-    db: Database,
 }
 
-impl GeoCache {
-    pub fn new(token: String) -> Result<Self, Box<dyn Error>> {
-        let db = Database::create(Path::new("cache/geo.redb"))?;
-        Ok(GeoCache {
+impl IpInfoClient {
+    pub fn new(token: String) -> Self {
+        IpInfoClient {
             client: Client::new(),
             token,
-            db,
+        }
+    }
+}
+
+#[async_trait]
+impl GeoProvider for IpInfoClient {
+    async fn lookup(&self, ip: IpAddr) -> Result<IpInfo, Box<dyn Error>> {
+        let url = format!("https://api.ipinfo.io/lite/{}?token={}", ip, self.token);
+        let response = self.client.get(&url).send().await?;
+        // A missing or unknown token is answered with one of these.
+        if let status @ (StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) = response.status() {
+            return Err(GeoLookupError::Unauthorized(status.to_string()).into());
+        }
+        Ok(response.json().await?)
+    }
+}
+
+/// Looks addresses up in a local MaxMind country or city database, such as
+/// GeoLite2-Country. No network access or token is needed. City databases
+/// also give the client's coordinates.
+pub struct MaxMindDatabase {
+    reader: Reader<Vec<u8>>,
+}
+
+impl MaxMindDatabase {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(MaxMindDatabase {
+            reader: Reader::open_readfile(path)?,
         })
     }
+}
 
-    pub async fn get_geo_data(&self, ip: &str) -> Result<IpInfo, Box<dyn Error>> {
-        if let Some(info) = self.get_cached_ip_info(ip)? {
-            return Ok(info);
+#[async_trait]
+impl GeoProvider for MaxMindDatabase {
+    async fn lookup(&self, ip: IpAddr) -> Result<IpInfo, Box<dyn Error>> {
+        // A country database answers with the same record, minus the location.
+        let record: geoip2::City = self
+            .reader
+            .lookup(ip)?
+            .ok_or_else(|| format!("{} is not in the geo database", ip))?;
+
+        let english_name = |names: Option<BTreeMap<&str, &str>>| {
+            names
+                .and_then(|names| names.get("en").map(|name| name.to_string()))
+                .unwrap_or_default()
+        };
+        let (country_code, country) = record
+            .country
+            .map(|country| (country.iso_code, english_name(country.names)))
+            .unwrap_or_default();
+        let (continent_code, continent) = record
+            .continent
+            .map(|continent| (continent.code, english_name(continent.names)))
+            .unwrap_or_default();
+        let coordinates = record.location.and_then(|location| {
+            Some(Coordinates {
+                latitude: location.latitude?,
+                longitude: location.longitude?,
+            })
+        });
+
+        Ok(IpInfo {
+            ip: ip.to_string(),
+            asn: String::new(),
+            as_name: String::new(),
+            as_domain: String::new(),
+            country_code: country_code.unwrap_or_default().to_string(),
+            country,
+            continent_code: continent_code.unwrap_or_default().to_string(),
+            continent,
+            coordinates,
+        })
+    }
+}
+
+const GEO_TABLE: TableDefinition<String, String> = TableDefinition::new("geo_cache");
+
+// Cache files open in this process, by path. redb refuses to open a file
+// twice, and a reloaded config opens the cache while the finder it replaces
+// still has it open, so the two share a handle instead.
+static OPEN_CACHES: LazyLock<Mutex<HashMap<PathBuf, Weak<Database>>>> =
+    LazyLock::new(Default::default);
+
+/// Caches another provider's answers on disk, so each address is only looked
+/// up once. Concurrent lookups of the same address share one request.
+pub struct GeoCache {
+    inner: Box<dyn GeoProvider>,
+    // None once closed.
+    db: Mutex<Option<Arc<Database>>>,
+    // Requests to `inner` still running, by address.
+    in_flight: Mutex<HashMap<IpAddr, Arc<OnceCell<Result<IpInfo, GeoLookupError>>>>>,
+    limiter: Option<RateLimiter>,
+    // Lookups stop once `inner` rejects the token this many times in a row.
+    auth_failure_limit: Option<u32>,
+    auth_failures: AtomicU32,
+}
+
+impl GeoCache {
+    pub fn new(
+        inner: Box<dyn GeoProvider>,
+        path: &Path,
+        max_lookups_per_second: u32,
+        auth_failure_limit: u32,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut cache = Self::open(inner, path)?;
+        cache.limiter = Some(RateLimiter::new(max_lookups_per_second));
+        cache.auth_failure_limit = Some(auth_failure_limit);
+        Ok(cache)
+    }
+
+    /// Opens the cache at `path`, sharing the handle of any other cache this
+    /// process has open there. A file redb can't open is moved aside to
+    /// `<path>.corrupt` and replaced with an empty cache, since everything in
+    /// it can be looked up again.
+    pub fn open(inner: Box<dyn GeoProvider>, path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut open_caches = OPEN_CACHES.lock().unwrap();
+        if let Some(db) = open_caches.get(path).and_then(Weak::upgrade) {
+            return Ok(Self::with_database(inner, db));
         }
+        let db = match Database::create(path) {
+            Ok(db) => db,
+            // Another process is using it, which says nothing about its contents.
+            Err(error @ DatabaseError::DatabaseAlreadyOpen) => return Err(error.into()),
+            Err(error) if path.exists() => {
+                let mut moved = PathBuf::from(path);
+                moved.as_mut_os_string().push(".corrupt");
+                warn!(
+                    "Geo cache {} can't be opened ({}), moving it to {} and starting over",
+                    path.display(),
+                    error,
+                    moved.display()
+                );
+                std::fs::rename(path, &moved)?;
+                Database::create(path)?
+            }
+            Err(error) => return Err(error.into()),
+        };
+        let db = Arc::new(db);
+        open_caches.insert(path.to_path_buf(), Arc::downgrade(&db));
+        Ok(Self::with_database(inner, db))
+    }
 
-        let url = format!("https://api.ipinfo.io/lite/{}?token={}", ip, self.token);
-        let response = self.client.get(&url).send().await?;
-        let ip_info: IpInfo = response.json().await?;
-        self.cache_ip_info(&ip_info)?;
+    fn with_database(inner: Box<dyn GeoProvider>, db: Arc<Database>) -> Self {
+        GeoCache {
+            inner,
+            db: Mutex::new(Some(db)),
+            in_flight: Mutex::new(HashMap::new()),
+            limiter: None,
+            auth_failure_limit: None,
+            auth_failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Whether lookups stopped after the token was rejected too many times.
+    pub fn is_disabled(&self) -> bool {
+        self.auth_failure_limit
+            .is_some_and(|limit| self.auth_failures.load(Ordering::SeqCst) >= limit)
+    }
+
+    // Asks `inner`, unless that would exceed the rate limit.
+    async fn fetch(&self, ip: IpAddr) -> Result<IpInfo, GeoLookupError> {
+        if self.is_disabled() {
+            return Err(GeoLookupError::Disabled);
+        }
+        if self
+            .limiter
+            .as_ref()
+            .is_some_and(|limiter| !limiter.try_acquire())
+        {
+            return Err(GeoLookupError::Saturated);
+        }
+        metrics().record_geo_cache_miss();
+        let ip_info = self.inner.lookup(ip).await.map_err(|error| {
+            metrics().record_geo_api_error();
+            self.lookup_failed(error)
+        })?;
+        self.auth_failures.store(0, Ordering::SeqCst);
+        self.cache_ip_info(&ip_info)
+            .map_err(|error| GeoLookupError::Failed(error.to_string()))?;
         Ok(ip_info)
     }
 
+    // Counts rejected tokens, warning once when there have been enough in a
+    // row to disable lookups.
+    fn lookup_failed(&self, error: Box<dyn Error>) -> GeoLookupError {
+        let Some(GeoLookupError::Unauthorized(status)) = error.downcast_ref::<GeoLookupError>()
+        else {
+            return GeoLookupError::Failed(error.to_string());
+        };
+        let failures = self.auth_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.auth_failure_limit == Some(failures) {
+            warn!(
+                "The geo provider rejected the token {} times in a row ({}). Geo lookups are \
+                 disabled and every player goes to the fallbacks until the token is fixed and \
+                 the config reloaded with SIGHUP",
+                failures, status
+            );
+        }
+        GeoLookupError::Unauthorized(status.clone())
+    }
+
     fn cache_ip_info(&self, info: &IpInfo) -> Result<(), Box<dyn Error>> {
+        let db = self.db.lock().unwrap();
+        let Some(db) = db.as_ref() else {
+            return Ok(());
+        };
         let json = serde_json::to_string(info)?;
-        let tx = self.db.begin_write()?;
+        // Commits default to immediate durability, so a committed entry is
+        // on disk before this returns.
+        let tx = db.begin_write()?;
         {
             let mut table = tx.open_table(GEO_TABLE)?;
             table.insert(&info.ip, &json)?;
@@ -58,7 +361,11 @@ impl GeoCache {
     }
 
     fn get_cached_ip_info(&self, ip: &str) -> Result<Option<IpInfo>, Box<dyn Error>> {
-        let tx = self.db.begin_read()?;
+        let db = self.db.lock().unwrap();
+        let Some(db) = db.as_ref() else {
+            return Ok(None);
+        };
+        let tx = db.begin_read()?;
         let table = tx.open_table(GEO_TABLE)?;
         if let Some(json) = table.get(String::from(ip))? {
             let info: IpInfo = serde_json::from_str(&json.value())?;
@@ -69,9 +376,86 @@ impl GeoCache {
     }
 }
 
+#[async_trait]
+impl GeoProvider for GeoCache {
+    async fn lookup(&self, ip: IpAddr) -> Result<IpInfo, Box<dyn Error>> {
+        if let Some(info) = self.get_cached_ip_info(&ip.to_string())? {
+            metrics().record_geo_cache_hit();
+            return Ok(info);
+        }
+
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(ip)
+            .or_default()
+            .clone();
+        let result = cell.get_or_init(|| self.fetch(ip)).await.clone();
+        // The answer is cached by now, so later lookups don't need the cell.
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(&ip)
+            .is_some_and(|current| Arc::ptr_eq(current, &cell))
+        {
+            in_flight.remove(&ip);
+        }
+        Ok(result?)
+    }
+
+    // Addresses nobody logged in from yet stay unknown, rather than costing a
+    // request and a token from the rate limit.
+    async fn cached_lookup(&self, ip: IpAddr) -> Option<IpInfo> {
+        let info = self.get_cached_ip_info(&ip.to_string()).ok()??;
+        metrics().record_geo_cache_hit();
+        Some(info)
+    }
+
+    // Dropping the last handle to the database is what marks the file as
+    // cleanly closed, so the next start doesn't have to repair it.
+    fn close(&self) {
+        self.db.lock().unwrap().take();
+        self.inner.close();
+    }
+}
+
+/// A token bucket refilled at `per_second` tokens a second, holding at most
+/// one second's worth.
+struct RateLimiter {
+    per_second: f64,
+    // Tokens left, as of the instant.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(per_second: u32) -> Self {
+        let per_second = f64::from(per_second);
+        RateLimiter {
+            per_second,
+            bucket: Mutex::new((per_second, Instant::now())),
+        }
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, refilled_at) = &mut *bucket;
+        let now = Instant::now();
+        let refill = now.duration_since(*refilled_at).as_secs_f64() * self.per_second;
+        *tokens = (*tokens + refill).min(self.per_second);
+        *refilled_at = now;
+        if *tokens < 1.0 {
+            return false;
+        }
+        *tokens -= 1.0;
+        true
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
     use tempfile::tempdir;
 
     fn sample_ipinfo() -> IpInfo {
@@ -84,19 +468,19 @@ mod tests {
             country: "United States".to_string(),
             continent_code: "NA".to_string(),
             continent: "North America".to_string(),
+            coordinates: None,
         }
     }
 
+    fn dummy_client() -> Box<dyn GeoProvider> {
+        Box::new(IpInfoClient::new("dummy".to_string()))
+    }
+
     #[test]
     fn test_cache_ip_info_and_get_cached_ip_info() {
         let dir = tempdir().unwrap();
         let db_path = dir.path().join("geo_test.redb");
-        let db = Database::create(&db_path).unwrap();
-        let cache = GeoCache {
-            client: Client::new(),
-            token: "dummy".to_string(),
-            db,
-        };
+        let cache = GeoCache::open(dummy_client(), &db_path).unwrap();
 
         let info = sample_ipinfo();
         cache.cache_ip_info(&info).unwrap();
@@ -106,6 +490,205 @@ mod tests {
         assert_eq!(retrieved.unwrap().ip, info.ip);
     }
 
+    #[test]
+    fn test_corrupt_cache_file_is_replaced() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("geo_test.redb");
+        std::fs::write(&db_path, b"not a redb database").unwrap();
+
+        let cache = GeoCache::open(dummy_client(), &db_path).unwrap();
+        let info = sample_ipinfo();
+        cache.cache_ip_info(&info).unwrap();
+        assert!(cache.get_cached_ip_info(&info.ip).unwrap().is_some());
+
+        let moved = std::fs::read(dir.path().join("geo_test.redb.corrupt")).unwrap();
+        assert_eq!(moved, b"not a redb database");
+    }
+
+    #[test]
+    fn test_closed_cache_can_be_reopened() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("geo_test.redb");
+        let cache = GeoCache::open(dummy_client(), &db_path).unwrap();
+        let info = sample_ipinfo();
+        cache.cache_ip_info(&info).unwrap();
+
+        cache.close();
+        assert!(cache.get_cached_ip_info(&info.ip).unwrap().is_none());
+
+        let reopened = GeoCache::open(dummy_client(), &db_path).unwrap();
+        assert!(reopened.get_cached_ip_info(&info.ip).unwrap().is_some());
+        assert!(!dir.path().join("geo_test.redb.corrupt").exists());
+    }
+
+    #[test]
+    fn test_cache_open_twice_shares_the_file() {
+        let dir = tempdir().unwrap();
+        let db_path = dir.path().join("geo_test.redb");
+        let first = GeoCache::open(dummy_client(), &db_path).unwrap();
+        let second = GeoCache::open(dummy_client(), &db_path).unwrap();
+
+        let info = sample_ipinfo();
+        first.cache_ip_info(&info).unwrap();
+        first.close();
+        assert!(second.get_cached_ip_info(&info.ip).unwrap().is_some());
+        assert!(!dir.path().join("geo_test.redb.corrupt").exists());
+    }
+
+    // Answers every lookup after a short delay, counting the calls.
+    struct CountingProvider(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl GeoProvider for CountingProvider {
+        async fn lookup(&self, ip: IpAddr) -> Result<IpInfo, Box<dyn Error>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(IpInfo {
+                ip: ip.to_string(),
+                ..sample_ipinfo()
+            })
+        }
+    }
+
+    fn counting_cache(dir: &Path) -> (GeoCache, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let cache = GeoCache::open(
+            Box::new(CountingProvider(calls.clone())),
+            &dir.join("geo_test.redb"),
+        )
+        .unwrap();
+        (cache, calls)
+    }
+
+    #[tokio::test]
+    async fn test_lookups_are_counted() {
+        let dir = tempdir().unwrap();
+        let (cache, calls) = counting_cache(dir.path());
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+
+        // The counters are global, so other tests may add to them too.
+        let before = metrics().geo_cache_counts();
+        cache.lookup(ip).await.unwrap();
+        let after_miss = metrics().geo_cache_counts();
+        assert!(after_miss.misses > before.misses);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        cache.lookup(ip).await.unwrap();
+        let after_hit = metrics().geo_cache_counts();
+        assert!(after_hit.hits > after_miss.hits);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cached_lookups_never_ask_the_provider() {
+        let dir = tempdir().unwrap();
+        let (cache, calls) = counting_cache(dir.path());
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+
+        assert!(cache.cached_lookup(ip).await.is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        cache.lookup(ip).await.unwrap();
+        assert_eq!(cache.cached_lookup(ip).await.unwrap().ip, "1.2.3.4");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_share_one_request() {
+        let dir = tempdir().unwrap();
+        let (cache, calls) = counting_cache(dir.path());
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+
+        let results = futures::future::join_all((0..10).map(|_| cache.lookup(ip))).await;
+        for result in results {
+            assert_eq!(result.unwrap().ip, "1.2.3.4");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lookups_past_the_rate_limit_fail_fast() {
+        let dir = tempdir().unwrap();
+        let (mut cache, calls) = counting_cache(dir.path());
+        cache.limiter = Some(RateLimiter::new(1));
+
+        cache.lookup("1.2.3.4".parse().unwrap()).await.unwrap();
+        let error = cache.lookup("5.6.7.8".parse().unwrap()).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<GeoLookupError>(),
+            Some(GeoLookupError::Saturated)
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Cached addresses don't count against the limit.
+        cache.lookup("1.2.3.4".parse().unwrap()).await.unwrap();
+    }
+
+    // Rejects every lookup the way ipinfo rejects a bad token, counting the calls.
+    struct RejectingProvider(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl GeoProvider for RejectingProvider {
+        async fn lookup(&self, _ip: IpAddr) -> Result<IpInfo, Box<dyn Error>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(GeoLookupError::Unauthorized("403 Forbidden".to_string()).into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repeated_auth_failures_disable_lookups() {
+        let dir = tempdir().unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut cache = GeoCache::open(
+            Box::new(RejectingProvider(calls.clone())),
+            &dir.path().join("geo_test.redb"),
+        )
+        .unwrap();
+        cache.auth_failure_limit = Some(2);
+
+        for ip in ["1.2.3.4", "5.6.7.8"] {
+            let error = cache.lookup(ip.parse().unwrap()).await.unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<GeoLookupError>(),
+                Some(GeoLookupError::Unauthorized(_))
+            ));
+        }
+        assert!(cache.is_disabled());
+
+        let error = cache.lookup("9.9.9.9".parse().unwrap()).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<GeoLookupError>(),
+            Some(GeoLookupError::Disabled)
+        ));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_reloading_with_a_new_token_enables_lookups_again() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("geo_test.redb");
+        let rejected = GeoCache::new(
+            Box::new(RejectingProvider(Arc::new(AtomicUsize::new(0)))),
+            &path,
+            10,
+            1,
+        )
+        .unwrap();
+        let _ = rejected.lookup("1.2.3.4".parse().unwrap()).await;
+        assert!(rejected.is_disabled());
+
+        // A reload builds the provider again while the old finder still has
+        // the cache open.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let reloaded =
+            GeoCache::new(Box::new(CountingProvider(calls.clone())), &path, 10, 1).unwrap();
+        assert!(!reloaded.is_disabled());
+        let info = reloaded.lookup("1.2.3.4".parse().unwrap()).await.unwrap();
+        assert_eq!(info.ip, "1.2.3.4");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
     #[test]
     fn test_ipinfo_serialization() {
         let info = sample_ipinfo();
@@ -114,4 +697,43 @@ mod tests {
         assert_eq!(info.ip, deserialized.ip);
         assert_eq!(info.country, deserialized.country);
     }
+
+    fn test_database() -> MaxMindDatabase {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/geo-country-test.mmdb"
+        );
+        MaxMindDatabase::open(Path::new(path)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_maxmind_lookup() {
+        let database = test_database();
+
+        let info = database.lookup("192.0.2.1".parse().unwrap()).await.unwrap();
+        assert_eq!(info.ip, "192.0.2.1");
+        assert_eq!(info.country_code, "GB");
+        assert_eq!(info.country, "United Kingdom");
+        assert_eq!(info.continent_code, "EU");
+        assert_eq!(info.continent, "Europe");
+
+        let info = database
+            .lookup("198.51.100.7".parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(info.country_code, "US");
+        assert_eq!(info.continent_code, "NA");
+    }
+
+    #[tokio::test]
+    async fn test_maxmind_unknown_address() {
+        let database = test_database();
+
+        assert!(
+            database
+                .lookup("203.0.113.1".parse().unwrap())
+                .await
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,168 @@
+//! Liveness and readiness probes for container orchestrators, served apart
+//! from the Minecraft port.
+//!
+//! `GET /healthz` answers 200 as long as the process is running. `GET /readyz`
+//! answers 200 once at least one backend answered its last player count poll,
+//! and 503 otherwise. When counting transfers the backends are never polled,
+//! so instead it answers 200 while one takes players and its last ping, if
+//! any, didn't fail. The config was validated at startup, so it needs no
+//! check of its own. Neither probe waits on the finder: liveness never looks
+//! at it, and readiness falls back to the backends it last saw while the
+//! finder is busy.
+
+use crate::backend::MinecraftServer;
+use crate::config::PlayerCountSource;
+use crate::finder::ServerFinder;
+use crate::http_server::{self, Request, Response};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+
+/// Serves the probes for whichever finder is behind `server_finder`, so
+/// readiness follows its polls, and a reload, right away. `count_source` is
+/// how the status cache counts players, which decides whether backends get
+/// polled at all.
+pub async fn serve(
+    listener: TcpListener,
+    server_finder: Arc<Mutex<Box<dyn ServerFinder>>>,
+    count_source: PlayerCountSource,
+) -> std::io::Result<()> {
+    let last_seen = Arc::new(StdMutex::new(server_finder.lock().await.backends()));
+    http_server::serve(listener, "health", move |request| {
+        let server_finder = server_finder.clone();
+        let last_seen = last_seen.clone();
+        async move {
+            let (status, body) = respond(&request, || {
+                let backends = current_backends(&server_finder, &last_seen);
+                is_ready(&backends, count_source)
+            });
+            Response {
+                status,
+                content_type: "text/plain",
+                body: body.to_string(),
+            }
+        }
+    })
+    .await
+}
+
+// `ready` is only asked for on `/readyz`.
+fn respond(request: &Request, ready: impl FnOnce() -> bool) -> (&'static str, &'static str) {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/healthz") => ("200 OK", "ok"),
+        ("GET", "/readyz") => {
+            if ready() {
+                ("200 OK", "ready")
+            } else {
+                ("503 Service Unavailable", "no backend is up")
+            }
+        }
+        _ => ("404 Not Found", "not found"),
+    }
+}
+
+// The finder's backends, or the ones seen last while it is busy placing a
+// player. The copies share their state with the finder's, so even a list
+// that is out of date reports how its backends are doing now.
+fn current_backends(
+    server_finder: &Mutex<Box<dyn ServerFinder>>,
+    last_seen: &StdMutex<Vec<MinecraftServer>>,
+) -> Vec<MinecraftServer> {
+    let mut last_seen = last_seen.lock().unwrap();
+    if let Ok(server_finder) = server_finder.try_lock() {
+        *last_seen = server_finder.backends();
+    }
+    last_seen.clone()
+}
+
+fn is_ready(backends: &[MinecraftServer], count_source: PlayerCountSource) -> bool {
+    backends.iter().any(|backend| match count_source {
+        PlayerCountSource::Ping => backend.last_player_count().is_some(),
+        PlayerCountSource::Transfers => backend.accepts_players() && !backend.is_down(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{finder_for, spawn_player_count_backend};
+    use std::net::SocketAddr;
+
+    async fn spawn_health(
+        finder: Box<dyn ServerFinder>,
+        count_source: PlayerCountSource,
+    ) -> SocketAddr {
+        spawn_shared_health(Arc::new(Mutex::new(finder)), count_source).await
+    }
+
+    async fn spawn_shared_health(
+        finder: Arc<Mutex<Box<dyn ServerFinder>>>,
+        count_source: PlayerCountSource,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, finder, count_source));
+        addr
+    }
+
+    async fn status(addr: SocketAddr, path: &str) -> u16 {
+        reqwest::get(format!("http://{}{}", addr, path))
+            .await
+            .unwrap()
+            .status()
+            .as_u16()
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_while_every_backend_is_down() {
+        let finder = finder_for(&["127.0.0.1:1"]);
+        let backends = finder.backends();
+        assert!(backends[0].get_player_count().await.is_err());
+        let addr = spawn_health(finder, PlayerCountSource::Ping).await;
+
+        assert_eq!(status(addr, "/healthz").await, 200);
+        assert_eq!(status(addr, "/readyz").await, 503);
+    }
+
+    #[tokio::test]
+    async fn test_ready_once_a_backend_is_up() {
+        let up = spawn_player_count_backend(4).await.to_string();
+        let finder = finder_for(&["127.0.0.1:1".to_string(), up]);
+        let backends = finder.backends();
+        let addr = spawn_health(finder, PlayerCountSource::Ping).await;
+        assert_eq!(status(addr, "/readyz").await, 503);
+
+        backends[1].get_player_count().await.unwrap();
+        assert_eq!(status(addr, "/readyz").await, 200);
+        assert_eq!(status(addr, "/unknown").await, 404);
+    }
+
+    #[tokio::test]
+    async fn test_ready_without_polls_when_counting_transfers() {
+        let finder = finder_for(&["127.0.0.1:1", "127.0.0.1:2"]);
+        let backends = finder.backends();
+        let addr = spawn_health(finder, PlayerCountSource::Transfers).await;
+        assert_eq!(status(addr, "/readyz").await, 200);
+
+        // Down once every backend failed a ping, or is draining.
+        assert!(backends[0].get_player_count().await.is_err());
+        backends[1].set_draining(true);
+        assert_eq!(status(addr, "/readyz").await, 503);
+    }
+
+    #[tokio::test]
+    async fn test_probes_answer_while_the_finder_is_locked() {
+        let up = spawn_player_count_backend(4).await.to_string();
+        let finder = finder_for(&[up]);
+        let backends = finder.backends();
+        let finder = Arc::new(Mutex::new(finder));
+        let addr = spawn_shared_health(finder.clone(), PlayerCountSource::Ping).await;
+
+        let _busy = finder.lock().await;
+        assert_eq!(status(addr, "/healthz").await, 200);
+        assert_eq!(status(addr, "/readyz").await, 503);
+        // The backends seen at startup still report their latest polls.
+        backends[0].get_player_count().await.unwrap();
+        assert_eq!(status(addr, "/readyz").await, 200);
+    }
+}
@@ -0,0 +1,116 @@
+//! The bare HTTP/1.1 the admin API, the health probes and the metrics page are
+//! served with: one request per connection, read in a single packet, and one
+//! response with the connection closed after it.
+
+use log::debug;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// The parts of a request the endpoints look at.
+#[derive(Debug, Default)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Reads the request line and headers of `raw`. Anything missing is left
+    /// empty, which no endpoint matches.
+    pub fn parse(raw: &str) -> Self {
+        let mut lines = raw.split("\r\n");
+        let mut request_line = lines.next().unwrap_or_default().split(' ');
+        let method = request_line.next().unwrap_or_default().to_string();
+        let path = request_line.next().unwrap_or_default().to_string();
+        let headers = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Request {
+            method,
+            path,
+            headers,
+        }
+    }
+
+    /// The value of the first header called `name`, in any case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response {
+    /// The status line after the version, like `200 OK`.
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+/// Answers every request on `listener` with `handle`, each connection on its
+/// own task. `name` says which server failed in the logs.
+pub async fn serve<H, F>(
+    listener: TcpListener,
+    name: &'static str,
+    handle: H,
+) -> std::io::Result<()>
+where
+    H: Fn(Request) -> F + Send + Sync + 'static,
+    F: Future<Output = Response> + Send + 'static,
+{
+    let handle = Arc::new(handle);
+    loop {
+        let (mut stream, addr) = listener.accept().await?;
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            let mut request = [0u8; 4096];
+            let Ok(read) = stream.read(&mut request).await else {
+                return;
+            };
+            let request = Request::parse(&String::from_utf8_lossy(&request[..read]));
+
+            let response = handle(request).await;
+            if let Err(error) = stream.write_all(&response.to_bytes()).await {
+                debug!("Failed to write {} response to {}: {}", name, addr, error);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_line_and_headers_are_read() {
+        let request = Request::parse(
+            "POST /backends/a.example.com/drain HTTP/1.1\r\nHost: localhost\r\nAUTHORIZATION:  Bearer secret \r\n\r\nignored: body",
+        );
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/backends/a.example.com/drain");
+        assert_eq!(request.header("authorization"), Some("Bearer secret"));
+        assert_eq!(request.header("ignored"), None);
+
+        let garbage = Request::parse("");
+        assert_eq!(garbage.method, "");
+        assert_eq!(garbage.path, "");
+    }
+}
@@ -0,0 +1,141 @@
+//! Answers the server list ping of clients from before 1.7, which starts with
+//! a 0xFE byte instead of a length-prefixed handshake.
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const LEGACY_PING: u8 = 0xFE;
+const KICK: u8 = 0xFF;
+// Not a real protocol, so old clients list the server as incompatible.
+const LEGACY_PROTOCOL: &str = "127";
+
+/// Checks the first byte of `stream` without consuming it.
+pub async fn is_legacy_ping(stream: &TcpStream) -> std::io::Result<bool> {
+    let mut first = [0u8; 1];
+    let read = stream.peek(&mut first).await?;
+    Ok(read == 1 && first[0] == LEGACY_PING)
+}
+
+/// Sends the kick packet legacy clients read their server list entry from,
+/// then closes the stream.
+pub async fn respond(
+    stream: &mut TcpStream,
+    motd: &str,
+    online: u32,
+    max: u32,
+) -> std::io::Result<()> {
+    // Consume the ping first. Closing a socket with unread data resets the
+    // connection, and the client can lose the reply with it. 1.6 clients
+    // follow 0xFE 0x01 with a short plugin message naming the host they used.
+    let mut ping = [0u8; 512];
+    let read = stream.read(&mut ping).await?;
+    debug!("Consumed a {} byte legacy ping", read);
+
+    stream.write_all(&kick_packet(motd, online, max)).await?;
+    stream.shutdown().await
+}
+
+// The 1.4+ format: fields separated by NUL and sent as UTF-16BE, behind a
+// length in UTF-16 code units.
+fn kick_packet(motd: &str, online: u32, max: u32) -> Vec<u8> {
+    let text = [
+        "§1",
+        LEGACY_PROTOCOL,
+        "Loadbalancer",
+        motd,
+        &online.to_string(),
+        &max.to_string(),
+    ]
+    .join("\0");
+    let units: Vec<u16> = text.encode_utf16().collect();
+
+    let mut packet = vec![KICK];
+    packet.extend_from_slice(&(units.len() as u16).to_be_bytes());
+    for unit in units {
+        packet.extend_from_slice(&unit.to_be_bytes());
+    }
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_legacy_ping_gets_kick_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        client.write_all(&[0xFE, 0x01]).await.unwrap();
+        assert!(is_legacy_ping(&stream).await.unwrap());
+        respond(&mut stream, "A test server", 3, 100).await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response[0], KICK);
+        let units: Vec<u16> = response[3..]
+            .chunks(2)
+            .map(|unit| u16::from_be_bytes([unit[0], unit[1]]))
+            .collect();
+        assert_eq!(
+            u16::from_be_bytes([response[1], response[2]]) as usize,
+            units.len()
+        );
+        let text = String::from_utf16(&units).unwrap();
+        let fields: Vec<&str> = text.split('\0').collect();
+        assert_eq!(
+            fields,
+            ["§1", "127", "Loadbalancer", "A test server", "3", "100"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_survives_closing_after_a_1_6_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        // 0xFE 0x01, then the MC|PingHost plugin message.
+        let mut ping = vec![0xFE, 0x01, 0xFA, 0x00, 0x0B];
+        for unit in "MC|PingHost".encode_utf16() {
+            ping.extend_from_slice(&unit.to_be_bytes());
+        }
+        let host: Vec<u16> = "localhost".encode_utf16().collect();
+        ping.extend_from_slice(&(7 + 2 * host.len() as u16).to_be_bytes());
+        ping.push(78);
+        ping.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        for unit in host {
+            ping.extend_from_slice(&unit.to_be_bytes());
+        }
+        ping.extend_from_slice(&25565i32.to_be_bytes());
+        client.write_all(&ping).await.unwrap();
+
+        assert!(is_legacy_ping(&stream).await.unwrap());
+        respond(&mut stream, "motd", 0, 100).await.unwrap();
+        drop(stream);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, kick_packet("motd", 0, 100));
+    }
+
+    #[tokio::test]
+    async fn test_modern_handshake_is_not_legacy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+
+        // The length prefix of a handshake packet.
+        client.write_all(&[0x10, 0x00]).await.unwrap();
+        assert!(!is_legacy_ping(&stream).await.unwrap());
+    }
+}
@@ -0,0 +1,54 @@
+//! A load balancer for Minecraft servers. Players are sent to a backend
+//! picked by a `finder::ServerFinder`, chosen by the config's `mode`.
+//!
+//! The binary wires these modules together, but they can also be used on
+//! their own, for example to pick backends with this crate's finders from
+//! another application:
+//!
+//! ```
+//! use loadbalancer_rs::config::Config;
+//! use loadbalancer_rs::finder::{ServerFinder, get_server_finder};
+//!
+//! let config = Config::from_yaml_str(
+//!     r#"
+//! mode: static
+//! static:
+//!   algorithm: round_robin
+//!   servers:
+//!     - address: "eu.example.com"
+//!     - address: "us.example.com"
+//! "#,
+//! )
+//! .unwrap();
+//!
+//! let finder = get_server_finder(config).unwrap();
+//! let addresses: Vec<String> = finder
+//!     .backends()
+//!     .into_iter()
+//!     .map(|server| server.address)
+//!     .collect();
+//! assert_eq!(addresses, ["eu.example.com", "us.example.com"]);
+//! ```
+
+pub mod access_log;
+pub mod config;
+pub mod connection;
+pub mod finder;
+pub mod backend;
+pub mod status;
+pub mod address_resolver;
+pub mod metrics;
+pub mod proxy_protocol;
+pub mod admin;
+pub mod legacy_ping;
+pub mod maintenance;
+pub mod health;
+pub mod query;
+pub mod cli;
+pub mod packet_limit;
+pub mod self_test;
+pub mod reload;
+pub mod geo_api;
+mod http_server;
+#[cfg(test)]
+mod test_support;
@@ -1,58 +1,376 @@
-pub mod config;
-pub mod connection;
-pub mod finder;
-pub mod backend;
-pub mod status;
-pub mod address_resolver;
-mod geo_api;
-
-use log::info;
+use clap::Parser;
+use futures::future::select_all;
+use log::{error, info, warn};
 use std::error::Error;
 use std::fs::write;
-use std::path::Path;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc};
-use tokio::net::TcpListener;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
-use crate::config::Config;
-use crate::connection::Connection;
-use crate::finder::ServerFinder;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use loadbalancer_rs::backend::MinecraftServer;
+use loadbalancer_rs::config::Config;
+use loadbalancer_rs::connection::Connection;
+use loadbalancer_rs::finder::ServerFinder;
+use loadbalancer_rs::maintenance::Maintenance;
+use loadbalancer_rs::{
+    address_resolver, admin, cli, finder, health, legacy_ping, metrics, proxy_protocol, reload,
+    self_test, status,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    simple_logger::init_with_level(log::Level::Info).unwrap();
+    let args = cli::Args::parse();
 
-    let config_path = "config.yaml";
-    if !Path::new(config_path).exists() {
-        // Write the default configuration to the file
-        write(config_path, Config::default_config_str())?;
+    // Also picks up the `log` records of modules not using `tracing` yet.
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    // An explicit --config is used as is. Otherwise the first config file
+    // found is used, in this order.
+    let config_path = args.config.clone().or_else(|| {
+        ["config.yaml", "config.toml", "config.json"]
+            .into_iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+    });
+    let config_path = match config_path {
+        Some(path) => path,
+        None => {
+            // Write the default configuration to the file
+            write("config.yaml", Config::default_config_str())?;
+            info!("No config file found, wrote the default configuration to config.yaml");
+            PathBuf::from("config.yaml")
+        }
+    };
+    info!("Loading config from {}", config_path.display());
+    // A config that exists but can't be loaded is left alone: overwriting it
+    // with the default would throw away the operator's settings.
+    let config = match Config::from_file(&config_path) {
+        Ok(config) => config,
+        Err(error) => {
+            error!("Could not load {}: {}", config_path.display(), error);
+            error!("Fix the file and restart; it has not been modified.");
+            std::process::exit(1);
+        }
+    };
+
+    let motd = config.status_motd();
+    address_resolver::init(&config.dns.clone().unwrap_or_default())?;
+
+    if args.check {
+        let ok = cli::check(config, args.strict).await;
+        std::process::exit(if ok { 0 } else { 1 });
     }
-    let config = Config::from_yaml_file(Path::new("config.yaml"))?;
 
-    let motd = config.motd.clone();
-    let server_finder: Arc<Mutex<Box<dyn ServerFinder>>> = Arc::new(Mutex::new(finder::get_server_finder(config)?));
+    if let Some(metrics_cfg) = config.metrics.as_ref().filter(|m| m.enabled) {
+        let metrics_listener = TcpListener::bind(&metrics_cfg.bind).await?;
+        info!("Serving metrics on {}", metrics_cfg.bind);
+        tokio::spawn(metrics::serve(metrics_listener));
+    }
 
-    let listener = TcpListener::bind("0.0.0.0:25565").await?;
-    let status_cache = Arc::new(Mutex::new(status::StatusCache::new()));
+    let server_finder: Arc<Mutex<Box<dyn ServerFinder>>> = Arc::new(Mutex::new(finder::get_server_finder(config.clone())?));
+    let maintenance = Arc::new(Maintenance::from_config(&config));
+    if config.startup_self_test {
+        let servers = self_test::backends(&config, &**server_finder.lock().await);
+        tokio::spawn(log_self_test(servers, config.poll_concurrency()));
+    }
+    let config = Arc::new(config);
 
-    loop {
-        let (stream, addr) = listener.accept().await?;
+    if let Some(admin_cfg) = config.admin.as_ref().filter(|a| a.enabled) {
+        let admin_listener = TcpListener::bind(&admin_cfg.bind).await?;
+        info!("Serving admin API on {}", admin_cfg.bind);
+        tokio::spawn(admin::serve(admin_listener, server_finder.clone(), maintenance.clone(), admin_cfg.token.clone()));
+    }
+
+    if let Some(health_cfg) = config.health.as_ref().filter(|h| h.enabled) {
+        let health_listener = TcpListener::bind(&health_cfg.bind).await?;
+        info!("Serving health probes on {}", health_cfg.bind);
+        tokio::spawn(health::serve(health_listener, server_finder.clone(), config.player_count_source));
+    }
+
+    let mut listeners = Vec::new();
+    for address in args.listen_addresses(&config) {
+        listeners.push(TcpListener::bind(&address).await?);
+        info!("Listening on {}", address);
+    }
+    let status_cache = Arc::new(Mutex::new(status::StatusCache::from_config(&config, maintenance.clone())));
+    if config.status_warmup {
+        status::spawn_warmup(status_cache.clone(), server_finder.clone()).await;
+    }
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutdown signal received, no longer accepting connections");
+            shutdown.cancel();
+        }
+    });
+
+    tokio::spawn({
+        let server_finder = server_finder.clone();
+        async move {
+            let mut interval = tokio::time::interval(STATE_SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                save_finder_state(&server_finder).await;
+            }
+        }
+    });
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(config_path, server_finder.clone()));
+
+    let tracker = TaskTracker::new();
+    accept_connections(&listeners, &shutdown, |stream, addr| {
         let server_finder = server_finder.clone();
 
         let status_cache = status_cache.clone();
+        let maintenance = maintenance.clone();
         let motd = motd.clone();
+        let config = config.clone();
+        let shutdown = shutdown.clone();
+
+        tracker.spawn(async move {
+            let mut stream = stream;
+            let mut addr = addr;
+            if config.accept_proxy_protocol {
+                match timeout(config.handshake_timeout(), proxy_protocol::read_header(&mut stream)).await {
+                    Ok(Ok(Some(client_addr))) => addr = client_addr,
+                    Ok(Ok(None)) => {}
+                    Ok(Err(error)) => {
+                        info!("Rejected connection from {}: {}", addr, error);
+                        return;
+                    }
+                    Err(_) => {
+                        info!("Dropped connection from {}, no PROXY header in time", addr);
+                        return;
+                    }
+                }
+            }
+
+            if !config.is_ip_allowed(addr.ip()) {
+                info!("Dropped connection from blocked address {}", addr);
+                return;
+            }
+
+            let is_legacy_ping = match timeout(config.handshake_timeout(), legacy_ping::is_legacy_ping(&stream)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    info!("Dropped connection from {}, nothing sent in time", addr);
+                    return;
+                }
+            };
+            match is_legacy_ping {
+                Ok(false) => {}
+                Ok(true) => {
+                    status::refresh_player_count(&status_cache, &server_finder).await;
+                    let (online, max_players) = {
+                        let status_cache = status_cache.lock().await;
+                        (status_cache.player_count().unwrap_or(0), status_cache.max_players())
+                    };
+                    let motd = if maintenance.is_enabled() { &maintenance.motd } else { &motd };
+                    if let Err(error) = legacy_ping::respond(&mut stream, motd, online, max_players).await {
+                        info!("Failed to answer legacy ping from {}: {}", addr, error);
+                    }
+                    return;
+                }
+                Err(error) => {
+                    info!("Failed to read from {}: {}", addr, error);
+                    return;
+                }
+            }
 
-        tokio::spawn(async move {
             let (read, write) = stream.into_split();
             info!("Accepted connection from {}", addr);
 
-            let mut connection = Connection::new(read, write, server_finder, status_cache, addr, motd.clone());
+            let mut connection = Connection::new(read, write, server_finder, status_cache, maintenance, addr, config.clone());
 
             loop {
-                if !connection.process_packets().await {
-                    info!("Connection terminated");
-                    break;
+                tokio::select! {
+                    keep_going = connection.process_packets() => {
+                        if !keep_going {
+                            connection.span().in_scope(|| info!("Connection terminated"));
+                            break;
+                        }
+                    }
+                    _ = shutdown.cancelled(), if config.disconnect_on_shutdown => {
+                        connection.disconnect_for_shutdown().await;
+                        break;
+                    }
                 }
             }
         });
+    })
+    .await?;
+
+    tracker.close();
+    info!("Waiting for {} connections to finish", tracker.len());
+    if timeout(config.shutdown_grace_period(), tracker.wait()).await.is_err() {
+        info!("Shutdown grace period elapsed, dropping remaining connections");
+    }
+    save_finder_state(&server_finder).await;
+    server_finder.lock().await.close();
+    info!("Shutdown complete");
+    Ok(())
+}
+
+// Only logs: a backend that is down at startup may well come up later.
+async fn log_self_test(servers: Vec<MinecraftServer>, concurrency: usize) {
+    let report = self_test::run(servers, concurrency).await;
+    for line in report.summary() {
+        if report.all_reachable() {
+            info!("{}", line);
+        } else {
+            warn!("{}", line);
+        }
+    }
+}
+
+// Swaps in the backends of the config file on SIGHUP. Only the finder is
+// reloaded; every other setting still takes a restart.
+#[cfg(unix)]
+async fn reload_on_hangup(config_path: PathBuf, server_finder: Arc<Mutex<Box<dyn ServerFinder>>>) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to install SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        info!("Reloading backends from {}", config_path.display());
+        let config = match Config::from_file(&config_path) {
+            Ok(config) => config,
+            Err(error) => {
+                error!("Could not reload {}, keeping the current backends: {}", config_path.display(), error);
+                continue;
+            }
+        };
+        let new_finder = match finder::get_server_finder(config) {
+            Ok(new_finder) => new_finder,
+            Err(error) => {
+                error!("Could not set up the reloaded backends, keeping the current ones: {}", error);
+                continue;
+            }
+        };
+        let removed = reload::swap_finder(&server_finder, new_finder).await;
+        tokio::spawn(reload::report_drain(removed));
+    }
+}
+
+// How often the finder's state is written out, on top of the write at shutdown.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+async fn save_finder_state(server_finder: &Mutex<Box<dyn ServerFinder>>) {
+    if let Err(error) = server_finder.lock().await.save_state() {
+        warn!("Failed to save server finder state: {}", error);
+    }
+}
+
+/// Accepts connections on every listener until `shutdown` is cancelled,
+/// handing each one to `handle`. IPv4 clients of a dual-stack listener are
+/// reported with their plain IPv4 address rather than the mapped IPv6 form.
+async fn accept_connections<F>(
+    listeners: &[TcpListener],
+    shutdown: &CancellationToken,
+    mut handle: F,
+) -> std::io::Result<()>
+where
+    F: FnMut(TcpStream, SocketAddr),
+{
+    loop {
+        tokio::select! {
+            (accepted, _, _) = select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))) => {
+                let (stream, mut addr) = accepted?;
+                addr.set_ip(addr.ip().to_canonical());
+                handle(stream, addr);
+            }
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("Failed to install SIGTERM handler");
+        terminate.recv().await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_accept_loop_exits_on_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+
+        let (accepted_tx, mut accepted_rx) = tokio::sync::mpsc::unbounded_channel();
+        let accept_loop = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                accept_connections(&[listener], &shutdown, |_, addr| {
+                    accepted_tx.send(addr).unwrap();
+                })
+                .await
+            }
+        });
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        assert!(accepted_rx.recv().await.is_some());
+
+        shutdown.cancel();
+        let result = timeout(Duration::from_secs(1), accept_loop).await;
+        assert!(result.unwrap().unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_accepts_on_ipv4_and_ipv6_listeners() {
+        let listeners = vec![
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("[::1]:0").await.unwrap(),
+        ];
+        let addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let shutdown = CancellationToken::new();
+
+        let (accepted_tx, mut accepted_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                accept_connections(&listeners, &shutdown, |_, addr| {
+                    accepted_tx.send(addr).unwrap();
+                })
+                .await
+            }
+        });
+
+        let _v6_client = TcpStream::connect(addrs[1]).await.unwrap();
+        let peer = accepted_rx.recv().await.unwrap();
+        assert!(peer.is_ipv6());
+        assert!(peer.ip().is_loopback());
+
+        let _v4_client = TcpStream::connect(addrs[0]).await.unwrap();
+        let peer = accepted_rx.recv().await.unwrap();
+        assert!(peer.is_ipv4());
+
+        shutdown.cancel();
     }
 }
@@ -0,0 +1,46 @@
+//! Maintenance mode: while enabled, the server list shows a maintenance motd
+//! and every player trying to join is disconnected with a message.
+
+use crate::config::Config;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Shared between the status cache, every connection and the admin API, so
+/// toggling it takes effect immediately.
+#[derive(Debug)]
+pub struct Maintenance {
+    enabled: AtomicBool,
+    pub motd: String,
+    pub message: String,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance {
+            enabled: AtomicBool::new(false),
+            motd: "Under maintenance".to_string(),
+            message: "The server is under maintenance, please try again later.".to_string(),
+        }
+    }
+}
+
+impl Maintenance {
+    pub fn from_config(config: &Config) -> Self {
+        let defaults = Self::default();
+        let Some(maintenance) = &config.maintenance else {
+            return defaults;
+        };
+        Maintenance {
+            enabled: AtomicBool::new(maintenance.enabled),
+            motd: maintenance.motd.clone().unwrap_or(defaults.motd),
+            message: maintenance.message.clone().unwrap_or(defaults.message),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
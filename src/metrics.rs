@@ -0,0 +1,245 @@
+use crate::http_server::{self, Response};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+#[derive(Debug, Default)]
+struct BackendMetrics {
+    selections: u64,
+    up: bool,
+}
+
+/// How the geo cache answered lookups since startup.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GeoCacheCounts {
+    pub hits: u64,
+    // Every miss is a request to the provider's API.
+    pub misses: u64,
+    pub api_errors: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections: AtomicUsize,
+    transfers: AtomicU64,
+    poll_latency_micros: AtomicU64,
+    geo_cache_hits: AtomicU64,
+    geo_cache_misses: AtomicU64,
+    geo_api_errors: AtomicU64,
+    backends: Mutex<BTreeMap<String, BackendMetrics>>,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Relaxed);
+    }
+
+    pub fn record_transfer(&self) {
+        self.transfers.fetch_add(1, Relaxed);
+    }
+
+    pub fn record_selection(&self, backend: &str) {
+        let mut backends = self.backends.lock().unwrap();
+        backends.entry(backend.to_string()).or_default().selections += 1;
+    }
+
+    pub fn set_backend_up(&self, backend: &str, up: bool) {
+        let mut backends = self.backends.lock().unwrap();
+        backends.entry(backend.to_string()).or_default().up = up;
+    }
+
+    /// Drops the series of a backend its finder no longer has.
+    pub fn forget_backend(&self, backend: &str) {
+        self.backends.lock().unwrap().remove(backend);
+    }
+
+    pub fn record_poll_latency(&self, latency: Duration) {
+        self.poll_latency_micros
+            .store(latency.as_micros() as u64, Relaxed);
+    }
+
+    pub fn record_geo_cache_hit(&self) {
+        self.geo_cache_hits.fetch_add(1, Relaxed);
+    }
+
+    pub fn record_geo_cache_miss(&self) {
+        self.geo_cache_misses.fetch_add(1, Relaxed);
+    }
+
+    pub fn record_geo_api_error(&self) {
+        self.geo_api_errors.fetch_add(1, Relaxed);
+    }
+
+    pub fn geo_cache_counts(&self) -> GeoCacheCounts {
+        GeoCacheCounts {
+            hits: self.geo_cache_hits.load(Relaxed),
+            misses: self.geo_cache_misses.load(Relaxed),
+            api_errors: self.geo_api_errors.load(Relaxed),
+        }
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "lb_connections",
+            "gauge",
+            "Connections currently being handled.",
+        );
+        writeln!(out, "lb_connections {}", self.connections.load(Relaxed)).unwrap();
+
+        write_header(
+            &mut out,
+            "lb_transfers_total",
+            "counter",
+            "Players transferred to a backend.",
+        );
+        writeln!(out, "lb_transfers_total {}", self.transfers.load(Relaxed)).unwrap();
+
+        write_header(
+            &mut out,
+            "lb_player_count_poll_seconds",
+            "gauge",
+            "Duration of the last player count poll.",
+        );
+        let latency = self.poll_latency_micros.load(Relaxed) as f64 / 1_000_000.0;
+        writeln!(out, "lb_player_count_poll_seconds {}", latency).unwrap();
+
+        let geo = self.geo_cache_counts();
+        for (name, help, value) in [
+            (
+                "lb_geo_cache_hits_total",
+                "Geo lookups answered from the cache.",
+                geo.hits,
+            ),
+            (
+                "lb_geo_cache_misses_total",
+                "Geo lookups sent to the provider's API.",
+                geo.misses,
+            ),
+            (
+                "lb_geo_api_errors_total",
+                "Geo lookups the provider's API failed to answer.",
+                geo.api_errors,
+            ),
+        ] {
+            write_header(&mut out, name, "counter", help);
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+
+        let backends = self.backends.lock().unwrap();
+
+        write_header(
+            &mut out,
+            "lb_backend_selections_total",
+            "counter",
+            "Times a backend was selected for a player.",
+        );
+        for (address, backend) in backends.iter() {
+            writeln!(
+                out,
+                "lb_backend_selections_total{{backend=\"{}\"}} {}",
+                escape_label(address),
+                backend.selections
+            )
+            .unwrap();
+        }
+
+        write_header(
+            &mut out,
+            "lb_backend_up",
+            "gauge",
+            "Whether the last poll of a backend succeeded.",
+        );
+        for (address, backend) in backends.iter() {
+            writeln!(
+                out,
+                "lb_backend_up{{backend=\"{}\"}} {}",
+                escape_label(address),
+                backend.up as u8
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves the metrics over plain HTTP. Every request path returns the same page.
+pub async fn serve(listener: TcpListener) -> std::io::Result<()> {
+    http_server::serve(listener, "metrics", |_request| async {
+        Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body: metrics().render(),
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_scrape_metrics_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener));
+
+        metrics().record_selection("a.example.com");
+        metrics().set_backend_up("a.example.com", true);
+
+        let body = reqwest::get(format!("http://{}/metrics", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+
+        for name in [
+            "lb_connections",
+            "lb_transfers_total",
+            "lb_player_count_poll_seconds",
+            "lb_geo_cache_hits_total",
+            "lb_geo_cache_misses_total",
+            "lb_geo_api_errors_total",
+            "lb_backend_selections_total{backend=\"a.example.com\"}",
+            "lb_backend_up{backend=\"a.example.com\"} 1",
+        ] {
+            assert!(body.contains(name), "missing {} in:\n{}", name, body);
+        }
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
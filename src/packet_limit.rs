@@ -0,0 +1,138 @@
+//! Caps the size of the packets a client may send. The decoder trusts the
+//! length prefix of every packet, so a client could otherwise claim one of
+//! several gigabytes and have it buffered.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, ReadBuf};
+
+// A VarInt never takes more than five bytes.
+const MAX_LENGTH_BYTES: u32 = 5;
+
+/// Wraps the client's side of the connection, following the packet framing
+/// as bytes pass through to the decoder. A read fails as soon as a length
+/// prefix over `max_packet_size` arrives, before the packet itself is read.
+pub struct PacketSizeLimit<R> {
+    inner: R,
+    max_packet_size: usize,
+    // The length prefix being read, and how many of its bytes were seen.
+    length: usize,
+    length_bytes: u32,
+    // Bytes of the current packet still to come after its length prefix.
+    remaining: usize,
+}
+
+impl<R> PacketSizeLimit<R> {
+    pub fn new(inner: R, max_packet_size: usize) -> Self {
+        PacketSizeLimit {
+            inner,
+            max_packet_size,
+            length: 0,
+            length_bytes: 0,
+            remaining: 0,
+        }
+    }
+
+    // Advances the framing over `bytes`, which may end partway through a
+    // length prefix or a packet.
+    fn track(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let skipped = self.remaining.min(bytes.len());
+                self.remaining -= skipped;
+                bytes = &bytes[skipped..];
+                continue;
+            }
+
+            let byte = bytes[0];
+            bytes = &bytes[1..];
+            self.length |= ((byte & 0x7F) as usize) << (7 * self.length_bytes);
+            self.length_bytes += 1;
+            if byte & 0x80 != 0 {
+                if self.length_bytes == MAX_LENGTH_BYTES {
+                    return Err(invalid_data(
+                        "packet length VarInt is longer than 5 bytes".to_string(),
+                    ));
+                }
+                continue;
+            }
+
+            if self.length > self.max_packet_size {
+                return Err(invalid_data(format!(
+                    "packet of {} bytes is over the {} byte limit",
+                    self.length, self.max_packet_size
+                )));
+            }
+            self.remaining = self.length;
+            self.length = 0;
+            self.length_bytes = 0;
+        }
+        Ok(())
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for PacketSizeLimit<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let already_filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.track(&buf.filled()[already_filled..])?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_packets_within_the_limit_pass_through() {
+        // A 3 byte packet, an empty one, then a 200 byte one.
+        let mut stream = vec![3, 1, 2, 3, 0, 0xC8, 0x01];
+        stream.extend([7; 200]);
+
+        let mut read = Vec::new();
+        PacketSizeLimit::new(&stream[..], 200)
+            .read_to_end(&mut read)
+            .await
+            .unwrap();
+        assert_eq!(read, stream);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_packet_is_rejected() {
+        // One GiB, in a single length prefix.
+        let stream = [0x80, 0x80, 0x80, 0x80, 0x04];
+
+        let error = PacketSizeLimit::new(&stream[..], 1024)
+            .read_to_end(&mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("1073741824 bytes"), "{}", error);
+    }
+
+    #[test]
+    fn test_framing_is_followed_across_reads() {
+        let mut limit = PacketSizeLimit::new((), 300);
+
+        // A 300 byte packet whose length prefix and body are split up.
+        limit.track(&[0xAC]).unwrap();
+        limit.track(&[0x02, 1, 2]).unwrap();
+        limit.track(&[0; 298]).unwrap();
+        assert_eq!(limit.remaining, 0);
+
+        // The next prefix claims a byte more than allowed.
+        limit.track(&[0xAD]).unwrap();
+        assert!(limit.track(&[0x02]).is_err());
+    }
+}
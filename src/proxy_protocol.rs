@@ -0,0 +1,320 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyHeaderError {
+    #[error("IO error reading PROXY header: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Malformed PROXY header: {0}")]
+    Malformed(&'static str),
+}
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// The longest possible v1 line, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+// Version 2, LOCAL and PROXY commands.
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+// Address family in the high nibble, STREAM transport in the low nibble.
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// Builds a binary PROXY protocol v2 header describing a TCP connection from
+/// `source` to `destination`. Mixed address families are sent as IPv6, with
+/// the IPv4 side mapped into `::ffff:0:0/96`.
+pub fn encode_v2(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = Vec::with_capacity(52);
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push(V2_PROXY);
+
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            header.push(V2_TCP4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src.octets());
+            header.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            header.push(V2_TCP6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_ipv6(src).octets());
+            header.extend_from_slice(&to_ipv6(dst).octets());
+        }
+    }
+
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+/// Reads a v1 or v2 PROXY protocol header off the start of a connection,
+/// consuming nothing past it. Returns the original client address, or `None`
+/// when the sender declared the connection as its own (LOCAL or UNKNOWN).
+pub async fn read_header<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut prefix = [0u8; 6];
+    reader.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        return read_v1(reader).await;
+    }
+    if prefix != V2_SIGNATURE[..6] {
+        return Err(ProxyHeaderError::Malformed("missing PROXY signature"));
+    }
+
+    let mut rest = [0u8; 6];
+    reader.read_exact(&mut rest).await?;
+    if rest != V2_SIGNATURE[6..] {
+        return Err(ProxyHeaderError::Malformed("missing PROXY signature"));
+    }
+    read_v2(reader).await
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    loop {
+        if V1_PREFIX.len() + line.len() >= V1_MAX_LENGTH {
+            return Err(ProxyHeaderError::Malformed("v1 header too long"));
+        }
+        line.push(reader.read_u8().await?);
+        if line.ends_with(b"\r\n") {
+            line.truncate(line.len() - 2);
+            break;
+        }
+    }
+
+    let line = std::str::from_utf8(&line)
+        .map_err(|_| ProxyHeaderError::Malformed("v1 header is not ASCII"))?;
+    let mut fields = line.split(' ');
+
+    let family = fields.next();
+    if family == Some("UNKNOWN") {
+        return Ok(None);
+    }
+
+    let (Some(source), Some(_destination), Some(source_port), Some(_destination_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(ProxyHeaderError::Malformed(
+            "v1 header has the wrong number of fields",
+        ));
+    };
+
+    let ip: IpAddr = source
+        .parse()
+        .map_err(|_| ProxyHeaderError::Malformed("invalid v1 source address"))?;
+    let port: u16 = source_port
+        .parse()
+        .map_err(|_| ProxyHeaderError::Malformed("invalid v1 source port"))?;
+
+    match (family, ip) {
+        (Some("TCP4"), IpAddr::V4(_)) | (Some("TCP6"), IpAddr::V6(_)) => {
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(ProxyHeaderError::Malformed(
+            "v1 address does not match its family",
+        )),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<SocketAddr>, ProxyHeaderError> {
+    let command = reader.read_u8().await?;
+    let family = reader.read_u8().await?;
+    let length = reader.read_u16().await? as usize;
+
+    let mut addresses = vec![0u8; length];
+    reader.read_exact(&mut addresses).await?;
+
+    match command {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => {
+            return Err(ProxyHeaderError::Malformed(
+                "unsupported v2 version or command",
+            ));
+        }
+    }
+
+    match family {
+        V2_TCP4 => {
+            if length < 12 {
+                return Err(ProxyHeaderError::Malformed(
+                    "v2 IPv4 address block too short",
+                ));
+            }
+            let ip: [u8; 4] = addresses[0..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        V2_TCP6 => {
+            if length < 36 {
+                return Err(ProxyHeaderError::Malformed(
+                    "v2 IPv6 address block too short",
+                ));
+            }
+            let ip: [u8; 16] = addresses[0..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // UNSPEC, UDP and UNIX sockets carry no usable client address.
+        _ => Ok(None),
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_v2_ipv4() {
+        let source: SocketAddr = "192.0.2.1:51000".parse().unwrap();
+        let destination: SocketAddr = "198.51.100.2:25565".parse().unwrap();
+
+        let header = encode_v2(source, destination);
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 0x0C]);
+        expected.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 2]);
+        expected.extend_from_slice(&[0xC7, 0x38, 0x63, 0xDD]);
+        assert_eq!(header, expected);
+        assert_eq!(header.len(), 28);
+    }
+
+    #[test]
+    fn test_encode_v2_ipv6() {
+        let source: SocketAddr = "[2001:db8::1]:51000".parse().unwrap();
+        let destination: SocketAddr = "[2001:db8::2]:25565".parse().unwrap();
+
+        let header = encode_v2(source, destination);
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x21, 0x00, 0x24]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0D, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0D, 0xB8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+        expected.extend_from_slice(&[0xC7, 0x38, 0x63, 0xDD]);
+        assert_eq!(header, expected);
+        assert_eq!(header.len(), 52);
+    }
+
+    #[test]
+    fn test_encode_v2_mixed_families() {
+        let source: SocketAddr = "192.0.2.1:51000".parse().unwrap();
+        let destination: SocketAddr = "[2001:db8::2]:25565".parse().unwrap();
+
+        let header = encode_v2(source, destination);
+
+        assert_eq!(header[13], V2_TCP6);
+        assert_eq!(
+            &header[16..32],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 192, 0, 2, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_ipv4() {
+        let mut input: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 25565\r\n\x10";
+
+        let addr = read_header(&mut input).await.unwrap();
+
+        assert_eq!(addr, Some("192.0.2.1:51000".parse().unwrap()));
+        assert_eq!(input, b"\x10");
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_ipv6() {
+        let mut input: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 51000 25565\r\n";
+
+        let addr = read_header(&mut input).await.unwrap();
+
+        assert_eq!(addr, Some("[2001:db8::1]:51000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_unknown() {
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+
+        assert_eq!(read_header(&mut input).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_ipv4() {
+        let source: SocketAddr = "192.0.2.1:51000".parse().unwrap();
+        let destination: SocketAddr = "198.51.100.2:25565".parse().unwrap();
+        let mut header = encode_v2(source, destination);
+        header.push(0x10);
+        let mut input = &header[..];
+
+        let addr = read_header(&mut input).await.unwrap();
+
+        assert_eq!(addr, Some(source));
+        assert_eq!(input, b"\x10");
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_ipv6() {
+        let source: SocketAddr = "[2001:db8::1]:51000".parse().unwrap();
+        let destination: SocketAddr = "[2001:db8::2]:25565".parse().unwrap();
+        let header = encode_v2(source, destination);
+
+        let addr = read_header(&mut &header[..]).await.unwrap();
+
+        assert_eq!(addr, Some(source));
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_local() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[V2_LOCAL, 0x00, 0x00, 0x00]);
+
+        assert_eq!(read_header(&mut &header[..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_rejects_malformed_headers() {
+        let inputs: [&[u8]; 3] = [
+            b"\x10\x00\xfa\x05\x09localhost",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2\r\n",
+            b"PROXY TCP6 192.0.2.1 198.51.100.2 51000 25565\r\n",
+        ];
+
+        for input in inputs {
+            let result = read_header(&mut &input[..]).await;
+            assert!(
+                matches!(result, Err(ProxyHeaderError::Malformed(_))),
+                "{:?}",
+                result
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_rejects_unterminated_line() {
+        let mut input = b"PROXY TCP4 ".to_vec();
+        input.resize(200, b'1');
+
+        let result = read_header(&mut &input[..]).await;
+
+        assert!(matches!(result, Err(ProxyHeaderError::Malformed(_))));
+    }
+}
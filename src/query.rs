@@ -0,0 +1,119 @@
+//! Reads a backend's player count over the GameSpy4 Query protocol, which
+//! vanilla servers answer on UDP when `enable-query` is set. Useful where
+//! status pings are throttled or firewalled.
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const MAGIC: [u8; 2] = [0xFE, 0xFD];
+const HANDSHAKE: u8 = 0x09;
+const STAT: u8 = 0x00;
+// Servers only keep the low four bits of each byte, so every byte stays below 0x10.
+const SESSION_ID: u32 = 0x0102_0304;
+
+/// Asks the query server at `addr` for its online player count: a handshake
+/// for a challenge token, then a basic stat request carrying it. Each reply
+/// is waited on for at most `reply_timeout`, since UDP requests can be lost.
+pub async fn player_count(
+    addr: SocketAddr,
+    reply_timeout: Duration,
+) -> Result<u32, Box<dyn Error>> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+    let mut buffer = [0u8; 1500];
+
+    socket.send(&request(HANDSHAKE, &[])).await?;
+    let read = receive(&socket, &mut buffer, reply_timeout).await?;
+    let token = parse_challenge(&buffer[..read])?;
+
+    socket.send(&request(STAT, &token.to_be_bytes())).await?;
+    let read = receive(&socket, &mut buffer, reply_timeout).await?;
+    parse_basic_stat(&buffer[..read])
+}
+
+async fn receive(
+    socket: &UdpSocket,
+    buffer: &mut [u8],
+    reply_timeout: Duration,
+) -> Result<usize, Box<dyn Error>> {
+    match tokio::time::timeout(reply_timeout, socket.recv(buffer)).await {
+        Ok(read) => Ok(read?),
+        Err(_) => Err(format!("No query reply within {:?}", reply_timeout).into()),
+    }
+}
+
+fn request(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut packet = MAGIC.to_vec();
+    packet.push(kind);
+    packet.extend_from_slice(&SESSION_ID.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+// Checks a reply's type and session id, returning the rest of it.
+fn reply_body(packet: &[u8], kind: u8) -> Result<&[u8], Box<dyn Error>> {
+    if packet.len() < 5 || packet[0] != kind || packet[1..5] != SESSION_ID.to_be_bytes() {
+        return Err(format!("Unexpected query reply of {} bytes", packet.len()).into());
+    }
+    Ok(&packet[5..])
+}
+
+// The token is sent back as a NUL-terminated decimal string.
+fn parse_challenge(packet: &[u8]) -> Result<i32, Box<dyn Error>> {
+    let body = reply_body(packet, HANDSHAKE)?;
+    let token = body.split(|byte| *byte == 0).next().unwrap_or_default();
+    Ok(std::str::from_utf8(token)?.parse()?)
+}
+
+// A basic stat reply is the motd, game type, map, online and max players,
+// each NUL-terminated, followed by the port and address.
+fn parse_basic_stat(packet: &[u8]) -> Result<u32, Box<dyn Error>> {
+    let body = reply_body(packet, STAT)?;
+    let online = body
+        .split(|byte| *byte == 0)
+        .nth(3)
+        .ok_or("Query reply has no player count")?;
+    Ok(std::str::from_utf8(online)?.parse()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::spawn_query_backend;
+
+    #[tokio::test]
+    async fn test_player_count_after_challenge() {
+        let addr = spawn_query_backend(7).await;
+
+        let online = player_count(addr, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(online, 7);
+    }
+
+    #[tokio::test]
+    async fn test_silent_backend_times_out() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let result = player_count(addr, Duration::from_millis(100)).await;
+        assert!(result.unwrap_err().to_string().contains("No query reply"));
+    }
+
+    #[test]
+    fn test_reply_for_another_session_is_rejected() {
+        let mut reply = vec![HANDSHAKE, 0, 0, 0, 9];
+        reply.extend_from_slice(b"9513307\0");
+        assert!(parse_challenge(&reply).is_err());
+
+        let mut reply = vec![HANDSHAKE];
+        reply.extend_from_slice(&SESSION_ID.to_be_bytes());
+        reply.extend_from_slice(b"-9513307\0");
+        assert_eq!(parse_challenge(&reply).unwrap(), -9513307);
+    }
+}
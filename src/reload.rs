@@ -0,0 +1,171 @@
+//! Swaps in the server finder of a reloaded config, and tells operators when
+//! the backends it dropped have no players left on them.
+
+use crate::backend::MinecraftServer;
+use crate::finder::ServerFinder;
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+// How often removed backends are checked for sessions still routed to them.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Replaces the finder behind `server_finder` with `new_finder`, returning
+/// the backends the old one had and the new one doesn't. They are marked
+/// draining, since clones handed out before the reload may outlive it.
+/// Backends at addresses both have keep their sessions, drain flag and last
+/// poll across the swap.
+pub async fn swap_finder(
+    server_finder: &Mutex<Box<dyn ServerFinder>>,
+    mut new_finder: Box<dyn ServerFinder>,
+) -> Vec<MinecraftServer> {
+    let mut current = server_finder.lock().await;
+    let previous: HashMap<String, MinecraftServer> = current
+        .backends()
+        .into_iter()
+        .map(|server| (server.address.clone(), server))
+        .collect();
+    for server in new_finder.backends_mut() {
+        if let Some(previous) = previous.get(&server.address) {
+            server.share_state(previous);
+        }
+    }
+
+    let kept: HashSet<String> = new_finder
+        .backends()
+        .into_iter()
+        .map(|server| server.address)
+        .collect();
+    let removed: Vec<MinecraftServer> = current
+        .backends()
+        .into_iter()
+        .filter(|server| !kept.contains(&server.address))
+        .collect();
+    for server in &removed {
+        server.set_draining(true);
+    }
+    let old_finder = std::mem::replace(&mut *current, new_finder);
+    old_finder.close();
+    removed
+}
+
+/// Logs how many sessions each removed backend still has, then logs each
+/// one again once it has none left, at which point it is safe to shut down.
+pub async fn report_drain(mut removed: Vec<MinecraftServer>) {
+    for server in &removed {
+        info!(
+            "{} was removed from the config, {} active session(s) left",
+            server.address,
+            server.active_connections()
+        );
+    }
+
+    loop {
+        for address in take_drained(&mut removed) {
+            info!("{} is drained and can be shut down", address);
+        }
+        if removed.is_empty() {
+            return;
+        }
+        tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+    }
+}
+
+// Removes the backends without active sessions from `draining`, returning
+// their addresses.
+fn take_drained(draining: &mut Vec<MinecraftServer>) -> Vec<String> {
+    let mut drained = Vec::new();
+    draining.retain(|server| {
+        if server.active_connections() > 0 {
+            return true;
+        }
+        drained.push(server.address.clone());
+        false
+    });
+    drained
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::finder::get_server_finder;
+    use crate::test_support::{NoServerFinder, connection_pair, finder_for, test_config};
+
+    #[tokio::test]
+    async fn test_removed_backends_are_no_longer_selected_and_drain() {
+        let server_finder = Mutex::new(finder_for(&["127.0.0.1:1", "127.0.0.1:2", "127.0.0.1:3"]));
+        let _session = server_finder.lock().await.backends()[1].route_connection();
+
+        let mut removed = swap_finder(&server_finder, finder_for(&["127.0.0.1:1"])).await;
+        let addresses: Vec<&str> = removed
+            .iter()
+            .map(|server| server.address.as_str())
+            .collect();
+        assert_eq!(addresses, ["127.0.0.1:2", "127.0.0.1:3"]);
+        assert!(removed.iter().all(MinecraftServer::is_draining));
+
+        let (connection, _client) =
+            connection_pair(test_config(""), Box::new(NoServerFinder)).await;
+        for _ in 0..4 {
+            let server = server_finder
+                .lock()
+                .await
+                .find_server(&connection)
+                .await
+                .unwrap();
+            assert_eq!(server.address, "127.0.0.1:1");
+        }
+
+        // The backend with a session still open keeps draining.
+        assert_eq!(take_drained(&mut removed), ["127.0.0.1:3"]);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].active_connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_kept_backends_keep_their_state() {
+        let server_finder = Mutex::new(finder_for(&["127.0.0.1:1", "127.0.0.1:2"]));
+        let kept = server_finder.lock().await.backends()[0].clone();
+        kept.set_draining(true);
+        let _session = kept.route_connection();
+
+        swap_finder(&server_finder, finder_for(&["127.0.0.1:1", "127.0.0.1:3"])).await;
+        let reloaded = server_finder.lock().await.backends()[0].clone();
+        assert_eq!(reloaded.address, "127.0.0.1:1");
+        assert!(reloaded.is_draining());
+        assert_eq!(reloaded.active_connections(), 1);
+
+        // Both copies count the same sessions from now on.
+        let _reloaded_session = reloaded.route_connection();
+        assert_eq!(kept.active_connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_geo_finder_reloads_while_the_old_one_is_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = format!(
+            r#"
+mode: geo
+motd: test
+geo:
+  token: "abc123"
+  cache_file: "{}"
+  regions:
+    EU:
+      address: "127.0.0.1:1"
+  fallback:
+    address: "127.0.0.1:2"
+"#,
+            dir.path().join("geo.redb").display()
+        );
+        let finder = || get_server_finder(Config::from_yaml_str(&yaml).unwrap());
+        let server_finder = Mutex::new(finder().unwrap());
+
+        let removed = swap_finder(&server_finder, finder().unwrap()).await;
+        assert!(removed.is_empty());
+        // The replaced finder closed its handle, the current one still has one.
+        swap_finder(&server_finder, finder().unwrap()).await;
+    }
+}
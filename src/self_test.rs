@@ -0,0 +1,115 @@
+//! Status-pings every backend at startup, so a mistyped address shows up
+//! right away instead of when the first player is sent there.
+
+use crate::backend::{MinecraftServer, PingOptions};
+use crate::config::Config;
+use crate::finder::ServerFinder;
+use futures::{StreamExt, stream};
+
+/// Every backend `config` can send a player to: the finder's, and the lobby.
+pub fn backends(config: &Config, finder: &dyn ServerFinder) -> Vec<MinecraftServer> {
+    let mut servers = finder.backends();
+    if let Some(lobby) = &config.unrouteable_server {
+        servers.push(MinecraftServer::from_config(
+            lobby,
+            PingOptions::from_config(config),
+        ));
+    }
+    servers
+}
+
+/// The outcome of pinging each backend, in the order they were given.
+#[derive(Debug)]
+pub struct SelfTestReport {
+    // The player count each backend reported, or why it couldn't be pinged.
+    pub results: Vec<(String, Result<u32, String>)>,
+}
+
+impl SelfTestReport {
+    pub fn reachable(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, result)| result.is_ok())
+            .count()
+    }
+
+    pub fn all_reachable(&self) -> bool {
+        self.reachable() == self.results.len()
+    }
+
+    /// A headline, then a row per backend with its address padded so the
+    /// outcomes line up.
+    pub fn summary(&self) -> Vec<String> {
+        let width = self
+            .results
+            .iter()
+            .map(|(address, _)| address.len())
+            .max()
+            .unwrap_or(0);
+        let mut lines = vec![format!(
+            "Self-test: {} of {} backend(s) reachable",
+            self.reachable(),
+            self.results.len()
+        )];
+        for (address, result) in &self.results {
+            lines.push(match result {
+                Ok(online) => format!("  {:<width$}  reachable    {} online", address, online),
+                Err(error) => format!("  {:<width$}  unreachable  {}", address, error),
+            });
+        }
+        lines
+    }
+}
+
+/// Status-pings `servers`, at most `concurrency` at once.
+pub async fn run(servers: Vec<MinecraftServer>, concurrency: usize) -> SelfTestReport {
+    let results = stream::iter(servers)
+        .map(|server| async move {
+            let result = server
+                .get_player_count()
+                .await
+                .map_err(|error| error.to_string());
+            (server.address, result)
+        })
+        .buffered(concurrency)
+        .collect()
+        .await;
+    SelfTestReport { results }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::spawn_player_count_backend;
+
+    #[tokio::test]
+    async fn test_summary_lists_reachable_and_unreachable_backends() {
+        let up = spawn_player_count_backend(3).await.to_string();
+        let down = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let servers = vec![
+            MinecraftServer::new(up.clone()),
+            MinecraftServer::new(down.clone()),
+        ];
+
+        let report = run(servers, 8).await;
+        assert_eq!(report.reachable(), 1);
+        assert!(!report.all_reachable());
+
+        let summary = report.summary();
+        assert_eq!(summary[0], "Self-test: 1 of 2 backend(s) reachable");
+        let width = up.len().max(down.len());
+        assert_eq!(
+            summary[1],
+            format!("  {:<width$}  reachable    3 online", up)
+        );
+        assert!(
+            summary[2].starts_with(&format!("  {:<width$}  unreachable  ", down)),
+            "{}",
+            summary[2]
+        );
+    }
+}
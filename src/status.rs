@@ -1,69 +1,1057 @@
-use crate::finder::ServerFinder;
+use crate::backend::{MinecraftServer, PingOptions};
+use crate::config::{Config, PlayerCountSource, ProtocolMotd};
+use crate::finder::{PollBackoff, ServerFinder, StatusOverrides, poll_backends};
+use crate::maintenance::Maintenance;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use log::warn;
+use lru::LruCache;
 use pumpkin_protocol::java::client::status::CStatusResponse;
 use pumpkin_protocol::{Players, StatusResponse, Version};
-use std::collections::HashMap;
+use rand::Rng;
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::MutexGuard;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// The player limit shown in the server list when no backend reported one
+/// and none is configured.
+pub const MAX_PLAYERS: u32 = 1000;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
 
 pub struct StatusCache {
-    count: u32,
-    last_updated: Instant,
-    cache: HashMap<(String, u32, u32), String>,
+    // None when no backend answered the last poll.
+    count: Option<u32>,
+    // The sum of the limits the backends reported in the last poll, or None
+    // when none did.
+    max_players: Option<u32>,
+    // Shown when no backend reported a limit.
+    default_max_players: u32,
+    count_source: PlayerCountSource,
+    poll_interval: Duration,
+    // Most the interval is randomly lengthened or shortened by.
+    poll_jitter: Duration,
+    // Shown instead of the normal motd while every backend is down.
+    offline_motd: Option<String>,
+    // Used unless the finder picks a name for the client.
+    version_name: String,
+    // As a data URI. Used unless the finder picks one for the client.
+    favicon: Option<String>,
+    // Shown instead of the normal motd to clients in these protocol ranges.
+    protocol_motds: Vec<ProtocolMotd>,
+    // Its motd, icon and version name stand in for the configured ones.
+    primary: Option<MinecraftServer>,
+    // Most backends a poll pings at once.
+    poll_concurrency: usize,
+    maintenance: Arc<Maintenance>,
+    // None until the first poll, so the first status request always polls.
+    next_poll: Option<Instant>,
+    // When the primary backend is due to be pinged again, on the same
+    // schedule as the player count.
+    next_primary_poll: Option<Instant>,
+    // Rendered responses keyed by (motd, version name, protocol, player
+    // count, player limit, favicon). Every distinct count adds an entry, so
+    // the least recently used are evicted.
+    cache: LruCache<(String, String, u32, Option<u32>, u32, Option<String>), String>,
 }
 
-impl Default for StatusCache {
-    fn default() -> Self {
-        Self::new()
+impl StatusCache {
+    pub fn new(poll_interval: Duration, capacity: NonZeroUsize) -> Self {
+        StatusCache {
+            count: None,
+            max_players: None,
+            default_max_players: MAX_PLAYERS,
+            count_source: PlayerCountSource::Ping,
+            poll_interval,
+            poll_jitter: Duration::ZERO,
+            offline_motd: None,
+            version_name: "Loadbalancer".to_string(),
+            favicon: None,
+            protocol_motds: Vec::new(),
+            primary: None,
+            poll_concurrency: PingOptions::default().poll_concurrency,
+            maintenance: Arc::new(Maintenance::default()),
+            next_poll: None,
+            next_primary_poll: None,
+            cache: LruCache::new(capacity),
+        }
     }
-}
 
-impl StatusCache {
-    pub fn new() -> Self {
+    pub fn from_config(config: &Config, maintenance: Arc<Maintenance>) -> Self {
         StatusCache {
-            count: 0,
-            last_updated: Instant::now() - Duration::from_secs(60),
-            cache: HashMap::new(),
+            offline_motd: config.offline_motd.clone(),
+            default_max_players: config.status_max_players.unwrap_or(MAX_PLAYERS),
+            version_name: config.version_name(),
+            favicon: config.favicon.as_deref().and_then(favicon_or_warn),
+            protocol_motds: config.protocol_motds.clone(),
+            primary: config.primary_backend.as_ref().map(|server| {
+                MinecraftServer::from_config(server, PingOptions::from_config(config))
+            }),
+            count_source: config.player_count_source,
+            poll_concurrency: config.poll_concurrency(),
+            poll_jitter: config.player_count_poll_jitter(),
+            maintenance,
+            ..Self::new(
+                config.player_count_poll_interval(),
+                config.status_cache_size(),
+            )
         }
     }
 
-    pub async fn get_status_response(
+    /// The total player count from the last poll, or None when no backend
+    /// answered it. Counting transfers never pings, so it always has a count.
+    pub fn player_count(&self) -> Option<u32> {
+        self.count
+    }
+
+    /// The player limit from the last poll: the sum of what the backends
+    /// reported, or the configured default when none did.
+    pub fn max_players(&self) -> u32 {
+        self.max_players.unwrap_or(self.default_max_players)
+    }
+
+    // Whether the player count is due to be polled. The next poll is
+    // scheduled right away, so concurrent requests don't poll too.
+    fn take_due_poll(&mut self) -> bool {
+        let now = Instant::now();
+        if self.next_poll.is_some_and(|next_poll| now < next_poll) {
+            return false;
+        }
+        self.next_poll = Some(now + jittered(self.poll_interval, self.poll_jitter));
+        true
+    }
+
+    // The primary backend, if it's due to be pinged. Its next ping is
+    // scheduled right away, so concurrent requests don't ping it too.
+    fn take_due_primary(&mut self) -> Option<MinecraftServer> {
+        let primary = self.primary.as_ref()?;
+        let now = Instant::now();
+        if self
+            .next_primary_poll
+            .is_some_and(|next_poll| now < next_poll)
+        {
+            return None;
+        }
+        self.next_primary_poll = Some(now + jittered(self.poll_interval, self.poll_jitter));
+        Some(primary.clone())
+    }
+
+    /// The status response for a client that sent `client_protocol` in its
+    /// handshake, advertising `protocol`, with the finder's `overrides` for
+    /// that client. The player count is the one `refresh_player_count` last
+    /// stored.
+    pub fn get_status_response(
         &mut self,
         motd: String,
+        client_protocol: i32,
         protocol: u32,
-        server_finder: MutexGuard<'_, Box<dyn ServerFinder>>,
+        overrides: StatusOverrides,
     ) -> CStatusResponse {
-        if self.last_updated.elapsed().as_secs() > 15 {
-            self.count = server_finder.get_player_count().await;
-            self.last_updated = Instant::now();
+        // Picked here rather than when rendering, since the cache is keyed
+        // by the advertised protocol, which clamps outdated clients to the
+        // minimum.
+        let protocol_motd = self.protocol_motd(client_protocol);
+        let StatusOverrides {
+            version_name: finder_version_name,
+            motd: finder_motd,
+            favicon: finder_favicon,
+            backend: status_backend,
+        } = overrides;
+
+        // The primary backend's status, from its last ping, stands in for
+        // the configured motd, name and icon, but not for the ones the
+        // finder or a protocol range picks.
+        let mirrored = self.primary.as_ref().and_then(MinecraftServer::last_status);
+        let motd = protocol_motd
+            .or(finder_motd)
+            .or_else(|| mirrored.as_ref()?.description_text())
+            .unwrap_or(motd);
+        let version_name = finder_version_name
+            .or_else(|| mirrored.as_ref()?.version_name.clone())
+            .unwrap_or_else(|| self.version_name.clone());
+        let favicon = finder_favicon
+            .or_else(|| mirrored.as_ref()?.favicon.clone())
+            .or_else(|| self.favicon.clone());
+        let (count, max_players) = match status_backend {
+            None => (self.count, self.max_players()),
+            Some(server) => {
+                let count = match self.count_source {
+                    PlayerCountSource::Ping => server.last_player_count(),
+                    PlayerCountSource::Transfers => Some(server.active_connections()),
+                };
+                let max_players = server
+                    .last_max_players()
+                    .unwrap_or(self.default_max_players);
+                (count, max_players)
+            }
+        };
+
+        // Not cached, since maintenance can be toggled at any time.
+        if self.maintenance.is_enabled() {
+            let response = render_status(
+                maintenance_version(),
+                self.maintenance.motd.clone(),
+                count,
+                max_players,
+                favicon,
+            );
+            return CStatusResponse::new(response);
         }
 
-        if let Some(cached) = self.cache.get(&(motd.clone(), protocol, self.count)) {
+        let key = (motd, version_name, protocol, count, max_players, favicon);
+        if let Some(cached) = self.cache.get(&key) {
             return CStatusResponse::new(cached.clone());
         }
 
-        let response = self.build_status_response(motd.clone(), protocol, self.count);
-        self.cache
-            .insert((motd, protocol, self.count), response.clone());
+        let response = self.build_status_response(
+            key.0.clone(),
+            key.1.clone(),
+            protocol,
+            count,
+            max_players,
+            key.5.clone(),
+        );
+        self.cache.put(key, response.clone());
 
         CStatusResponse::new(response)
     }
 
-    fn build_status_response(&self, motd: String, protocol: u32, player_count: u32) -> String {
-        let response = StatusResponse {
-            version: Some(Version {
-                name: "Loadbalancer".to_string(),
-                protocol,
+    // The motd of the first protocol range `client_protocol` falls in.
+    fn protocol_motd(&self, client_protocol: i32) -> Option<String> {
+        self.protocol_motds
+            .iter()
+            .find(|variant| variant.matches(client_protocol))
+            .map(|variant| variant.motd.clone())
+    }
+
+    fn build_status_response(
+        &self,
+        motd: String,
+        version_name: String,
+        protocol: u32,
+        player_count: Option<u32>,
+        max_players: u32,
+        favicon: Option<String>,
+    ) -> String {
+        match (player_count, &self.offline_motd) {
+            (None, Some(offline_motd)) => render_status(
+                offline_version(),
+                offline_motd.clone(),
+                player_count,
+                max_players,
+                favicon,
+            ),
+            _ => render_status(
+                Version {
+                    name: version_name,
+                    protocol,
+                },
+                motd,
+                player_count,
+                max_players,
+                favicon,
+            ),
+        }
+    }
+}
+
+// The player count of `backends`, copied out of the finder along with its
+// `backoff` so neither cache nor finder is locked while they are pinged.
+async fn poll_player_count(
+    count_source: PlayerCountSource,
+    backends: &[MinecraftServer],
+    poll_concurrency: usize,
+    backoff: Option<&PollBackoff>,
+) -> Option<u32> {
+    match count_source {
+        PlayerCountSource::Ping => poll_backends(backends, poll_concurrency, backoff).await,
+        PlayerCountSource::Transfers => Some(routed_players(backends)),
+    }
+}
+
+// The players routed to `backends` lately, which is the count when counting
+// transfers.
+fn routed_players(backends: &[MinecraftServer]) -> u32 {
+    backends
+        .iter()
+        .map(|server| server.active_connections())
+        .sum()
+}
+
+// Pings the primary backend, whose status is kept until the next poll. While
+// it can't be reached, the configured motd, icon and version name are shown.
+async fn ping_primary(primary: Option<&MinecraftServer>) {
+    let Some(primary) = primary else {
+        return;
+    };
+    if let Err(error) = primary.get_status().await {
+        warn!(
+            "Could not ping primary backend {}: {}",
+            primary.address, error
+        );
+    }
+}
+
+// The sum of the player limits the backends reported in their last ping, or
+// None when none reported one, as when counting transfers.
+fn reported_max_players(backends: &[MinecraftServer]) -> Option<u32> {
+    backends
+        .iter()
+        .filter_map(MinecraftServer::last_max_players)
+        .reduce(u32::saturating_add)
+}
+
+// `interval` moved by a random amount of at most `jitter` either way.
+fn jittered(interval: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return interval;
+    }
+    let offset = rand::thread_rng().gen_range(Duration::ZERO..=jitter * 2);
+    (interval + offset).saturating_sub(jitter)
+}
+
+/// Polls the player count if the last poll is older than the poll interval,
+/// give or take the jitter. Neither the status cache nor the finder is locked
+/// during the poll, so status requests are answered from the last count and
+/// players keep being placed meanwhile.
+pub async fn refresh_player_count(
+    status_cache: &Mutex<StatusCache>,
+    server_finder: &Mutex<Box<dyn ServerFinder>>,
+) {
+    let (count_source, poll_concurrency) = {
+        let mut cache = status_cache.lock().await;
+        if !cache.take_due_poll() {
+            return;
+        }
+        (cache.count_source, cache.poll_concurrency)
+    };
+    poll_into(status_cache, server_finder, count_source, poll_concurrency).await;
+}
+
+// Polls copies of the finder's backends and stores the result.
+async fn poll_into(
+    status_cache: &Mutex<StatusCache>,
+    server_finder: &Mutex<Box<dyn ServerFinder>>,
+    count_source: PlayerCountSource,
+    poll_concurrency: usize,
+) {
+    let (backends, backoff) = {
+        let server_finder = server_finder.lock().await;
+        (server_finder.backends(), server_finder.poll_backoff())
+    };
+    let count = poll_player_count(
+        count_source,
+        &backends,
+        poll_concurrency,
+        backoff.as_deref(),
+    )
+    .await;
+    let max_players = reported_max_players(&backends);
+    let mut status_cache = status_cache.lock().await;
+    status_cache.count = count;
+    status_cache.max_players = max_players;
+}
+
+/// Pings the primary backend, if one is configured and its last ping is older
+/// than the poll interval. The status cache isn't locked during the ping.
+pub async fn refresh_primary(status_cache: &Mutex<StatusCache>) {
+    let primary = status_cache.lock().await.take_due_primary();
+    ping_primary(primary.as_ref()).await;
+}
+
+/// What `server_finder` shows a client at `ip` in the server list. At most one
+/// geo lookup is made, from the cache only, and the finder isn't locked while
+/// it runs.
+pub async fn status_overrides(
+    server_finder: &Mutex<Box<dyn ServerFinder>>,
+    ip: IpAddr,
+) -> StatusOverrides {
+    let geo = server_finder.lock().await.status_geo(ip);
+    let ip_info = match geo {
+        Some(geo) => geo.cached_lookup(ip).await,
+        None => None,
+    };
+    server_finder
+        .lock()
+        .await
+        .status_overrides(ip, ip_info.as_ref())
+}
+
+/// Polls the player count in the background, so the first status request
+/// finds it cached instead of waiting on every backend. Until the poll
+/// finishes, status requests are answered with a count of zero.
+pub async fn spawn_warmup(
+    status_cache: Arc<Mutex<StatusCache>>,
+    server_finder: Arc<Mutex<Box<dyn ServerFinder>>>,
+) -> JoinHandle<()> {
+    let (count_source, primary, poll_concurrency) = {
+        let mut cache = status_cache.lock().await;
+        cache.count = Some(0);
+        cache.take_due_poll();
+        (
+            cache.count_source,
+            cache.take_due_primary(),
+            cache.poll_concurrency,
+        )
+    };
+    tokio::spawn(async move {
+        // Requests are answered from the placeholder meanwhile.
+        ping_primary(primary.as_ref()).await;
+        poll_into(
+            &status_cache,
+            &server_finder,
+            count_source,
+            poll_concurrency,
+        )
+        .await;
+    })
+}
+
+// Advertising an unknown protocol makes clients show the version name in red
+// in place of the ping bars.
+fn maintenance_version() -> Version {
+    Version {
+        name: "Maintenance".to_string(),
+        protocol: 0,
+    }
+}
+
+// Kept apart from the maintenance label so players can tell an outage from
+// planned downtime.
+fn offline_version() -> Version {
+    Version {
+        name: "Offline".to_string(),
+        protocol: 0,
+    }
+}
+
+/// Reads the PNG at `path` into the data URI the server list expects.
+/// Clients only show 64x64 icons.
+pub fn load_favicon(path: &str) -> Result<String, Box<dyn Error>> {
+    let png = fs::read(path)?;
+    if !png.starts_with(PNG_SIGNATURE) {
+        return Err("not a PNG file".into());
+    }
+    Ok(format!("data:image/png;base64,{}", STANDARD.encode(png)))
+}
+
+/// Like `load_favicon`, but a favicon that can't be loaded is logged and
+/// left out rather than keeping the balancer from starting.
+pub fn favicon_or_warn(path: &str) -> Option<String> {
+    load_favicon(path)
+        .map_err(|error| warn!("Could not load favicon {}: {}", path, error))
+        .ok()
+}
+
+fn render_status(
+    version: Version,
+    description: String,
+    player_count: Option<u32>,
+    max_players: u32,
+    favicon: Option<String>,
+) -> String {
+    let response = StatusResponse {
+        version: Some(version),
+        players: Some(Players {
+            max: max_players,
+            online: player_count.unwrap_or(0),
+            sample: Vec::new(),
+        }),
+        description,
+        favicon,
+        enforce_secure_chat: false,
+    };
+
+    serde_json::to_string(&response).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Connection;
+    use crate::finder::{FinderError, get_server_finder};
+    use crate::geo_api::IpInfo;
+    use crate::test_support::{
+        NoServerFinder, PingTracker, spawn_delayed_player_count_backend,
+        spawn_delayed_status_backend, spawn_player_count_backend,
+        spawn_tracked_player_count_backend, test_config,
+    };
+    use async_trait::async_trait;
+    use std::net::SocketAddr;
+
+    // What finders without per-client status fields return.
+    const UNCHANGED: StatusOverrides = StatusOverrides {
+        version_name: None,
+        motd: None,
+        favicon: None,
+        backend: None,
+    };
+
+    // A shared finder routing to the single `backend`.
+    fn backend_finder(backend: SocketAddr) -> Arc<Mutex<Box<dyn ServerFinder>>> {
+        let mut config = test_config("");
+        let yaml = format!(
+            "algorithm: round_robin\nservers: [{{ address: \"{}\" }}]",
+            backend
+        );
+        config.static_cfg = Some(serde_yaml::from_str(&yaml).unwrap());
+        Arc::new(Mutex::new(get_server_finder(config).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn test_player_count_is_polled_once_per_interval() {
+        let tracker = Arc::new(PingTracker::default());
+        let backend = spawn_tracked_player_count_backend(2, Duration::ZERO, tracker.clone()).await;
+        let finder = backend_finder(backend);
+        let cache = Mutex::new(StatusCache::new(
+            Duration::from_millis(200),
+            NonZeroUsize::MIN,
+        ));
+
+        refresh_player_count(&cache, &finder).await;
+        assert_eq!(tracker.total(), 1);
+        assert_eq!(cache.lock().await.player_count(), Some(2));
+
+        refresh_player_count(&cache, &finder).await;
+        assert_eq!(tracker.total(), 1);
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        refresh_player_count(&cache, &finder).await;
+        assert_eq!(tracker.total(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_leaves_the_cache_and_finder_unlocked() {
+        let backend = spawn_delayed_player_count_backend(3, Duration::from_millis(300)).await;
+        let finder = backend_finder(backend);
+        let cache = Arc::new(Mutex::new(StatusCache::new(
+            Duration::from_secs(60),
+            NonZeroUsize::MIN,
+        )));
+
+        let refresh = tokio::spawn({
+            let (cache, finder) = (cache.clone(), finder.clone());
+            async move { refresh_player_count(&cache, &finder).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(cache.try_lock().is_ok());
+        assert!(finder.try_lock().is_ok());
+        // A second request while the first poll runs doesn't poll again.
+        assert!(!cache.lock().await.take_due_poll());
+        refresh.await.unwrap();
+        assert_eq!(cache.lock().await.player_count(), Some(3));
+    }
+
+    #[test]
+    fn test_jittered_interval_stays_in_range() {
+        let interval = Duration::from_secs(15);
+        let jitter = Duration::from_secs(3);
+
+        let delays: Vec<Duration> = (0..100).map(|_| jittered(interval, jitter)).collect();
+        assert!(
+            delays
+                .iter()
+                .all(|delay| (interval - jitter..=interval + jitter).contains(delay)),
+            "{:?}",
+            delays
+        );
+        // Polls actually spread out rather than all landing on one offset.
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+
+        assert_eq!(jittered(interval, Duration::ZERO), interval);
+    }
+
+    #[test]
+    fn test_next_poll_is_scheduled_within_the_jitter() {
+        let mut cache = StatusCache::new(Duration::from_secs(15), NonZeroUsize::MIN);
+        cache.poll_jitter = Duration::from_secs(3);
+
+        let before = Instant::now();
+        assert!(cache.take_due_poll());
+        let after = Instant::now();
+
+        let next_poll = cache.next_poll.unwrap();
+        assert!(next_poll >= before + Duration::from_secs(12));
+        assert!(next_poll <= after + Duration::from_secs(18));
+    }
+
+    #[tokio::test]
+    async fn test_warmup_fills_the_cache() {
+        let backend = spawn_player_count_backend(3).await;
+        let finder = backend_finder(backend);
+        let cache = Arc::new(Mutex::new(StatusCache::new(
+            Duration::from_secs(60),
+            NonZeroUsize::MIN,
+        )));
+
+        spawn_warmup(cache.clone(), finder.clone())
+            .await
+            .await
+            .unwrap();
+        assert_eq!(cache.lock().await.count, Some(3));
+        assert!(cache.lock().await.next_poll.unwrap() > Instant::now());
+
+        let response =
+            cache
+                .lock()
+                .await
+                .get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+        assert_eq!(json["players"]["online"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_leaves_the_finder_unlocked() {
+        let backend = spawn_delayed_player_count_backend(3, Duration::from_millis(300)).await;
+        let finder = backend_finder(backend);
+        let cache = Arc::new(Mutex::new(StatusCache::new(
+            Duration::from_secs(60),
+            NonZeroUsize::MIN,
+        )));
+
+        let warmup = spawn_warmup(cache.clone(), finder.clone()).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(finder.try_lock().is_ok());
+        warmup.await.unwrap();
+        assert_eq!(cache.lock().await.count, Some(3));
+    }
+
+    #[test]
+    fn test_response_cache_evicts_oldest_entry() {
+        let mut cache = StatusCache::new(Duration::ZERO, NonZeroUsize::new(2).unwrap());
+
+        for motd in ["first", "second", "third"] {
+            cache.get_status_response(motd.to_string(), 772, 772, UNCHANGED);
+        }
+
+        assert_eq!(cache.cache.len(), 2);
+        let key = |motd: &str| {
+            (
+                motd.to_string(),
+                "Loadbalancer".to_string(),
+                772,
+                None,
+                MAX_PLAYERS,
+                None,
+            )
+        };
+        assert!(!cache.cache.contains(&key("first")));
+        assert!(cache.cache.contains(&key("second")));
+        assert!(cache.cache.contains(&key("third")));
+    }
+
+    fn description(response: &CStatusResponse) -> serde_json::Value {
+        let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+        json["description"].clone()
+    }
+
+    #[tokio::test]
+    async fn test_offline_motd_is_used_when_all_backends_are_down() {
+        let finder: Mutex<Box<dyn ServerFinder>> = Mutex::new(Box::new(NoServerFinder));
+        let config = test_config("offline_motd: \"Down for maintenance\"");
+        let cache = Mutex::new(StatusCache::from_config(
+            &config,
+            Arc::new(Maintenance::default()),
+        ));
+
+        refresh_player_count(&cache, &finder).await;
+        let response =
+            cache
+                .lock()
+                .await
+                .get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        assert_eq!(description(&response), "Down for maintenance");
+    }
+
+    #[tokio::test]
+    async fn test_offline_response_is_not_labelled_maintenance() {
+        let finder: Mutex<Box<dyn ServerFinder>> = Mutex::new(Box::new(NoServerFinder));
+        let config = test_config("offline_motd: \"Down for maintenance\"");
+        let cache = Mutex::new(StatusCache::from_config(
+            &config,
+            Arc::new(Maintenance::default()),
+        ));
+
+        refresh_player_count(&cache, &finder).await;
+        let response =
+            cache
+                .lock()
+                .await
+                .get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+        assert_eq!(json["version"]["name"], "Offline");
+        assert_ne!(json["version"]["name"], maintenance_version().name);
+        assert_eq!(json["version"]["protocol"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_motd_is_used_while_backends_are_up() {
+        let finder = backend_finder(spawn_player_count_backend(1).await);
+        let config = test_config("offline_motd: \"Down for maintenance\"");
+        let cache = Mutex::new(StatusCache::from_config(
+            &config,
+            Arc::new(Maintenance::default()),
+        ));
+
+        refresh_player_count(&cache, &finder).await;
+        let response =
+            cache
+                .lock()
+                .await
+                .get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        assert_eq!(description(&response), "motd");
+    }
+
+    #[test]
+    fn test_configured_motd_is_the_description() {
+        let mut config = test_config("");
+        let mut cache = StatusCache::from_config(&config, Arc::new(Maintenance::default()));
+
+        let response = cache.get_status_response(config.status_motd(), 772, 772, UNCHANGED);
+        assert_eq!(description(&response), "test");
+
+        config.motd = None;
+        let response = cache.get_status_response(config.status_motd(), 772, 772, UNCHANGED);
+        assert_eq!(description(&response), "A Minecraft Load Balancer");
+    }
+
+    #[test]
+    fn test_outdated_clients_see_the_update_motd() {
+        let config = test_config(
+            r#"
+min_protocol_version: 767
+protocol_motds:
+  - max_protocol: 766
+    motd: "Please update to 1.21"
+"#,
+        );
+        let mut cache = StatusCache::from_config(&config, Arc::new(Maintenance::default()));
+
+        // 765 and 767 are both advertised 767, so only the motd tells their
+        // cached responses apart.
+        for (client_protocol, expected) in [
+            (765, "Please update to 1.21"),
+            (767, "motd"),
+            (772, "motd"),
+            (765, "Please update to 1.21"),
+        ] {
+            let response = cache.get_status_response(
+                "motd".to_string(),
+                client_protocol,
+                config.advertised_protocol(client_protocol),
+                UNCHANGED,
+            );
+            assert_eq!(description(&response), expected, "{}", client_protocol);
+        }
+    }
+
+    #[test]
+    fn test_maintenance_motd_is_served_while_enabled() {
+        let config = test_config("maintenance:\n  motd: \"Back soon\"");
+        let maintenance = Arc::new(Maintenance::from_config(&config));
+        let mut cache = StatusCache::from_config(&config, maintenance.clone());
+
+        maintenance.set_enabled(true);
+        let response = cache.get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        assert_eq!(description(&response), "Back soon");
+        let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+        assert_eq!(json["version"]["protocol"], 0);
+
+        maintenance.set_enabled(false);
+        let response = cache.get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        assert_eq!(description(&response), "motd");
+    }
+
+    #[tokio::test]
+    async fn test_player_count_from_transfers() {
+        let config = test_config("player_count_source: transfers");
+        let finder = Mutex::new(get_server_finder(config.clone()).unwrap());
+        let backend = finder.lock().await.backends()[0].clone();
+        let _routed = [backend.route_connection(), backend.route_connection()];
+        drop(backend.route_connection());
+        let cache = Mutex::new(StatusCache::from_config(
+            &config,
+            Arc::new(Maintenance::default()),
+        ));
+
+        // a.example.com is never pinged, it would not answer.
+        refresh_player_count(&cache, &finder).await;
+        let response =
+            cache
+                .lock()
+                .await
+                .get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+        assert_eq!(json["players"]["online"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_max_players_is_the_sum_of_the_backends() {
+        let status = |online: u32, max: u32| serde_json::json!({ "players": { "online": online, "max": max } });
+        let first = spawn_delayed_status_backend(status(3, 60), Duration::ZERO).await;
+        let second = spawn_delayed_status_backend(status(4, 40), Duration::ZERO).await;
+        let config = Config::from_yaml_str(&format!(
+            "mode: static\nstatus_max_players: 500\nstatic:\n  algorithm: round_robin\n  servers:\n    - address: \"{}\"\n    - address: \"{}\"\n",
+            first, second
+        ))
+        .unwrap();
+        let finder = Mutex::new(get_server_finder(config.clone()).unwrap());
+        let cache = Mutex::new(StatusCache::from_config(
+            &config,
+            Arc::new(Maintenance::default()),
+        ));
+        refresh_player_count(&cache, &finder).await;
+
+        let response =
+            cache
+                .lock()
+                .await
+                .get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+        assert_eq!(json["players"]["online"], 7);
+        assert_eq!(json["players"]["max"], 100);
+
+        // Counting transfers pings nothing, so the configured limit is shown.
+        let config = test_config("status_max_players: 500\nplayer_count_source: transfers");
+        let finder = Mutex::new(get_server_finder(config.clone()).unwrap());
+        let cache = Mutex::new(StatusCache::from_config(
+            &config,
+            Arc::new(Maintenance::default()),
+        ));
+        refresh_player_count(&cache, &finder).await;
+        let response =
+            cache
+                .lock()
+                .await
+                .get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+        assert_eq!(json["players"]["max"], 500);
+    }
+
+    /// Names the version after the client's address, like a geo finder
+    /// naming it after the region.
+    struct BrandingFinder;
+
+    #[async_trait]
+    impl ServerFinder for BrandingFinder {
+        async fn get_player_count(&self) -> Option<u32> {
+            Some(0)
+        }
+
+        fn backends(&self) -> Vec<MinecraftServer> {
+            Vec::new()
+        }
+
+        async fn find_server(
+            &mut self,
+            _connection: &Connection,
+        ) -> Result<MinecraftServer, FinderError> {
+            Err(FinderError::NoServersAvailable)
+        }
+
+        fn status_overrides(&self, ip: IpAddr, _ip_info: Option<&IpInfo>) -> StatusOverrides {
+            StatusOverrides {
+                version_name: (!ip.is_loopback()).then(|| format!("Loadbalancer {}", ip)),
+                ..StatusOverrides::default()
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_finder_overrides_version_name() {
+        let finder: Mutex<Box<dyn ServerFinder>> = Mutex::new(Box::new(BrandingFinder));
+        let config = test_config("version_name: \"Network\"");
+        let mut cache = StatusCache::from_config(&config, Arc::new(Maintenance::default()));
+
+        for (ip, expected) in [
+            ("192.0.2.1", "Loadbalancer 192.0.2.1"),
+            ("198.51.100.7", "Loadbalancer 198.51.100.7"),
+            ("127.0.0.1", "Network"),
+        ] {
+            let response = cache.get_status_response(
+                "motd".to_string(),
+                772,
+                772,
+                status_overrides(&finder, ip.parse().unwrap()).await,
+            );
+            let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+            assert_eq!(json["version"]["name"], expected, "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_primary_backend_status_is_mirrored() {
+        let primary = spawn_delayed_status_backend(
+            serde_json::json!({
+                "version": { "name": "Paper 1.21.8", "protocol": 772 },
+                "players": { "online": 5, "max": 100 },
+                "description": { "text": "Welcome to ", "extra": [{ "text": "the network" }] },
+                "favicon": "data:image/png;base64,AAAA",
             }),
-            players: Some(Players {
-                max: 1000,
-                online: player_count,
-                sample: Vec::new(),
+            Duration::ZERO,
+        )
+        .await;
+        let config = test_config(&format!("primary_backend:\n  address: \"{}\"", primary));
+        let cache = Mutex::new(StatusCache::from_config(
+            &config,
+            Arc::new(Maintenance::default()),
+        ));
+
+        refresh_primary(&cache).await;
+        let response =
+            cache
+                .lock()
+                .await
+                .get_status_response("motd".to_string(), 772, 772, UNCHANGED);
+        let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+        assert_eq!(description(&response), "Welcome to the network");
+        assert_eq!(json["version"]["name"], "Paper 1.21.8");
+        assert_eq!(json["favicon"], "data:image/png;base64,AAAA");
+    }
+
+    #[tokio::test]
+    async fn test_primary_backend_is_pinged_without_locking_the_cache() {
+        let primary = spawn_delayed_status_backend(
+            serde_json::json!({
+                "version": { "name": "Paper 1.21.8", "protocol": 772 },
+                "players": { "online": 5, "max": 100 },
+                "description": { "text": "Welcome" },
             }),
-            description: motd,
-            favicon: None,
-            enforce_secure_chat: false,
+            Duration::from_millis(300),
+        )
+        .await;
+        let config = test_config(&format!("primary_backend:\n  address: \"{}\"", primary));
+        let cache = Arc::new(Mutex::new(StatusCache::from_config(
+            &config,
+            Arc::new(Maintenance::default()),
+        )));
+
+        let refresh = tokio::spawn({
+            let cache = cache.clone();
+            async move { refresh_primary(&cache).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(cache.try_lock().is_ok());
+        // A second request while the first ping runs doesn't ping again.
+        assert!(cache.lock().await.take_due_primary().is_none());
+        refresh.await.unwrap();
+        let primary = cache.lock().await.primary.clone().unwrap();
+        assert!(primary.last_status().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_regional_player_count_follows_client_region() {
+        let yaml = format!(
+            r#"
+mode: geo
+motd: test
+player_count_source: transfers
+geo:
+  provider: maxmind
+  database: "{}/testdata/geo-country-test.mmdb"
+  regions:
+    EU:
+      address: "eu.backend"
+    NA:
+      address: "na.backend"
+  fallback:
+    address: "fallback.backend"
+  regional_player_count: true
+"#,
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let config = Config::from_yaml_str(&yaml).unwrap();
+        let finder = Mutex::new(get_server_finder(config.clone()).unwrap());
+        let mut routed = Vec::new();
+        for backend in finder.lock().await.backends() {
+            let players = match backend.region.as_deref() {
+                Some("EU") => 2,
+                Some("NA") => 5,
+                _ => 0,
+            };
+            routed.extend((0..players).map(|_| backend.route_connection()));
+        }
+        let mut cache = StatusCache::from_config(&config, Arc::new(Maintenance::default()));
+
+        for (ip, online) in [("192.0.2.1", 2), ("198.51.100.7", 5), ("10.0.0.1", 0)] {
+            let response = cache.get_status_response(
+                "motd".to_string(),
+                772,
+                772,
+                status_overrides(&finder, ip.parse().unwrap()).await,
+            );
+            let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+            assert_eq!(json["players"]["online"], online, "{}", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_favicon_follows_client_region() {
+        let dir = tempfile::tempdir().unwrap();
+        let icon = |name: &str| {
+            let path = dir.path().join(format!("{}.png", name));
+            fs::write(&path, [PNG_SIGNATURE, name.as_bytes()].concat()).unwrap();
+            path.to_string_lossy().into_owned()
         };
+        let yaml = format!(
+            r#"
+mode: geo
+favicon: "{}"
+player_count_source: transfers
+geo:
+  provider: maxmind
+  database: "{}/testdata/geo-country-test.mmdb"
+  regions:
+    EU:
+      address: "eu.backend"
+    NA:
+      address: "na.backend"
+  fallback:
+    address: "fallback.backend"
+  favicons:
+    eu: "{}"
+"#,
+            icon("global"),
+            env!("CARGO_MANIFEST_DIR"),
+            icon("eu")
+        );
+        let config = Config::from_yaml_str(&yaml).unwrap();
+        let finder = Mutex::new(get_server_finder(config.clone()).unwrap());
+        let mut cache = StatusCache::from_config(&config, Arc::new(Maintenance::default()));
+
+        let eu_icon = load_favicon(&icon("eu")).unwrap();
+        let global_icon = load_favicon(&icon("global")).unwrap();
+        assert_ne!(eu_icon, global_icon);
+        // NA has no icon of its own, and 10.0.0.1 can't be located.
+        for (ip, expected) in [
+            ("192.0.2.1", &eu_icon),
+            ("198.51.100.7", &global_icon),
+            ("10.0.0.1", &global_icon),
+        ] {
+            let response = cache.get_status_response(
+                "motd".to_string(),
+                772,
+                772,
+                status_overrides(&finder, ip.parse().unwrap()).await,
+            );
+            let json: serde_json::Value = serde_json::from_str(&response.json_response).unwrap();
+            assert_eq!(json["favicon"], *expected, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_favicon_must_be_a_png() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("icon.png");
+        fs::write(&path, "GIF89a").unwrap();
 
-        serde_json::to_string(&response).unwrap_or_default()
+        let error = load_favicon(&path.to_string_lossy()).unwrap_err();
+        assert_eq!(error.to_string(), "not a PNG file");
+        assert!(load_favicon("missing.png").is_err());
     }
 }
@@ -0,0 +1,344 @@
+//! Helpers shared by tests that need a live connection or a fake backend.
+
+use crate::backend::MinecraftServer;
+use crate::config::Config;
+use crate::connection::Connection;
+use crate::finder::{FinderError, ServerFinder, get_server_finder};
+use crate::maintenance::Maintenance;
+use crate::status::StatusCache;
+use async_trait::async_trait;
+use pumpkin_protocol::ServerPacket;
+use pumpkin_protocol::java::client::status::CStatusResponse;
+use pumpkin_protocol::java::packet_decoder::TCPNetworkDecoder;
+use pumpkin_protocol::java::packet_encoder::TCPNetworkEncoder;
+use pumpkin_protocol::java::server::handshake::SHandShake;
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+/// A finder with nothing to offer.
+pub struct NoServerFinder;
+
+#[async_trait]
+impl ServerFinder for NoServerFinder {
+    async fn get_player_count(&self) -> Option<u32> {
+        None
+    }
+
+    fn backends(&self) -> Vec<MinecraftServer> {
+        Vec::new()
+    }
+
+    async fn find_server(
+        &mut self,
+        _connection: &Connection,
+    ) -> Result<MinecraftServer, FinderError> {
+        Err(FinderError::NoServersAvailable)
+    }
+}
+
+/// A minimal static-mode config with `extra` appended to the root.
+pub fn test_config(extra: &str) -> Config {
+    let yaml = format!(
+        r#"
+mode: static
+motd: test
+static:
+  algorithm: round_robin
+  servers:
+    - address: "a.example.com"
+{}
+"#,
+        extra
+    );
+    Config::from_yaml_str(&yaml).unwrap()
+}
+
+/// A round-robin static finder over `addresses`.
+pub fn finder_for(addresses: &[impl AsRef<str>]) -> Box<dyn ServerFinder> {
+    let servers: String = addresses
+        .iter()
+        .map(|address| format!("\n    - address: \"{}\"", address.as_ref()))
+        .collect();
+    let yaml = format!(
+        "mode: static\nstatic:\n  algorithm: round_robin\n  servers:{}\n",
+        servers
+    );
+    get_server_finder(Config::from_yaml_str(&yaml).unwrap()).unwrap()
+}
+
+/// Returns a connection accepted from a local socket, plus the client end of that socket.
+pub async fn connection_pair(
+    config: Config,
+    finder: Box<dyn ServerFinder>,
+) -> (Connection, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (stream, addr) = listener.accept().await.unwrap();
+    let (read, write) = stream.into_split();
+
+    let maintenance = Arc::new(Maintenance::from_config(&config));
+    let connection = Connection::new(
+        read,
+        write,
+        Arc::new(Mutex::new(finder)),
+        Arc::new(Mutex::new(StatusCache::from_config(
+            &config,
+            maintenance.clone(),
+        ))),
+        maintenance,
+        addr,
+        Arc::new(config),
+    );
+    (connection, client)
+}
+
+/// Wraps the client end of a connection so tests can read the packets sent to it.
+pub fn client_decoder(
+    client: TcpStream,
+) -> (TCPNetworkDecoder<BufReader<OwnedReadHalf>>, OwnedWriteHalf) {
+    let (read, write) = client.into_split();
+    (TCPNetworkDecoder::new(BufReader::new(read)), write)
+}
+
+/// Starts a backend on a local port that waits `delay`, then answers every
+/// status ping with `status`.
+pub async fn spawn_delayed_status_backend(status: Value, delay: Duration) -> SocketAddr {
+    spawn_backend(status, delay, 0, None).await
+}
+
+/// Starts a backend that drops its first `failures` connections, then answers normally.
+pub async fn spawn_flaky_player_count_backend(online: u32, failures: usize) -> SocketAddr {
+    spawn_backend(player_count_status(online), Duration::ZERO, failures, None).await
+}
+
+/// Counts the status pings being answered at once by every backend it is
+/// shared with, the most there ever were, and how many there were in all.
+#[derive(Debug, Default)]
+pub struct PingTracker {
+    in_flight: AtomicUsize,
+    peak: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl PingTracker {
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::SeqCst)
+    }
+
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::SeqCst)
+    }
+
+    fn start(self: &Arc<Self>) -> TrackedPing {
+        self.total.fetch_add(1, Ordering::SeqCst);
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(in_flight, Ordering::SeqCst);
+        TrackedPing(self.clone())
+    }
+}
+
+// A ping counted as in flight until dropped.
+struct TrackedPing(Arc<PingTracker>);
+
+impl Drop for TrackedPing {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Starts a backend reporting `online` players after `delay`, counting its
+/// pings in `tracker`.
+pub async fn spawn_tracked_player_count_backend(
+    online: u32,
+    delay: Duration,
+    tracker: Arc<PingTracker>,
+) -> SocketAddr {
+    spawn_backend(player_count_status(online), delay, 0, Some(tracker)).await
+}
+
+async fn spawn_backend(
+    status: Value,
+    delay: Duration,
+    failures: usize,
+    tracker: Option<Arc<PingTracker>>,
+) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let status = status.to_string();
+
+    tokio::spawn(async move {
+        let mut failures = failures;
+        while let Ok((stream, _)) = listener.accept().await {
+            if failures > 0 {
+                failures -= 1;
+                drop(stream);
+                continue;
+            }
+
+            let status = status.clone();
+            let ping = tracker.as_ref().map(PingTracker::start);
+            tokio::spawn(answer_status_ping(stream, status, delay, ping));
+        }
+    });
+    addr
+}
+
+async fn answer_status_ping(
+    stream: TcpStream,
+    status: String,
+    delay: Duration,
+    ping: Option<TrackedPing>,
+) {
+    let (read, write) = stream.into_split();
+    let mut reader = TCPNetworkDecoder::new(BufReader::new(read));
+    let mut writer = TCPNetworkEncoder::new(BufWriter::new(write));
+
+    // Handshake, then the status request.
+    for _ in 0..2 {
+        if reader.get_raw_packet().await.is_err() {
+            return;
+        }
+    }
+
+    tokio::time::sleep(delay).await;
+    // Stop counting before answering, as the client may ping again right away.
+    drop(ping);
+
+    let mut buffer = Vec::new();
+    Connection::write_packet(&CStatusResponse::new(status), &mut buffer).unwrap();
+    let _ = writer.write_packet(buffer.into()).await;
+}
+
+/// Starts a backend that answers status pings and passes on the protocol
+/// version and server address of every handshake it receives.
+pub async fn spawn_handshake_recording_backend() -> (SocketAddr, UnboundedReceiver<(i32, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (handshakes, received) = unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let handshakes = handshakes.clone();
+            tokio::spawn(async move {
+                let (read, write) = stream.into_split();
+                let mut reader = TCPNetworkDecoder::new(BufReader::new(read));
+                let mut writer = TCPNetworkEncoder::new(BufWriter::new(write));
+
+                let Ok(packet) = reader.get_raw_packet().await else {
+                    return;
+                };
+                let Ok(handshake) = SHandShake::read(&packet.payload[..]) else {
+                    return;
+                };
+                let _ = handshakes.send((handshake.protocol_version.0, handshake.server_address));
+                if reader.get_raw_packet().await.is_err() {
+                    return;
+                }
+
+                let status = player_count_status(0).to_string();
+                let mut buffer = Vec::new();
+                Connection::write_packet(&CStatusResponse::new(status), &mut buffer).unwrap();
+                let _ = writer.write_packet(buffer.into()).await;
+            });
+        }
+    });
+    (addr, received)
+}
+
+/// Starts a backend that answers status pings, then reports every client
+/// that closes its side of the connection afterwards.
+pub async fn spawn_close_watching_backend() -> (SocketAddr, UnboundedReceiver<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (closes, received) = unbounded_channel();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let closes = closes.clone();
+            tokio::spawn(async move {
+                let (read, write) = stream.into_split();
+                let mut reader = TCPNetworkDecoder::new(BufReader::new(read));
+                let mut writer = TCPNetworkEncoder::new(BufWriter::new(write));
+
+                for _ in 0..2 {
+                    if reader.get_raw_packet().await.is_err() {
+                        return;
+                    }
+                }
+                let status = player_count_status(0).to_string();
+                let mut buffer = Vec::new();
+                Connection::write_packet(&CStatusResponse::new(status), &mut buffer).unwrap();
+                let _ = writer.write_packet(buffer.into()).await;
+
+                // The connection is kept open from this end, so the read
+                // only ends once the client closes it.
+                if reader.get_raw_packet().await.is_err() {
+                    let _ = closes.send(());
+                }
+            });
+        }
+    });
+    (addr, received)
+}
+
+/// Starts a backend reporting `online` players out of 100.
+pub async fn spawn_player_count_backend(online: u32) -> SocketAddr {
+    spawn_delayed_player_count_backend(online, Duration::ZERO).await
+}
+
+pub async fn spawn_delayed_player_count_backend(online: u32, delay: Duration) -> SocketAddr {
+    spawn_delayed_status_backend(player_count_status(online), delay).await
+}
+
+/// Starts a UDP query server reporting `online` players. It only answers a
+/// stat request carrying the challenge token it handed out.
+pub async fn spawn_query_backend(online: u32) -> SocketAddr {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        const TOKEN: i32 = 9513307;
+        let mut request = [0u8; 64];
+        loop {
+            let Ok((read, client)) = socket.recv_from(&mut request).await else {
+                return;
+            };
+            let request = &request[..read];
+            if read < 7 || request[..2] != [0xFE, 0xFD] {
+                continue;
+            }
+            let session = &request[3..7];
+            let mut reply = vec![request[2]];
+            reply.extend_from_slice(session);
+            match (request[2], &request[7..]) {
+                (0x09, []) => reply.extend_from_slice(format!("{}\0", TOKEN).as_bytes()),
+                (0x00, token) if token == TOKEN.to_be_bytes() => {
+                    let stat = format!("A fake backend\0SMP\0world\0{}\0100\0", online);
+                    reply.extend_from_slice(stat.as_bytes());
+                    reply.extend_from_slice(&addr.port().to_le_bytes());
+                    reply.extend_from_slice(b"127.0.0.1\0");
+                }
+                _ => continue,
+            }
+            let _ = socket.send_to(&reply, client).await;
+        }
+    });
+    addr
+}
+
+fn player_count_status(online: u32) -> Value {
+    json!({
+        "version": { "name": "1.21.8", "protocol": 772 },
+        "players": { "max": 100, "online": online },
+        "description": "A fake backend",
+    })
+}
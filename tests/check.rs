@@ -0,0 +1,91 @@
+use std::fs;
+use std::process::{Command, Output};
+
+// Runs `--check` against `config`, from an empty working directory.
+fn check(config: &str) -> (Output, String) {
+    check_with(config, &[])
+}
+
+fn check_with(config: &str, flags: &[&str]) -> (Output, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("balancer.yaml");
+    fs::write(&path, config).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_loadbalancer-rs"))
+        .arg("--config")
+        .arg(&path)
+        .arg("--check")
+        .args(flags)
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+
+    // --check never touches the config or writes a default one.
+    assert_eq!(fs::read_to_string(&path).unwrap(), config);
+    assert!(!dir.path().join("config.yaml").exists());
+    (output, String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn valid_config_passes() {
+    let (output, stdout) = check(
+        r#"
+mode: static
+static:
+  algorithm: round_robin
+  servers:
+    - address: "127.0.0.1:25566"
+    - address: "127.0.0.1:25567"
+"#,
+    );
+
+    assert!(output.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("127.0.0.1:25566 -> 127.0.0.1:25566"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("2 backend(s) resolved"), "{}", stdout);
+}
+
+#[test]
+fn invalid_config_fails() {
+    let (output, stdout) = check("mode: static\n");
+
+    assert!(!output.status.success(), "{}", stdout);
+    assert!(stdout.contains("requires a 'static' section"), "{}", stdout);
+}
+
+#[test]
+fn strict_check_fails_on_unreachable_backends() {
+    let down = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = format!(
+        r#"
+mode: static
+static:
+  algorithm: round_robin
+  servers:
+    - address: "{}"
+"#,
+        down
+    );
+
+    let (output, stdout) = check(&config);
+    assert!(output.status.success(), "{}", stdout);
+
+    let (output, stdout) = check_with(&config, &["--strict"]);
+    assert!(!output.status.success(), "{}", stdout);
+    assert!(
+        stdout.contains("Self-test: 0 of 1 backend(s) reachable"),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains(&format!("{}  unreachable", down)),
+        "{}",
+        stdout
+    );
+}